@@ -0,0 +1,75 @@
+#![allow(dead_code)] // Each example only uses part of it
+
+// Shared by the examples: loading a ROM, scripted input for a Tetris versus match,
+// and a way to look at the screens from a terminal
+
+use gameboy_emu::{Button, GameBoi, InputState};
+
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
+pub type Frame = [u8; WIDTH * HEIGHT];
+
+// (frame, side, button) pressed for a few frames, to reach a versus game:
+// skip the copyright, pick 2PLAYER on both sides, the first one starts as master,
+// picks the music, and both pick their height
+const MENUS: [(usize, usize, Button); 8] = [
+    (300, 0, Button::Start),
+    (300, 1, Button::Start),
+    (600, 0, Button::Right),
+    (600, 1, Button::Right),
+    (700, 0, Button::Start),
+    (800, 0, Button::Start),
+    (900, 0, Button::Start),
+    (900, 1, Button::Start),
+];
+const PRESS_FRAMES: usize = 5;
+const GAME_STARTS: usize = 1000;
+
+pub fn load(path: &str) -> GameBoi {
+    let rom = std::fs::read(path).expect("couldn't read the ROM");
+    let mut gameboi = GameBoi::new();
+    gameboi.load_rom_from_data(&rom);
+    gameboi
+}
+
+pub fn tetris_input(frame: usize, side: usize) -> InputState {
+    let mut input = InputState::new();
+    for &(at, who, button) in &MENUS {
+        if who == side && (at..at + PRESS_FRAMES).contains(&frame) {
+            input.press(button);
+        }
+    }
+
+    // The first side drops its pieces where they spawn, the second one piles them
+    // up on the left, both rotating every other piece
+    if frame >= GAME_STARTS {
+        let t = frame - GAME_STARTS;
+        match (side, t % 60) {
+            (_, 0..=3) if (t / 60).is_multiple_of(2) => input.press(Button::A),
+            (1, 10..=13 | 20..=23 | 30..=33) => input.press(Button::Left),
+            (_, 40..) => input.press(Button::Down),
+            _ => {}
+        }
+    }
+    input
+}
+
+// Screens side by side, a character for 2x4 pixels
+pub fn print_screens(frames: &[Frame]) {
+    const SHADES: [char; 4] = [' ', '.', 'o', '#'];
+    for y in (0..HEIGHT).step_by(4) {
+        let mut line = String::new();
+        for frame in frames {
+            line.push('|');
+            for x in (0..WIDTH).step_by(2) {
+                let darkest = (0..4)
+                    .flat_map(|dy| (0..2).map(move |dx| frame[(y + dy) * WIDTH + x + dx] & 3))
+                    .max()
+                    .unwrap();
+                line.push(SHADES[darkest as usize]);
+            }
+            line.push('|');
+        }
+        println!("{line}");
+    }
+}
@@ -0,0 +1,44 @@
+// Up to four Game Boys running the same game through a DMG-07 adapter, headless. Made
+// for four player games like F-1 Race or Wave Race, which aren't shipped here: every
+// player holds Start for a moment now and then to go through the menus, then the
+// screens are printed side by side.
+//
+// cargo run --release --example four_player -- <rom> [players] [frames]
+
+mod common;
+
+use common::{load, print_screens};
+use gameboy_emu::{Button, Dmg07, InputState};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args
+        .get(1)
+        .expect("usage: four_player <rom> [players] [frames]");
+    let players: usize = args.get(2).map_or(4, |n| n.parse().expect("players"));
+    let frames: usize = args.get(3).map_or(1200, |n| n.parse().expect("frames"));
+
+    let mut adapter = Dmg07::new((0..players).map(|_| load(path)).collect());
+    let start = std::time::Instant::now();
+    for frame in 0..frames {
+        for player in 0..adapter.players() {
+            let mut input = InputState::new();
+            // Players don't press at the same time, player 1 first
+            if (frame + player * 10) % 240 < 5 {
+                input.press(Button::Start);
+            }
+            adapter.gameboi(player).set_input(input);
+        }
+        let screens = adapter.step();
+
+        if frame + 1 == frames {
+            print_screens(&screens);
+        }
+    }
+    println!(
+        "{} frames for {} Game Boys in {:?}",
+        frames,
+        players,
+        start.elapsed()
+    );
+}
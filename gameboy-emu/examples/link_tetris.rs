@@ -0,0 +1,35 @@
+// Two Tetris instances playing a versus match over an in-process link cable, headless.
+// The menus are gone through with scripted input, then each side plays its own way,
+// and both screens are printed once in a while.
+//
+// cargo run --release --example link_tetris -- [path to Tetris.gb] [frames]
+
+mod common;
+
+use common::{load, print_screens, tetris_input};
+use gameboy_emu::LinkedPair;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let frames: usize = args.get(2).map_or(3000, |n| n.parse().expect("frames"));
+
+    let mut pair = LinkedPair::new(load(path), load(path));
+    let start = std::time::Instant::now();
+    for frame in 0..frames {
+        for side in 0..2 {
+            pair.gameboi(side).set_input(tetris_input(frame, side));
+        }
+        let screens = pair.step();
+
+        if (frame + 1).is_multiple_of(1000) || frame + 1 == frames {
+            println!("Frame {}", frame + 1);
+            print_screens(&screens);
+        }
+    }
+    println!(
+        "{} frames for each Game Boy in {:?}",
+        frames,
+        start.elapsed()
+    );
+}
@@ -0,0 +1,110 @@
+// Goes online through the emulated Mobile Adapter GB the way a game would: log in to the
+// ISP, look up the server, connect, send an HTTP request and read the answer. The
+// server is a mock listening on localhost, the adapter sends the game's connection to it.
+//
+// cargo run --release --example mobile_adapter
+
+use gameboy_emu::{MobileAdapter, MobileConfig, SerialDevice};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener};
+
+const HOST: &str = "gameboy.datacenter.ne.jp";
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+// Answers every request with the path that was asked for
+fn mock_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't start the mock server");
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+            let body = format!("Hello from the mock server, you asked for {path}\n");
+            let answer = format!(
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            reader.get_mut().write_all(answer.as_bytes()).unwrap();
+        }
+    });
+    address
+}
+
+// Sends a packet and clocks the answer out, returns its command and data
+fn send(adapter: &mut MobileAdapter, command: u8, data: &[u8]) -> (u8, Vec<u8>) {
+    let header = [command, 0, 0, data.len() as u8];
+    let checksum = header
+        .iter()
+        .chain(data)
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    let mut packet = vec![0x99, 0x66];
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&checksum.to_be_bytes());
+    for byte in packet {
+        adapter.exchange(byte, 0);
+    }
+    assert_eq!(adapter.exchange(0x81, 0), 0x88, "no adapter");
+    assert_eq!(adapter.exchange(0x00, 0), command | 0x80, "packet refused");
+
+    // Idle bytes until the answer starts
+    while adapter.exchange(0x4B, 0) != 0x99 {}
+    assert_eq!(adapter.exchange(0x4B, 0), 0x66);
+    let header: Vec<u8> = (0..4).map(|_| adapter.exchange(0x4B, 0)).collect();
+    let data: Vec<u8> = (0..header[3]).map(|_| adapter.exchange(0x4B, 0)).collect();
+    adapter.exchange(0x4B, 0);
+    adapter.exchange(0x4B, 0);
+    adapter.exchange(0x80, 0);
+    adapter.exchange(header[0], 0);
+    (header[0] & 0x7F, data)
+}
+
+fn main() {
+    let server = mock_server();
+    println!("Mock server on {server}");
+    let config = MobileConfig::new()
+        .host(HOST, SERVER_IP)
+        .server(SERVER_IP, 80, &server);
+    let mut adapter = MobileAdapter::new(config);
+
+    let (_, data) = send(&mut adapter, 0x10, b"NINTENDO");
+    println!("Session started: {}", String::from_utf8_lossy(&data));
+
+    let mut login = vec![4];
+    login.extend_from_slice(b"user");
+    login.push(4);
+    login.extend_from_slice(b"pass");
+    login.extend_from_slice(&[0; 8]);
+    let (_, data) = send(&mut adapter, 0x21, &login);
+    println!(
+        "Logged in as {}",
+        Ipv4Addr::new(data[0], data[1], data[2], data[3])
+    );
+
+    let (_, ip) = send(&mut adapter, 0x28, HOST.as_bytes());
+    println!("{HOST} is {}", Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]));
+
+    let mut connect = ip.clone();
+    connect.extend_from_slice(&80u16.to_be_bytes());
+    let (_, data) = send(&mut adapter, 0x23, &connect);
+    let id = data[0];
+    println!("Connected, connection {id}");
+
+    let mut request = vec![id];
+    request
+        .extend_from_slice(format!("GET /index.html HTTP/1.0\r\nHost: {HOST}\r\n\r\n").as_bytes());
+    let mut answer = Vec::new();
+    let (mut command, mut data) = send(&mut adapter, 0x15, &request);
+    // Games poll with empty transfers until the server closes the connection
+    while command == 0x15 {
+        answer.extend_from_slice(&data[1..]);
+        (command, data) = send(&mut adapter, 0x15, &[id]);
+    }
+    println!("Received:\n{}", String::from_utf8_lossy(&answer));
+
+    send(&mut adapter, 0x22, &[]);
+    send(&mut adapter, 0x11, &[]);
+    println!("Logged out");
+}
@@ -0,0 +1,53 @@
+// Records a movie of a scripted Tetris game, or plays one back and checks it doesn't
+// desync. Movies recorded from power on can be used as regression tests: a change to
+// the emulator that alters the game shows up as a desync on the first frame it affects.
+//
+// cargo run --release --example movie -- record <movie> [rom] [frames]
+// cargo run --release --example movie -- play <movie> [rom]
+
+mod common;
+
+use common::{load, tetris_input};
+use gameboy_emu::{Movie, MoviePlayer, MovieRecorder};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let usage = "usage: movie record|play <movie> [rom] [frames]";
+    let path = args.get(2).expect(usage);
+    let rom = args.get(3).map_or("../Tetris.gb", String::as_str);
+    let mut gameboi = load(rom);
+
+    match args.get(1).map(String::as_str) {
+        Some("record") => {
+            let frames: usize = args.get(4).map_or(1800, |n| n.parse().expect("frames"));
+            let mut recorder = MovieRecorder::new(&gameboi, true);
+            for frame in 0..frames {
+                recorder.step(&mut gameboi, tetris_input(frame, 0));
+            }
+            recorder
+                .finish()
+                .save(path)
+                .expect("couldn't write the movie");
+            println!(
+                "Recorded {frames} frames, final state {:016x}",
+                gameboi.state_hash()
+            );
+        }
+        Some("play") => {
+            let movie = Movie::load(path).expect("couldn't read the movie");
+            let mut player = MoviePlayer::new(movie, &mut gameboi).expect("can't play the movie");
+            while let Some(frame) = player.step(&mut gameboi) {
+                if let Err(error) = frame {
+                    println!("{error}");
+                    std::process::exit(1);
+                }
+            }
+            println!(
+                "Played {} frames, final state {:016x}",
+                player.frame(),
+                gameboi.state_hash()
+            );
+        }
+        _ => panic!("{usage}"),
+    }
+}
@@ -0,0 +1,116 @@
+// Prints a screenshot on the emulated Game Boy Printer, the way a game would: the screen
+// is turned into tiles and sent over the serial protocol, compressed, then printed.
+//
+// cargo run --release --example print_screen -- [rom] [frames] [output directory]
+
+mod common;
+
+use common::{Frame, HEIGHT, WIDTH, load};
+use gameboy_emu::{Printer, SerialDevice};
+
+fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+    let length = data.len() as u16;
+    let mut body = vec![command, compressed as u8];
+    body.extend_from_slice(&length.to_le_bytes());
+    body.extend_from_slice(data);
+    let checksum = body
+        .iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+
+    let mut packet = vec![0x88, 0x33];
+    packet.extend(body);
+    packet.extend_from_slice(&checksum.to_le_bytes());
+    packet.extend_from_slice(&[0, 0]); // For the answers
+    packet
+}
+
+// Sends a packet, returns the status the printer answered with
+fn send(printer: &mut Printer, packet: &[u8]) -> u8 {
+    let answers: Vec<u8> = packet
+        .iter()
+        .map(|&byte| printer.exchange(byte, 0))
+        .collect();
+    assert_eq!(answers[answers.len() - 2], 0x81, "no printer");
+    answers[answers.len() - 1]
+}
+
+// 2 bits per pixel, a tile row is 2 bytes: low bits then high bits
+fn tiles(frame: &Frame, rows: std::ops::Range<usize>) -> Vec<u8> {
+    let mut data = Vec::new();
+    for tile_y in rows {
+        for tile_x in 0..WIDTH / 8 {
+            for line in 0..8 {
+                let (mut low, mut high) = (0, 0);
+                for bit in 0..8 {
+                    let color = frame[(tile_y * 8 + line) * WIDTH + tile_x * 8 + bit] & 3;
+                    low |= (color & 1) << (7 - bit);
+                    high |= (color >> 1) << (7 - bit);
+                }
+                data.extend_from_slice(&[low, high]);
+            }
+        }
+    }
+    data
+}
+
+// The printer's run length encoding: runs of the same byte, or literal bytes
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(129)
+            .take_while(|&&b| b == data[i])
+            .count();
+        if run >= 2 {
+            out.extend_from_slice(&[0x80 | (run - 2) as u8, data[i]]);
+            i += run;
+        } else {
+            let start = i;
+            while i < data.len()
+                && i - start < 128
+                && !(i + 1 < data.len() && data[i] == data[i + 1])
+            {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+    out
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let frames: usize = args.get(2).map_or(400, |n| n.parse().expect("frames"));
+    let directory = args.get(3).map_or("prints", String::as_str);
+
+    let mut gameboi = load(path);
+    let mut frame = [0; WIDTH * HEIGHT];
+    for _ in 0..frames {
+        frame = gameboi.step();
+    }
+
+    let mut printer = Printer::new(directory);
+    send(&mut printer, &packet(0x01, false, &[]));
+    // A DATA packet holds 2 rows of tiles
+    for band in 0..HEIGHT / 16 {
+        let data = compress(&tiles(&frame, band * 2..band * 2 + 2));
+        send(&mut printer, &packet(0x04, true, &data));
+    }
+    send(&mut printer, &packet(0x04, false, &[]));
+    // 1 sheet, a line of margin above and 3 below, the usual palette, default exposure
+    let status = send(
+        &mut printer,
+        &packet(0x02, false, &[0x01, 0x13, 0xE4, 0x40]),
+    );
+    println!("Printing, status {status:02X}");
+
+    let mut polls = 1;
+    while send(&mut printer, &packet(0x0F, false, &[])) & 0x02 != 0 {
+        polls += 1;
+    }
+    println!("Done after {polls} status requests, the image is in {directory}/");
+}
@@ -0,0 +1,53 @@
+// Plays Tetris with rewind on, goes back a few seconds, and plays the same input again:
+// the frames have to be the ones seen the first time. Then rewinds as far as the
+// budget allows.
+//
+// cargo run --release --example rewind -- [rom] [frames] [frames to rewind] [budget in KB]
+
+mod common;
+
+use common::{Frame, load, tetris_input};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let frames: u64 = args.get(2).map_or(1600, |n| n.parse().expect("frames"));
+    let back: u64 = args.get(3).map_or(300, |n| n.parse().expect("frames"));
+    let budget: usize = args.get(4).map_or(4096, |n| n.parse().expect("budget"));
+
+    let mut gameboi = load(path);
+    gameboi.enable_rewind(1, budget * 1024);
+    let mut seen: Vec<Frame> = Vec::new();
+    while gameboi.frames() < frames {
+        gameboi.set_input(tetris_input(gameboi.frames() as usize, 0));
+        seen.push(gameboi.step());
+    }
+
+    let undone = gameboi.rewind(back);
+    println!(
+        "Rewound {undone} frames, back to frame {}",
+        gameboi.frames()
+    );
+    while gameboi.frames() < frames {
+        let frame = gameboi.frames();
+        gameboi.set_input(tetris_input(frame as usize, 0));
+        if gameboi.step() != seen[frame as usize] {
+            println!("Frame {frame} differs from the first time");
+            std::process::exit(1);
+        }
+    }
+    println!("Played the same frames again up to frame {frames}");
+
+    let mut total = 0;
+    loop {
+        let undone = gameboi.rewind(1);
+        if undone == 0 {
+            break;
+        }
+        total += undone;
+    }
+    println!(
+        "{budget} KB of history held {total} frames, the oldest is frame {}",
+        gameboi.frames()
+    );
+}
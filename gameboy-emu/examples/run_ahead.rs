@@ -0,0 +1,58 @@
+// Measures what run-ahead costs, and checks what it shows: with the input held the same,
+// running N frames ahead shows at each step the frame a normal run shows N steps later.
+//
+// cargo run --release --example run_ahead -- [rom] [frames]
+
+mod common;
+
+use common::{Frame, load};
+use gameboy_emu::{Button, InputState};
+use std::time::Instant;
+
+// Start now and then to go through the menus, otherwise the same input for long stretches
+fn input(frame: usize) -> InputState {
+    let mut input = InputState::new();
+    if frame % 200 < 5 {
+        input.press(Button::Start);
+    }
+    input
+}
+
+fn run(path: &str, frames: usize, ahead: u32) -> (Vec<Frame>, f64) {
+    let mut gameboi = load(path);
+    gameboi.set_run_ahead(ahead);
+    let start = Instant::now();
+    let shown = (0..frames)
+        .map(|frame| {
+            gameboi.set_input(input(frame));
+            gameboi.step()
+        })
+        .collect();
+    (shown, start.elapsed().as_secs_f64())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let frames: usize = args.get(2).map_or(1200, |n| n.parse().expect("frames"));
+
+    let (normal, base) = run(path, frames, 0);
+    println!(
+        "No run-ahead: {:.2} ms per frame",
+        base * 1000.0 / frames as f64
+    );
+    for ahead in 1..=3 {
+        let (shown, time) = run(path, frames, ahead);
+        let ahead = ahead as usize;
+        // Frames around an input change can't match, the future was run with the old input
+        let matching = (0..frames - ahead)
+            .filter(|&frame| shown[frame] == normal[frame + ahead])
+            .count();
+        println!(
+            "{ahead} frame(s) ahead: {:.2} ms per frame, {:.2}x the CPU time, {matching}/{} frames match a normal run {ahead} later",
+            time * 1000.0 / frames as f64,
+            time / base,
+            frames - ahead
+        );
+    }
+}
@@ -0,0 +1,53 @@
+// Saves a state in the middle of a game, runs on, then loads the state in a new
+// GameBoi and checks it produces the very same frames. Input keeps changing through
+// the run, some of it queued so it lands in the middle of frames.
+//
+// cargo run --release --example save_state -- [rom] [frames before saving] [frames after]
+
+mod common;
+
+use common::{Frame, load, tetris_input};
+use gameboy_emu::GameBoi;
+
+fn run(gameboi: &mut GameBoi, from: usize, frames: usize) -> Vec<Frame> {
+    (from..from + frames)
+        .map(|frame| {
+            gameboi.set_input(tetris_input(frame, 0));
+            gameboi.queue_input(35_000, tetris_input(frame + 3, 0));
+            gameboi.step()
+        })
+        .collect()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let before: usize = args.get(2).map_or(1100, |n| n.parse().expect("frames"));
+    let after: usize = args.get(3).map_or(600, |n| n.parse().expect("frames"));
+
+    let mut gameboi = load(path);
+    run(&mut gameboi, 0, before);
+    let state = gameboi.save_state();
+    println!("Saved a {} byte state at frame {before}", state.len());
+    let original = run(&mut gameboi, before, after);
+
+    // No ROM loaded, it comes with the state
+    let mut restored = GameBoi::new();
+    restored
+        .load_state(&state)
+        .expect("couldn't load the state");
+    let replayed = run(&mut restored, before, after);
+
+    match original.iter().zip(&replayed).position(|(a, b)| a != b) {
+        Some(frame) => {
+            println!("Frame {} differs after loading the state", before + frame);
+            std::process::exit(1);
+        }
+        None => println!("The {after} frames after loading are identical"),
+    }
+    assert_eq!(
+        gameboi.save_state(),
+        restored.save_state(),
+        "the states differ at the end"
+    );
+}
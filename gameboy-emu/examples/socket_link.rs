@@ -0,0 +1,54 @@
+// One side of a Tetris versus match over a socket link, headless. Run it twice, from
+// two terminals, the listening side being the first player:
+//
+// cargo run --release --example socket_link -- listen 127.0.0.1:5555 [path to Tetris.gb] [frames]
+// cargo run --release --example socket_link -- connect 127.0.0.1:5555 [path to Tetris.gb] [frames]
+//
+// Both print their screen at the end, with a hash of every frame they went through,
+// which comes out the same from one run to the next.
+
+mod common;
+
+use common::{load, print_screens, tetris_input};
+use gameboy_emu::SocketLink;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let usage = "usage: socket_link listen|connect <address> [rom] [frames]";
+    let (mode, address) = match (args.get(1), args.get(2)) {
+        (Some(mode), Some(address)) => (mode.as_str(), address.as_str()),
+        _ => panic!("{usage}"),
+    };
+    let path = args.get(3).map_or("../Tetris.gb", String::as_str);
+    let frames: usize = args.get(4).map_or(3000, |n| n.parse().expect("frames"));
+
+    let (link, side) = match mode {
+        "listen" => (SocketLink::listen(address), 0),
+        "connect" => (SocketLink::connect(address), 1),
+        _ => panic!("{usage}"),
+    };
+    let link = link.expect("couldn't set up the link");
+
+    let mut gameboi = load(path);
+    gameboi.connect_serial(Box::new(link));
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let start = std::time::Instant::now();
+    let mut screen = [0; common::WIDTH * common::HEIGHT];
+    for frame in 0..frames {
+        gameboi.set_input(tetris_input(frame, side));
+        screen = gameboi.step();
+        for &pixel in screen.iter() {
+            hash = (hash ^ pixel as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    print_screens(&[screen]);
+    println!(
+        "Player {}: {} frames in {:?}, hash {:016x}",
+        side + 1,
+        frames,
+        start.elapsed(),
+        hash
+    );
+}
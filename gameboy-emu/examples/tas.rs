@@ -0,0 +1,77 @@
+// A console for tool-assisted runs: advances frame by frame with the input given on
+// stdin, telling lag frames apart, with save states to retry a section. The screen
+// shows the buttons held in its bottom left corner.
+//
+// cargo run --release --example tas -- [rom] < inputs.txt
+//
+// One command per line:
+//   A+Right 3   hold A and Right for 3 frames (the count defaults to 1), - for nothing
+//   show        print the screen
+//   save, load  the save state
+//   # ...       comments
+
+mod common;
+
+use common::{load, print_screens};
+use gameboy_emu::{Button, InputState};
+use std::io::BufRead;
+
+fn parse_input(text: &str) -> Option<InputState> {
+    let mut input = InputState::new();
+    if text == "-" {
+        return Some(input);
+    }
+    for name in text.split('+') {
+        let button = Button::ALL
+            .into_iter()
+            .find(|button| format!("{button:?}").eq_ignore_ascii_case(name))?;
+        input.press(button);
+    }
+    Some(input)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+
+    let mut gameboi = load(path);
+    gameboi.set_input_display(true);
+    let mut screen = [0; 160 * 144];
+    let mut state = None;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("couldn't read stdin");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            [comment, ..] if comment.starts_with('#') => {}
+            ["show"] => print_screens(&[screen]),
+            ["save"] => state = Some(gameboi.save_state()),
+            ["load"] => match &state {
+                Some(state) => gameboi.load_state(state).expect("our own state"),
+                None => println!("Nothing saved"),
+            },
+            [buttons, count @ ..] => {
+                let Some(input) = parse_input(buttons) else {
+                    println!("Unknown buttons {buttons}");
+                    continue;
+                };
+                let count: usize = count
+                    .first()
+                    .map_or(Some(1), |n| n.parse().ok())
+                    .unwrap_or(1);
+                for _ in 0..count {
+                    gameboi.set_input(input);
+                    screen = gameboi.step();
+                    let lag = if gameboi.is_lag_frame() { " lag" } else { "" };
+                    println!("Frame {} {buttons}{lag}", gameboi.frames());
+                }
+            }
+        }
+    }
+    println!(
+        "{} frames, {} lag frames",
+        gameboi.frames(),
+        gameboi.lag_frames()
+    );
+}
@@ -0,0 +1,27 @@
+// Runs a ROM headless as fast as it goes, with no input, and reports the time per frame.
+// Keep the machine otherwise idle, and take the median of a few runs.
+//
+// cargo run --release --example throughput -- [rom] [frames]
+
+mod common;
+
+use common::load;
+use std::time::Instant;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("../Tetris.gb", String::as_str);
+    let frames: usize = args.get(2).map_or(3000, |n| n.parse().expect("frames"));
+
+    let mut gameboi = load(path);
+    let start = Instant::now();
+    for _ in 0..frames {
+        std::hint::black_box(gameboi.step());
+    }
+    let time = start.elapsed().as_secs_f64();
+    println!(
+        "{frames} frames in {time:.2} s, {:.2} ms per frame, {:.1}x real time",
+        time * 1000.0 / frames as f64,
+        frames as f64 / 59.73 / time
+    );
+}
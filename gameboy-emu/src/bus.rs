@@ -1,13 +1,39 @@
+use crate::input::InputState;
+use crate::interrupts::{self, IF, Interrupt};
 use crate::ppu::StatRegister;
 use crate::ppu::State;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::ppu::TileCache;
+use crate::ppu::{PPU, RendererKind};
+use crate::savestate::{self, StateError, StateReader, StateWriter};
+use crate::scheduler::{Event, Scheduler};
+use crate::serial::{self, Serial, SerialDevice};
+use crate::timer::{self, Timer};
+use std::collections::VecDeque;
+const JOYP: u16 = 0xFF00;
 const DMA: u16 = 0xFF46;
 const STAT: u16 = 0xFF41;
 
+// The bus owns the memory and the components, the CPU is lent the bus on every step
+#[derive(Clone)]
 pub struct Bus {
     memory: Memory,
-    joypad: u8,
+    scheduler: Scheduler,
+    ppu: PPU,
+    timer: Timer,
+    serial: Serial,
+    input: InputState,
+    queued_input: VecDeque<(u64, InputState)>, // Sorted by the time they apply at
+    joyp_lines: u8, // P10-P13 as last seen, for the interrupt on falling edges
+    joypad_polled: bool, // The game read JOYP since the last frame, for lag frames
+    dma: Option<DmaTransfer>,
+}
+
+// OAM DMA copies one byte per M-cycle, after a cycle to get started
+#[derive(Clone, Copy)]
+struct DmaTransfer {
+    source: u16,
+    index: u8,
+    running: bool,
 }
 
 /*
@@ -22,9 +48,143 @@ pub trait BusAccess {
 
 //TODO: Handle CPU blocking depending on PPU state
 impl Bus {
-    pub fn empty() -> Rc<RefCell<Self>> {
-        let memory = Memory::new(vec![]);
-        Rc::new(RefCell::new(Self { memory : memory, joypad :0 }))
+    pub fn new(renderer: RendererKind) -> Self {
+        let mut memory = Memory::new(vec![]);
+        let ppu = PPU::new(&mut memory, renderer);
+        let timer = Timer::new(&mut memory);
+        let serial = Serial::new(&mut memory);
+        let mut bus = Self {
+            memory,
+            scheduler: Scheduler::new(),
+            ppu,
+            timer,
+            serial,
+            input: InputState::new(),
+            queued_input: VecDeque::new(),
+            joyp_lines: 0x0F,
+            joypad_polled: false,
+            dma: None,
+        };
+        bus.sync_ppu();
+        bus
+    }
+
+    // ============ Scheduling ============
+
+    // Advances the master clock, only the components with a due event run now
+    pub fn tick(&mut self, cycles: u32) {
+        self.scheduler.advance(cycles);
+        while let Some(event) = self.scheduler.pop_due() {
+            match event {
+                Event::Ppu => self.sync_ppu(),
+                Event::Timer => self.sync_timer(),
+                Event::Dma => self.step_dma(),
+                Event::Input => self.apply_due_input(),
+                Event::Serial => self.sync_serial(),
+            }
+        }
+    }
+
+    // ============ Interrupts ============
+
+    pub fn pending_interrupts(&self) -> u8 {
+        interrupts::pending(&self.memory)
+    }
+
+    // Highest priority interrupt pending, which is cleared from IF
+    pub fn take_interrupt(&mut self) -> Option<Interrupt> {
+        let interrupt = interrupts::highest_pending(&self.memory)?;
+        interrupts::acknowledge(&mut self.memory, interrupt);
+        Some(interrupt)
+    }
+
+    // Master clock, in T-cycles
+    pub fn now(&self) -> u64 {
+        self.scheduler.now()
+    }
+
+    pub fn until_next_event(&self) -> Option<u64> {
+        self.scheduler.until_next_event()
+    }
+
+    fn sync_ppu(&mut self) {
+        self.ppu.sync(&mut self.memory, self.scheduler.now());
+        let dots = self.ppu.next_event();
+        self.scheduler.schedule_in(Event::Ppu, dots as u64);
+    }
+
+    fn sync_timer(&mut self) {
+        self.timer.sync(&mut self.memory, self.scheduler.now());
+        match self.timer.next_event(&self.memory) {
+            Some(cycles) => self.scheduler.schedule_in(Event::Timer, cycles),
+            None => self.scheduler.cancel(Event::Timer),
+        }
+    }
+
+    fn sync_serial(&mut self) {
+        let now = self.scheduler.now();
+        self.serial.sync(&mut self.memory, now);
+        match self.serial.next_event(now) {
+            Some(cycles) => self.scheduler.schedule_in(Event::Serial, cycles),
+            None => self.scheduler.cancel(Event::Serial),
+        }
+    }
+
+    // Returns the device that was connected before
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        let previous = self.serial.connect(device, self.scheduler.now());
+        // A transfer waiting on an external clock can start polling the new device
+        self.sync_serial();
+        previous
+    }
+
+    pub fn disconnect_serial(&mut self) -> Option<Box<dyn SerialDevice>> {
+        let previous = self.serial.disconnect();
+        self.sync_serial();
+        previous
+    }
+
+    // Components catch up before the CPU looks at, or changes, anything they use
+    fn sync_for(&mut self, address: u16) {
+        match address {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B => self.sync_ppu(),
+            timer::DIV..=timer::TAC => self.sync_timer(),
+            serial::SB..=serial::SC => self.sync_serial(),
+            _ => {}
+        }
+    }
+
+    fn step_dma(&mut self) {
+        let Some(dma) = self.dma.as_mut() else {
+            return;
+        };
+        if !dma.running {
+            dma.running = true;
+            self.scheduler.schedule_in(Event::Dma, 4);
+            return;
+        }
+
+        let (source, index) = (dma.source, dma.index);
+        dma.index += 1;
+        if dma.index as usize == self.memory.oam.len() {
+            self.dma = None;
+        } else {
+            self.scheduler.schedule_in(Event::Dma, 4);
+        }
+
+        // The PPU has to see OAM as it was before this byte
+        self.sync_ppu();
+        let byte = self.memory.read(source + index as u16);
+        self.memory.oam[index as usize] = byte;
+    }
+
+    // While a transfer runs, the CPU can't reach OAM
+    fn dma_blocks(&self, address: u16) -> bool {
+        self.dma.is_some_and(|dma| dma.running) && (0xFE00..=0xFE9F).contains(&address)
+    }
+
+    pub fn ppu(&mut self) -> &mut PPU {
+        &mut self.ppu
     }
 
     //This loads from a path
@@ -38,6 +198,13 @@ impl Bus {
         self.memory.load_rom(data);
     }
 
+    // Of the ROM as mapped, the two banks
+    pub fn rom_hash(&self) -> u64 {
+        let mut rom = self.memory.rom0.to_vec();
+        rom.extend_from_slice(&self.memory.romn);
+        savestate::hash(&rom)
+    }
+
     pub fn write(&mut self, address: u16, value: u8, cpuread: bool) {
         //This breaks loading for some reason
         /*
@@ -47,78 +214,228 @@ impl Bus {
             self.memory.write(address, value);
         }*/
 
-        self.memory.write(address, value);
+        if cpuread && self.dma_blocks(address) {
+            return;
+        }
+        self.sync_for(address);
+
+        if (timer::DIV..=timer::TAC).contains(&address) {
+            let now = self.scheduler.now();
+            self.timer.write(&mut self.memory, now, address, value);
+        } else if (serial::SB..=serial::SC).contains(&address) {
+            let now = self.scheduler.now();
+            self.serial.write(&mut self.memory, now, address, value);
+        } else {
+            self.memory.write(address, value);
+        }
 
-        //Handling DMA Transfer
+        match address {
+            JOYP => self.update_joyp_lines(),
+            // The PPU may react on the very next dot (LCD off, STAT interrupt...)
+            0xFF40..=0xFF4B => self.scheduler.schedule_in(Event::Ppu, 1),
+            timer::DIV..=timer::TAC => self.sync_timer(),
+            serial::SB..=serial::SC => self.sync_serial(),
+            _ => {}
+        }
+
+        //Handling DMA Transfer, done by parts as events, meanwhile the cpu is usually
+        //executing a small procedure in HRAM
         if address == DMA {
-            let mut new_oam = [0; 160];
-            let base_address = (value as u16) << 8;
-            for i in 0..160 {
-                new_oam[i] = self.memory.read(base_address + i as u16);
-            }
-            self.memory.oam = new_oam;
-            //TODO: Advance cpu clock by 160 M Cycles
-            //Note this is not cycle accurate, normally the dma transfer is done by parts, in which
-            //the cpu can either be executing nops or small procedures in HRAM
+            self.dma = Some(DmaTransfer {
+                source: (value as u16) << 8,
+                index: 0,
+                running: false,
+            });
+            self.scheduler.schedule_in(Event::Dma, 4);
         }
     }
 
     pub fn read(&mut self, address: u16, cpuread: bool) -> u8 {
-        if cpuread && !self.cpu_can_acces(address) {
+        self.sync_for(address);
+        if cpuread && (!self.cpu_can_acces(address) || self.dma_blocks(address)) {
             0xFF
         } else {
-            if address == 0xFF00 {
+            if address == JOYP {
+                self.joypad_polled |= cpuread;
                 return self.read_joyp();
             }
+            // Only the 5 lower bits of IF exist, the others read as 1
+            if address == IF {
+                return self.memory.read(IF) | 0xE0;
+            }
             self.memory.read(address)
         }
     }
 
-    pub fn set_joypad(&mut self, value: u8){
-        self.joypad = value;
+    pub fn set_input(&mut self, input: InputState) {
+        self.input = input;
+        self.update_joyp_lines();
+    }
+
+    // The input changes in the given number of T-cycles, in the middle of a frame if needed
+    pub fn queue_input(&mut self, cycles: u64, input: InputState) {
+        let at = self.scheduler.now() + cycles;
+        let index = self.queued_input.partition_point(|&(time, _)| time <= at);
+        self.queued_input.insert(index, (at, input));
+        self.schedule_input();
+    }
+
+    // Everything queued is applied right away, for when the clock isn't running
+    pub fn flush_input(&mut self) {
+        while let Some((_, input)) = self.queued_input.pop_front() {
+            self.set_input(input);
+        }
+        self.scheduler.cancel(Event::Input);
+    }
+
+    fn apply_due_input(&mut self) {
+        let now = self.scheduler.now();
+        while let Some(&(time, input)) = self.queued_input.front() {
+            if time > now {
+                break;
+            }
+            self.queued_input.pop_front();
+            self.set_input(input);
+        }
+        self.schedule_input();
+    }
+
+    fn schedule_input(&mut self) {
+        match self.queued_input.front() {
+            Some(&(time, _)) => {
+                let cycles = time.saturating_sub(self.scheduler.now());
+                self.scheduler.schedule_in(Event::Input, cycles);
+            }
+            None => self.scheduler.cancel(Event::Input),
+        }
+    }
+
+    // Any selected line going from high to low requests the joypad interrupt, whether
+    // a button was pressed or the game selected a row with a button held
+    fn update_joyp_lines(&mut self) {
+        let lines = self.read_joyp() & 0x0F;
+        if self.joyp_lines & !lines != 0 {
+            interrupts::request(&mut self.memory, Interrupt::Joypad);
+        }
+        self.joyp_lines = lines;
+    }
+
+    // Whether the game read the joypad since the last call
+    pub fn take_joypad_polled(&mut self) -> bool {
+        std::mem::take(&mut self.joypad_polled)
+    }
+
+    pub fn input(&self) -> InputState {
+        self.input
     }
 
+    // A button of a selected row is held, which is what wakes the CPU from STOP
+    pub fn joypad_line_low(&mut self) -> bool {
+        self.read_joyp() & 0x0F != 0x0F
+    }
+
+    // Bits 7-6 read as 1, bits 5-4 are the selection, and the lower nibble has the
+    // selected rows ANDed together (a line reads 1 when nothing pulls it low)
     fn read_joyp(&mut self) -> u8 {
-        let ff0 = self.memory.read(0xFF00 );
-        //0b0000_0000
+        let ff0 = self.memory.read(JOYP);
         //Rather contradictory, but for nintendo 0 == selected
-        let select_dpad = (ff0 & 0b0001_0000) >> 4  == 0;
-        let select_buttons = (ff0 & 0b0010_0000) >> 5  == 0;
+        let select_dpad = ff0 & 0b0001_0000 == 0;
+        let select_buttons = ff0 & 0b0010_0000 == 0;
 
-        let result =
+        let joypad = self.input.joypad_lines();
+        let mut lines = 0b0000_1111;
         if select_dpad {
-            (self.joypad >> 4) & 0b0000_1111
-            //ff0 | (self.joypad >> 4 | 0b1101_0000)
-        } else if select_buttons {
-            self.joypad  & 0b0000_1111
-            //ff0 & (self.joypad | 0b1110_0000)
+            lines &= joypad >> 4;
+        }
+        if select_buttons {
+            lines &= joypad & 0b0000_1111;
+        }
+        0b1100_0000 | (ff0 & 0b0011_0000) | lines
+    }
+
+    // ============ Save States ============
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.memory.save_state(state);
+        self.scheduler.save_state(state);
+        self.ppu.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+
+        state.u8(self.input.bits());
+        state.u32(self.queued_input.len() as u32);
+        for &(time, input) in &self.queued_input {
+            state.u64(time);
+            state.u8(input.bits());
+        }
+        state.u8(self.joyp_lines);
+
+        state.bool(self.dma.is_some());
+        if let Some(dma) = self.dma {
+            state.u16(dma.source);
+            state.u8(dma.index);
+            state.bool(dma.running);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(state)?;
+        self.scheduler.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state, self.scheduler.now())?;
+
+        self.input = InputState::from_bits(state.u8()?);
+        let queued = state.u32()?;
+        self.queued_input.clear();
+        for _ in 0..queued {
+            let time = state.u64()?;
+            let input = InputState::from_bits(state.u8()?);
+            self.queued_input.push_back((time, input));
+        }
+        self.joyp_lines = state.u8()?;
+
+        self.dma = if state.bool()? {
+            Some(DmaTransfer {
+                source: state.u16()?,
+                index: state.u8()?,
+                running: state.bool()?,
+            })
         } else {
-            0xFF
+            None
         };
+        if self.dma.is_some_and(|dma| dma.index as usize >= self.memory.oam.len()) {
+            return Err(StateError::Invalid("DMA position"));
+        }
 
-        //println!("Polling Input FF00 : {:08b} Result : {:08b} Joypad : {:08b}", ff0, result,self.joypad);
-        result
-
+        // Lag frames are counted from here, states are loaded between frames
+        self.joypad_polled = false;
+        // The serial device may not be the one that was connected, it gets its own sync
+        self.sync_serial();
+        Ok(())
     }
 
-    fn get_ppu_state(&mut self) -> State {
-        StatRegister::new(self.read(STAT, false)).get_ppu_state()
+    // The PPU has already been synced for VRAM and OAM, other addresses don't depend on it
+    fn get_ppu_state(&self) -> State {
+        StatRegister::new(self.memory.read(STAT)).get_ppu_state()
     }
 
-    fn cpu_can_acces(&mut self, address: u16) -> bool {
+    fn cpu_can_acces(&self, address: u16) -> bool {
+        if !matches!(address, 0x8000..=0x9FFF | 0xFE00..=0xFE9F) {
+            return true;
+        }
         match self.get_ppu_state() {
             State::OAMSearch => !(0xFE00..=0xFE9F).contains(&address),
 
             State::PixelTransfer => !(0x8000..=0x9FFF).contains(&address),
 
             State::HBlank | State::VBlank => true,
-
-            _ => unreachable!(),
         }
     }
 }
 
-struct Memory {
+#[derive(Clone)]
+pub struct Memory {
     rom0: [u8; 16_384],
     romn: [u8; 16_384],
 
@@ -133,6 +450,8 @@ struct Memory {
 
     io: [u8; 128],
     interrupt: [u8; 1],
+
+    tile_cache: TileCache,
 }
 
 impl Memory {
@@ -162,39 +481,66 @@ impl Memory {
             io: [0; 0x80],
             hram: [0; 0x7F],
             interrupt: [0; 1],
+            tile_cache: TileCache::new(),
         }
     }
 
-    fn handle_blarg_output(&mut self, address: u16, value: u8) {
-        if address == 0xFF02 && value == 0x81 {
-            let c = self.io[0x01] as char; // Read the byte to send
-            print!("{}", c); // Print immediately
-            std::io::Write::flush(&mut std::io::stdout()).unwrap();
-            self.io[0x02] = 0; // Clear "transfer in progress" flag
+    // There is no mapper yet, the ROM is the two banks mapped at 0x0000-0x7FFF. It is
+    // saved along with the rest, a state doesn't need the ROM to be loaded
+    fn save_state(&self, state: &mut StateWriter) {
+        for region in self.regions() {
+            state.bytes(region);
         }
     }
 
-    fn write(&mut self, address: u16, value: u8) {
-        // Handle serial transfer for Blargg tests
-        self.handle_blarg_output(address, value);
-        let (region, address, _, writable) = self.map(address);
-
-        if address == 0xFFFF {
-            println!("Writing to IE {}", value);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let vram = self.vram;
+        for region in self.regions_mut() {
+            state.bytes(region)?;
         }
-
-        if writable {
-            region[address] = value;
+        // Only the tiles that changed get decoded again, states are often loaded close
+        // to where they were saved (rewind, run-ahead)
+        for (tile, (old, new)) in vram.chunks(16).zip(self.vram.chunks(16)).enumerate() {
+            if old != new {
+                self.tile_cache.invalidate(0x8000 + tile as u16 * 16);
+            }
         }
+        Ok(())
     }
 
-    fn read(&mut self, address: u16) -> u8 {
-        let (region, address, readable, _) = self.map(address);
-        if readable {
-            return region[address];
-        }
+    fn regions(&self) -> [&[u8]; 10] {
+        [
+            &self.rom0,
+            &self.romn,
+            &self.vram,
+            &self.ram,
+            &self.wram1,
+            &self.wram2,
+            &self.hram,
+            &self.oam,
+            &self.io,
+            &self.interrupt,
+        ]
+    }
 
-        0xFF
+    fn regions_mut(&mut self) -> [&mut [u8]; 10] {
+        [
+            &mut self.rom0,
+            &mut self.romn,
+            &mut self.vram,
+            &mut self.ram,
+            &mut self.wram1,
+            &mut self.wram2,
+            &mut self.hram,
+            &mut self.oam,
+            &mut self.io,
+            &mut self.interrupt,
+        ]
+    }
+
+    // Row of a tile (0..383, counted from 0x8000) as color indices, for the PPU
+    pub fn tile_row(&mut self, tile: u16, row: usize, flipx: bool) -> [u8; 8] {
+        self.tile_cache.row(&self.vram, tile, row, flipx)
     }
 
     fn load_rom(&mut self, rom: &[u8]) {
@@ -234,3 +580,109 @@ impl Memory {
         }
     }
 }
+
+// The PPU gets direct access to memory, without the CPU restrictions
+impl BusAccess for Memory {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom0[address as usize],
+            0x4000..=0x7FFF => self.romn[(address - 0x4000) as usize],
+
+            0x8000..=0x9FFF => self.vram[(address - 0x8000) as usize],
+            0xA000..=0xBFFF => self.ram[(address - 0xA000) as usize],
+
+            0xC000..=0xCFFF => self.wram1[(address - 0xC000) as usize],
+            0xD000..=0xDFFF => self.wram2[(address - 0xD000) as usize],
+
+            // Echo RAM mirrors C000–DDFF
+            0xE000..=0xEFFF => self.wram1[(address - 0xE000) as usize],
+            0xF000..=0xFDFF => self.wram2[(address - 0xF000) as usize],
+
+            0xFE00..=0xFE9F => self.oam[(address - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => 0xFF, // not usable
+
+            0xFF00..=0xFF7F => self.io[(address - 0xFF00) as usize],
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
+
+            0xFFFF => self.interrupt[0],
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.tile_cache.invalidate(address);
+        let (region, address, _, writable) = self.map(address);
+
+        if address == 0xFFFF {
+            println!("Writing to IE {}", value);
+        }
+
+        if writable {
+            region[address] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Button;
+
+    // A press queued in the middle of a frame shows in JOYP, and requests the joypad
+    // interrupt, on its own cycle
+    #[test]
+    fn queued_input_lands_on_its_cycle() {
+        let mut bus = Bus::new(RendererKind::Fifo);
+        bus.write(JOYP, 0x10, false); // Action buttons selected
+        bus.write(IF, 0x00, false);
+        let mut input = InputState::new();
+        input.press(Button::A);
+        bus.queue_input(30_000, input);
+
+        bus.tick(29_996);
+        assert_eq!(bus.read(JOYP, false) & 0x0F, 0x0F);
+        assert_eq!(bus.read(IF, false) & 0x10, 0);
+        bus.tick(4);
+        assert_eq!(bus.read(JOYP, false) & 0x0F, 0x0E);
+        assert_eq!(bus.read(IF, false) & 0x10, 0x10);
+    }
+
+    // With the clock stopped, flushing applies everything queued at once
+    #[test]
+    fn flush_applies_queued_input() {
+        let mut bus = Bus::new(RendererKind::Fifo);
+        bus.write(JOYP, 0x10, false);
+        let mut input = InputState::new();
+        input.press(Button::B);
+        bus.queue_input(1_000, input);
+        input.press(Button::A);
+        bus.queue_input(2_000, input);
+
+        bus.flush_input();
+        assert_eq!(bus.read(JOYP, false) & 0x0F, 0x0C);
+        assert!(bus.queued_input.is_empty());
+    }
+
+    // Tile 1 is changed behind the cache's back before saving, so the cache still
+    // holds its old rows: if loading decoded it again they would show
+    #[test]
+    fn load_state_decodes_only_the_changed_tiles() {
+        let mut memory = Memory::new(vec![]);
+        memory.vram[..32].fill(0xFF);
+        assert_eq!(memory.tile_row(0, 0, false), [3; 8]);
+        assert_eq!(memory.tile_row(1, 0, false), [3; 8]);
+        memory.vram[16..32].fill(0);
+
+        let mut state = StateWriter::new();
+        memory.save_state(&mut state);
+        let state = state.finish();
+
+        for address in 0x8000..0x8010 {
+            memory.write(address, 0);
+        }
+        assert_eq!(memory.tile_row(0, 0, false), [0; 8]);
+
+        memory.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+        assert_eq!(memory.tile_row(0, 0, false), [3; 8]);
+        assert_eq!(memory.tile_row(1, 0, false), [3; 8]);
+    }
+}
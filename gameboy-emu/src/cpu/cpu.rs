@@ -1,9 +1,6 @@
 #![allow(dead_code, unused_variables)]
 
 use crate::bus::Bus;
-use crate::bus::BusAccess;
-use std::cell::RefCell;
-use std::rc::Rc;
 
 //Try this: https://robertheaton.com/gameboy-doctor/
 
@@ -20,40 +17,31 @@ const TMA: u16 = 0xFF06;
 const TAC: u16 = 0xFF07;
 
 //Interrupt registers
-const IF: u16 = 0xFF0F; //Interrupt flag
-const IE: u16 = 0xFFFF; //Interrupt enable
+use crate::interrupts::{IE, IF};
 
 use super::{FlagCondition, MemAdress, Operand, Reg8, Reg16};
 use crate::cpu::opcodes::InstrPointer;
+use crate::savestate::{StateError, StateReader, StateWriter};
 use std::fmt;
 
 // ================================== CPU =============================
 
-#[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum Interrupt {
-    VBlank = 0x40,
-    LCDStat = 0x48,
-    Timer = 0x50, // Current bug <- Jumping here without asking, weird
-    Serial = 0x58,
-    Joypad = 0x60,
-}
-
 // Making the opcode decoding a child of CPU!
+// The CPU doesn't own the bus, it is lent for each step
+#[derive(Clone)]
 pub struct CPU {
-    bus: Rc<RefCell<Bus>>,
     registers: Registers,
     clock: u64,
     ime: bool,         // Interrupt Master Enable
     ime_pending: bool, // For delayed EI
+    ime_delayed: bool, // IME was enabled by an EI right before the current instruction
     halted: bool,
+    stopped: bool,  // STOP mode, only a button press wakes the CPU up
+    halt_bug: bool, // The next opcode fetch doesn't increment PC
     opcode_table: [InstrPointer; 256],
     cb_table: [InstrPointer; 256],
     //for debug purposes
     executing: (u8, InstrPointer),
-
-    //Timer
-    div_counter: u16,
 }
 
 use FlagCondition::*;
@@ -61,23 +49,37 @@ use MemAdress::*;
 use Operand::*;
 use Reg16::*;
 
-impl BusAccess for CPU {
-    fn read(&self, addr: u16) -> u8 {
-        self.bus.borrow_mut().read(addr, true)
+impl CPU {
+    // ============= Timing =============
+
+    // Every memory access takes one M-cycle, during which the rest of the system runs
+    fn read(&mut self, bus: &mut Bus, addr: u16) -> u8 {
+        self.cycle(bus);
+        bus.read(addr, true)
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
-        self.bus.borrow_mut().write(addr, value, true)
+    fn write(&mut self, bus: &mut Bus, addr: u16, value: u8) {
+        self.cycle(bus);
+        bus.write(addr, value, true)
     }
-}
 
-impl CPU {
-    pub fn print_state(&mut self) {
+    // An M-cycle, spent either on a memory access or idling inside an instruction
+    fn cycle(&mut self, bus: &mut Bus) {
+        self.idle(bus, 4);
+    }
+
+    fn idle(&mut self, bus: &mut Bus, cycles: u32) {
+        self.clock = self.clock.wrapping_add(cycles as u64);
+        bus.tick(cycles);
+    }
+
+    pub fn print_state(&mut self, bus: &mut Bus) {
+        // Peeking at memory here shouldn't take any time
         let pc_mem = [
-            self.read(self.registers.pc),
-            self.read(self.registers.pc.wrapping_add(1)),
-            self.read(self.registers.pc.wrapping_add(2)),
-            self.read(self.registers.pc.wrapping_add(3)),
+            bus.read(self.registers.pc, false),
+            bus.read(self.registers.pc.wrapping_add(1), false),
+            bus.read(self.registers.pc.wrapping_add(2), false),
+            bus.read(self.registers.pc.wrapping_add(3), false),
         ];
 
         println!(
@@ -96,16 +98,16 @@ impl CPU {
             pc_mem[1],
             pc_mem[2],
             pc_mem[3],
-            self.read(DIV),
-            self.read(TIMA),
-            self.read(TMA),
-            self.read(TAC),
-            self.read(IE),
-            self.read(IF),
+            bus.read(DIV, false),
+            bus.read(TIMA, false),
+            bus.read(TMA, false),
+            bus.read(TAC, false),
+            bus.read(IE, false),
+            bus.read(IF, false),
         );
     }
 
-    pub fn new(bus: Rc<RefCell<Bus>>) -> Self {
+    pub fn new(bus: &mut Bus) -> Self {
         let (opcode_table, cb_table) = CPU::build_table();
 
         //println! {"{:?}",opcode_table[0xFA]};
@@ -126,58 +128,73 @@ impl CPU {
 
         let executing = (0, InstrPointer::None);
 
-        let mut cpu = CPU {
+        CPU {
             registers,
-            bus,
             clock: 0,
             ime: false,
             ime_pending: false,
+            ime_delayed: false,
             halted: false,
+            stopped: false,
+            halt_bug: false,
             opcode_table,
             cb_table,
             executing,
-            div_counter: 0,
-        };
-
-        cpu.write(IE, 0x10);
-        cpu
+        }
     }
-    pub fn step(&mut self) -> u8 {
+    pub fn step(&mut self, bus: &mut Bus) -> u32 {
+        if self.stopped {
+            // The clock is stopped too, nothing runs until a button wakes the CPU up
+            return 0;
+        }
         self.update_ime();
+        let clock = self.clock;
         if self.halted {
-            self.clock = self.clock.wrapping_add(4);
-            self.advance_timer(4);
-            if self.interrupt_pending() {
+            // An interrupt can be raised between steps (a button press, a serial device),
+            // otherwise nothing can wake the CPU before the next event, so we skip to it
+            if !self.interrupt_pending(bus) {
+                let cycles = bus.until_next_event().unwrap_or(4).max(4).next_multiple_of(4);
+                self.idle(bus, cycles as u32);
+            }
+            if self.interrupt_pending(bus) {
+                // Waking up takes an extra M-cycle before the dispatch, without IME
+                // the CPU just goes on with the instruction after HALT
                 self.halted = false;
                 if self.ime {
-                    self.handle_interrupts();
-                } else {
-                    self.registers.pc = self.registers.pc.wrapping_add(1); // Skip 1st instr after HALT 
+                    self.cycle(bus);
+                    self.handle_interrupts(bus);
                 }
             }
-            return 4;
+            return self.clock.wrapping_sub(clock) as u32;
         }
         //CPU::print_state(self);
 
         let pc = self.registers.get_16register(PC);
-        let opcode = self.read(pc);
-        let cycles: u8 = {
-            if opcode == 0xCB {
-                let opcode2 = self.read(self.registers.pc.wrapping_add(1));
-                self.registers.pc = pc.wrapping_add(2);
-                self.execute_from_instr(self.cb_table[opcode2 as usize], opcode2)
-            } else {
-                self.registers.pc = pc.wrapping_add(1);
-                self.execute_from_instr(self.opcode_table[opcode as usize], opcode)
-            }
+        let opcode = self.read(bus, pc);
+        // With the HALT bug, the byte after HALT is read twice
+        let next = if self.halt_bug {
+            self.halt_bug = false;
+            pc
+        } else {
+            pc.wrapping_add(1)
         };
-        self.handle_interrupts();
-        cycles
+        if opcode == 0xCB {
+            let opcode2 = self.read(bus, next);
+            self.registers.pc = next.wrapping_add(1);
+            self.execute_from_instr(bus, self.cb_table[opcode2 as usize], opcode2);
+        } else {
+            self.registers.pc = next;
+            self.execute_from_instr(bus, self.opcode_table[opcode as usize], opcode);
+        }
+        self.handle_interrupts(bus);
+
+        // The bus has already been ticked by every M-cycle of the instruction
+        self.clock.wrapping_sub(clock) as u32
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self, bus: &mut Bus) {
         loop {
-            self.step();
+            self.step(bus);
             /* Optional: safety cutoff to prevent infinite loops
             if self.clock > 50_000_000 {
                 println!("❌ Timeout or infinite loop. Test failed or hanging.");
@@ -186,87 +203,115 @@ impl CPU {
         }
     }
 
-    fn execute_from_instr(&mut self, instr: InstrPointer, opcode: u8) -> u8 {
-        self.executing = (opcode, instr);
-        let clock1 = self.clock;
-        let cycles = match instr {
-            InstrPointer::Const(func, c) => {
-                func(self);
-                c
-            }
-            InstrPointer::Unop(func, op, c) => {
-                func(self, op);
-                c
-            }
-            InstrPointer::Binop(func, op1, op2, c) => {
-                func(self, op1, op2);
-                c
-            }
-            InstrPointer::None => panic!("Unimplemented opcode"),
-        };
+    // ============= Save States =============
 
-        // Add cycles to clock
-        self.clock = self.clock.wrapping_add(cycles as u64);
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let r = &self.registers;
+        for register in [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l] {
+            state.u8(register);
+        }
+        state.u16(r.sp);
+        state.u16(r.pc);
+        state.u64(self.clock);
+        for flag in [
+            self.ime,
+            self.ime_pending,
+            self.ime_delayed,
+            self.halted,
+            self.stopped,
+            self.halt_bug,
+        ] {
+            state.bool(flag);
+        }
+    }
 
-        let true_cycles = self.clock - clock1;
-        // Update timer //TODO make all clock additions u8, no need for u16!
-        self.advance_timer(true_cycles as u8);
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let r = &mut self.registers;
+        for register in [
+            &mut r.a, &mut r.f, &mut r.b, &mut r.c, &mut r.d, &mut r.e, &mut r.h, &mut r.l,
+        ] {
+            *register = state.u8()?;
+        }
+        r.sp = state.u16()?;
+        r.pc = state.u16()?;
+        self.clock = state.u64()?;
+        for flag in [
+            &mut self.ime,
+            &mut self.ime_pending,
+            &mut self.ime_delayed,
+            &mut self.halted,
+            &mut self.stopped,
+            &mut self.halt_bug,
+        ] {
+            *flag = state.bool()?;
+        }
+        Ok(())
+    }
 
-        true_cycles as u8
+    // Instructions spend their own cycles as they access memory, the cycle counts
+    // from the opcode table are only kept for reference
+    fn execute_from_instr(&mut self, bus: &mut Bus, instr: InstrPointer, opcode: u8) {
+        self.executing = (opcode, instr);
+        match instr {
+            InstrPointer::Const(func, _) => func(self, bus),
+            InstrPointer::Unop(func, op, _) => func(self, bus, op),
+            InstrPointer::Binop(func, op1, op2, _) => func(self, bus, op1, op2),
+            InstrPointer::None => panic!("Unimplemented opcode"),
+        }
     }
 
     fn check_condition(&mut self, op: Operand) -> bool {
         match op {
-            Flag(NZ) => return !self.registers.get_flag(ZERO),
-            Flag(Z) => return self.registers.get_flag(ZERO),
-            Flag(NC) => return !self.registers.get_flag(CARRY),
-            Flag(C) => return self.registers.get_flag(CARRY),
+            Flag(NZ) => !self.registers.get_flag(ZERO),
+            Flag(Z) => self.registers.get_flag(ZERO),
+            Flag(NC) => !self.registers.get_flag(CARRY),
+            Flag(C) => self.registers.get_flag(CARRY),
             Flag(None) => true,
             _ => panic!("not a flag condition!"),
         }
     }
 
-    fn get_operand_as_u8(&mut self, op: Operand) -> u8 {
+    fn get_operand_as_u8(&mut self, bus: &mut Bus, op: Operand) -> u8 {
         match op {
             R8(register) => self.registers.get_u8register(register),
 
             Address(AddrR16(register)) => {
                 let address = self.registers.get_16register(register);
-                self.read(address)
+                self.read(bus, address)
             }
             Address(HLInc) => {
                 let address = self.registers.get_16register(HL);
                 self.registers.set_16register(HL, address.wrapping_add(1));
-                return self.read(address);
+                self.read(bus, address)
             }
             Address(HLDec) => {
                 let address = self.registers.get_16register(HL);
                 self.registers.set_16register(HL, address.wrapping_sub(1));
-                return self.read(address);
+                self.read(bus, address)
             }
             Imm8 => {
                 let address = self.registers.get_16register(PC);
                 self.registers.set_16register(PC, address.wrapping_add(1));
-                return self.read(address);
+                self.read(bus, address)
             }
             Address(ImmAddr16) => {
-                let lsb = self.get_operand_as_u8(Imm8);
-                let msb = self.get_operand_as_u8(Imm8);
+                let lsb = self.get_operand_as_u8(bus, Imm8);
+                let msb = self.get_operand_as_u8(bus, Imm8);
                 let address = CPU::fuse_u8(lsb, msb);
                 //println!("{:02X} at {:02X}", self.bus.read(address), address);
-                return self.read(address);
+                self.read(bus, address)
             }
 
             //For LDH func
             Address(ImmAddr8) => {
-                let offset = self.get_operand_as_u8(Imm8);
+                let offset = self.get_operand_as_u8(bus, Imm8);
                 let address = CPU::fuse_u8(offset, 0xFF);
-                return self.read(address);
+                self.read(bus, address)
             }
             Address(AddrR8(register)) => {
                 let offset = self.registers.get_u8register(register);
                 let address = 0xFF00u16 + offset as u16;
-                return self.read(address);
+                self.read(bus, address)
             }
             Value(n) => n as u8,
 
@@ -275,73 +320,73 @@ impl CPU {
         }
     }
 
-    fn set_operand_from_u8(&mut self, op: Operand, value: u8) {
+    fn set_operand_from_u8(&mut self, bus: &mut Bus, op: Operand, value: u8) {
         match op {
             R8(register) => self.registers.set_u8register(register, value),
 
             Address(AddrR16(register)) => {
                 let address = self.registers.get_16register(register);
-                self.memwrite(address, value)
+                self.memwrite(bus, address, value)
             }
             Address(HLInc) => {
                 let address = self.registers.get_16register(HL);
-                self.memwrite(address, value);
+                self.memwrite(bus, address, value);
                 self.registers.set_16register(HL, address.wrapping_add(1));
             }
             Address(HLDec) => {
                 let address = self.registers.get_16register(HL);
-                self.memwrite(address, value);
+                self.memwrite(bus, address, value);
                 self.registers.set_16register(HL, address.wrapping_sub(1));
             }
             Address(ImmAddr16) => {
-                let lsb = self.get_operand_as_u8(Imm8);
-                let msb = self.get_operand_as_u8(Imm8);
+                let lsb = self.get_operand_as_u8(bus, Imm8);
+                let msb = self.get_operand_as_u8(bus, Imm8);
                 let address = CPU::fuse_u8(lsb, msb);
-                self.memwrite(address, value);
+                self.memwrite(bus, address, value);
             }
             //For LDH func
             Address(ImmAddr8) => {
-                let offset = self.get_operand_as_u8(Imm8);
+                let offset = self.get_operand_as_u8(bus, Imm8);
                 let address = 0xFF00u16 + offset as u16;
-                self.memwrite(address, value)
+                self.memwrite(bus, address, value)
             }
             Address(AddrR8(register)) => {
                 let offset = self.registers.get_u8register(register);
                 let address = 0xFF00u16 + offset as u16;
-                self.memwrite(address, value)
+                self.memwrite(bus, address, value)
             }
 
             _ => panic!("not a u8 operand for set!"),
         }
     }
 
-    fn set_operand_to_u16(&mut self, op: Operand, value: u16) {
+    fn set_operand_to_u16(&mut self, bus: &mut Bus, op: Operand, value: u16) {
         match op {
-            R16(register) => return self.registers.set_16register(register, value),
+            R16(register) => self.registers.set_16register(register, value),
             Address(ImmAddr16) => {
-                let lsb = self.get_operand_as_u8(Imm8);
-                let msb = self.get_operand_as_u8(Imm8);
+                let lsb = self.get_operand_as_u8(bus, Imm8);
+                let msb = self.get_operand_as_u8(bus, Imm8);
 
                 let address = CPU::fuse_u8(lsb, msb);
                 let (vlsb, vmsb) = CPU::split_u16(value);
-                self.memwrite(address, vlsb);
-                self.memwrite(address + 1, vmsb);
+                self.memwrite(bus, address, vlsb);
+                self.memwrite(bus, address + 1, vmsb);
             }
 
             _ => panic!("not a u16 operand for set"),
         }
     }
 
-    fn get_operand_as_u16(&mut self, op: Operand) -> u16 {
+    fn get_operand_as_u16(&mut self, bus: &mut Bus, op: Operand) -> u16 {
         match op {
-            R16(register) => return self.registers.get_16register(register),
+            R16(register) => self.registers.get_16register(register),
             Imm16 => {
-                let lsb: u8 = self.get_operand_as_u8(Imm8);
-                let msb: u8 = self.get_operand_as_u8(Imm8);
-                return CPU::fuse_u8(lsb, msb);
+                let lsb: u8 = self.get_operand_as_u8(bus, Imm8);
+                let msb: u8 = self.get_operand_as_u8(bus, Imm8);
+                CPU::fuse_u8(lsb, msb)
             }
-            Address(Fixed(value)) => return value,
-            Value(value) => return value,
+            Address(Fixed(value)) => value,
+            Value(value) => value,
             _ => panic!("not a u16 operand for get! "),
         }
     }
@@ -363,36 +408,35 @@ impl CPU {
         ((msb as u16) << 8) | (lsb as u16)
     }
 
-    fn memwrite(&mut self, address: u16, value: u8) {
-        if address == DIV {
-            self.div_counter = 0;
-            self.write(DIV, 0);
-            return;
-        }
-
-        self.write(address, value);
+    fn memwrite(&mut self, bus: &mut Bus, address: u16, value: u8) {
+        self.write(bus, address, value);
     }
     // ============= Loading =============
 
     //this implements both ldh and ld
-    pub(crate) fn ld_u8(&mut self, destination: Operand, source: Operand) {
-        let val_to_load: u8 = self.get_operand_as_u8(source);
-        self.set_operand_from_u8(destination, val_to_load);
+    pub(crate) fn ld_u8(&mut self, bus: &mut Bus, destination: Operand, source: Operand) {
+        let val_to_load: u8 = self.get_operand_as_u8(bus, source);
+        self.set_operand_from_u8(bus, destination, val_to_load);
     }
 
-    pub(crate) fn ld_u16(&mut self, destination: Operand, source: Operand) {
+    pub(crate) fn ld_u16(&mut self, bus: &mut Bus, destination: Operand, source: Operand) {
         // I think this doesn't properly implement LD [a16] SP
-        let val_to_load: u16 = self.get_operand_as_u16(source);
-        self.set_operand_to_u16(destination, val_to_load);
+        let val_to_load: u16 = self.get_operand_as_u16(bus, source);
+        self.set_operand_to_u16(bus, destination, val_to_load);
+        // LD SP, HL
+        if matches!((destination, source), (R16(_), R16(_))) {
+            self.cycle(bus);
+        }
     }
 
-    pub(crate) fn ld_u16_e8(&mut self, destination: Operand, _source: Operand) {
-        let e8 = self.get_operand_as_u8(Operand::Imm8) as i8;
-        let sp = self.get_operand_as_u16(Operand::R16(Reg16::SP));
+    pub(crate) fn ld_u16_e8(&mut self, bus: &mut Bus, destination: Operand, _source: Operand) {
+        let e8 = self.get_operand_as_u8(bus, Operand::Imm8) as i8;
+        let sp = self.get_operand_as_u16(bus, Operand::R16(Reg16::SP));
 
         let result = sp.wrapping_add_signed(e8 as i16);
+        self.cycle(bus);
 
-        self.set_operand_to_u16(destination, result);
+        self.set_operand_to_u16(bus, destination, result);
 
         let h = ((sp & 0xF) + ((e8 as u16) & 0xF)) > 0xF;
         let c = ((sp & 0xFF) + ((e8 as u16) & 0xFF)) > 0xFF;
@@ -401,21 +445,21 @@ impl CPU {
 
     // ============= Arithmetic =============
 
-    pub(crate) fn add(&mut self, op1: Operand, op2: Operand) {
-        let value1: u8 = self.get_operand_as_u8(op1);
-        let value2: u8 = self.get_operand_as_u8(op2);
+    pub(crate) fn add(&mut self, bus: &mut Bus, op1: Operand, op2: Operand) {
+        let value1: u8 = self.get_operand_as_u8(bus, op1);
+        let value2: u8 = self.get_operand_as_u8(bus, op2);
         let (result, overflowed) = value1.overflowing_add(value2);
 
         //Addition always stores back on a register
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
 
         let half_carry: bool = ((value1 & 0xF) + (value2 & 0xF)) > 0xF;
         self.update_flags(result == 0, false, half_carry, overflowed);
     }
 
-    pub(crate) fn adc(&mut self, op1: Operand, op2: Operand) {
-        let a: u8 = self.get_operand_as_u8(op1);
-        let n: u8 = self.get_operand_as_u8(op2);
+    pub(crate) fn adc(&mut self, bus: &mut Bus, op1: Operand, op2: Operand) {
+        let a: u8 = self.get_operand_as_u8(bus, op1);
+        let n: u8 = self.get_operand_as_u8(bus, op2);
         let carry_in: u8 = self.registers.get_flag(CARRY) as u8;
 
         let sum16 = a as u16 + n as u16 + carry_in as u16;
@@ -423,13 +467,13 @@ impl CPU {
         let half_carry = ((a & 0xF) + (n & 0xF) + carry_in) > 0xF;
         let carry = sum16 > 0xFF;
 
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
         self.update_flags(result == 0, false, half_carry, carry);
     }
 
-    pub(crate) fn sbc(&mut self, op1: Operand, op2: Operand) {
-        let a = self.get_operand_as_u8(op1);
-        let n = self.get_operand_as_u8(op2);
+    pub(crate) fn sbc(&mut self, bus: &mut Bus, op1: Operand, op2: Operand) {
+        let a = self.get_operand_as_u8(bus, op1);
+        let n = self.get_operand_as_u8(bus, op2);
         let carry_in = self.registers.get_flag(CARRY) as u8;
 
         let n_and_carry = n.wrapping_add(carry_in);
@@ -439,73 +483,73 @@ impl CPU {
         let half_carry = (a & 0xF) < ((n & 0xF) + carry_in);
         let carry = (a as u16) < (n as u16 + carry_in as u16);
 
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
         self.update_flags(result == 0, true, half_carry, carry);
     }
 
-    pub(crate) fn inc(&mut self, op1: Operand) {
-        let value: u8 = self.get_operand_as_u8(op1);
+    pub(crate) fn inc(&mut self, bus: &mut Bus, op1: Operand) {
+        let value: u8 = self.get_operand_as_u8(bus, op1);
         let (result, overflowed) = value.overflowing_add(1);
 
         //Addition always stores back on a register
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
 
         let half_carry: bool = ((value & 0xF) + (1 & 0xF)) > 0xF;
         let carry: bool = self.registers.get_flag(CARRY);
         self.update_flags(result == 0, false, half_carry, carry);
     }
 
-    pub(crate) fn sub(&mut self, op1: Operand, op2: Operand) {
-        let value1: u8 = self.get_operand_as_u8(op1);
-        let value2: u8 = self.get_operand_as_u8(op2);
+    pub(crate) fn sub(&mut self, bus: &mut Bus, op1: Operand, op2: Operand) {
+        let value1: u8 = self.get_operand_as_u8(bus, op1);
+        let value2: u8 = self.get_operand_as_u8(bus, op2);
         let result = value1.wrapping_sub(value2);
 
         //Addition always stores back on a register
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
 
         let half_carry: bool = (value1 & 0xF) < (value2 & 0xF);
         let c_flag = value1 < value2; // full-borrow
         self.update_flags(result == 0, true, half_carry, c_flag);
     }
 
-    pub(crate) fn dec(&mut self, op1: Operand) {
-        let value: u8 = self.get_operand_as_u8(op1);
+    pub(crate) fn dec(&mut self, bus: &mut Bus, op1: Operand) {
+        let value: u8 = self.get_operand_as_u8(bus, op1);
         let result = value.wrapping_sub(1);
 
-        self.set_operand_from_u8(op1, result);
+        self.set_operand_from_u8(bus, op1, result);
 
         let half_carry: bool = (value & 0xF) == 0;
         let keep_carry: bool = self.registers.get_flag(CARRY);
         self.update_flags(result == 0, true, half_carry, keep_carry);
     }
 
-    pub(crate) fn and(&mut self, source: Operand, op: Operand) {
-        let register_value = self.get_operand_as_u8(source);
-        let result: u8 = register_value & self.get_operand_as_u8(op);
+    pub(crate) fn and(&mut self, bus: &mut Bus, source: Operand, op: Operand) {
+        let register_value = self.get_operand_as_u8(bus, source);
+        let result: u8 = register_value & self.get_operand_as_u8(bus, op);
 
-        self.set_operand_from_u8(source, result);
+        self.set_operand_from_u8(bus, source, result);
         self.update_flags(result == 0, false, true, false);
     }
 
-    pub(crate) fn or(&mut self, source: Operand, op: Operand) {
-        let register_value = self.get_operand_as_u8(source);
-        let result: u8 = register_value | self.get_operand_as_u8(op);
+    pub(crate) fn or(&mut self, bus: &mut Bus, source: Operand, op: Operand) {
+        let register_value = self.get_operand_as_u8(bus, source);
+        let result: u8 = register_value | self.get_operand_as_u8(bus, op);
 
-        self.set_operand_from_u8(source, result);
+        self.set_operand_from_u8(bus, source, result);
         self.update_flags(result == 0, false, false, false);
     }
 
-    pub(crate) fn xor(&mut self, source: Operand, op: Operand) {
-        let register_value = self.get_operand_as_u8(source);
-        let result: u8 = register_value ^ self.get_operand_as_u8(op);
-        self.set_operand_from_u8(source, result);
+    pub(crate) fn xor(&mut self, bus: &mut Bus, source: Operand, op: Operand) {
+        let register_value = self.get_operand_as_u8(bus, source);
+        let result: u8 = register_value ^ self.get_operand_as_u8(bus, op);
+        self.set_operand_from_u8(bus, source, result);
 
         self.update_flags(result == 0, false, false, false);
     }
 
-    pub(crate) fn cp(&mut self, source: Operand, op: Operand) {
-        let register_value = self.get_operand_as_u8(source);
-        let to_sub: u8 = self.get_operand_as_u8(op);
+    pub(crate) fn cp(&mut self, bus: &mut Bus, source: Operand, op: Operand) {
+        let register_value = self.get_operand_as_u8(bus, source);
+        let to_sub: u8 = self.get_operand_as_u8(bus, op);
 
         let (result, carry) = register_value.overflowing_sub(to_sub);
 
@@ -514,30 +558,33 @@ impl CPU {
     }
 
     // ============= reg16 Arithmetic =============
-    pub(crate) fn inc_u16(&mut self, op: Operand) {
-        let value = self.get_operand_as_u16(op);
-        self.set_operand_to_u16(op, value.wrapping_add(1));
+    pub(crate) fn inc_u16(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u16(bus, op);
+        self.set_operand_to_u16(bus, op, value.wrapping_add(1));
+        self.cycle(bus);
     }
 
-    pub(crate) fn dec_u16(&mut self, op: Operand) {
-        let value = self.get_operand_as_u16(op);
-        self.set_operand_to_u16(op, value.wrapping_sub(1));
+    pub(crate) fn dec_u16(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u16(bus, op);
+        self.set_operand_to_u16(bus, op, value.wrapping_sub(1));
+        self.cycle(bus);
     }
 
-    pub(crate) fn add_hl_rr(&mut self, src: Operand) {
+    pub(crate) fn add_hl_rr(&mut self, bus: &mut Bus, src: Operand) {
         let hl = self.registers.get_16register(Reg16::HL);
-        let value = self.get_operand_as_u16(src);
+        let value = self.get_operand_as_u16(bus, src);
 
         let (result, carry) = hl.overflowing_add(value);
         let half_carry = ((hl & 0x0FFF) + (value & 0x0FFF)) > 0x0FFF;
 
         self.registers.set_16register(Reg16::HL, result);
         self.update_flags(self.registers.get_flag(ZERO), false, half_carry, carry);
+        self.cycle(bus);
     }
 
-    pub(crate) fn add_sp_e8(&mut self) {
+    pub(crate) fn add_sp_e8(&mut self, bus: &mut Bus) {
         let sp = self.registers.get_16register(Reg16::SP);
-        let offset = self.get_operand_as_u8(Operand::Imm8) as i8 as i16; // signed immediate
+        let offset = self.get_operand_as_u8(bus, Operand::Imm8) as i8 as i16; // signed immediate
 
         let result = sp.wrapping_add(offset as u16);
 
@@ -546,41 +593,39 @@ impl CPU {
 
         self.registers.set_16register(Reg16::SP, result);
         self.update_flags(false, false, half_carry, carry);
+        self.cycle(bus);
+        self.cycle(bus);
     }
 
     // ============= Jumps and Calls =============
 
-    pub(crate) fn jr(&mut self, condition: Operand, op: Operand) {
+    pub(crate) fn jr(&mut self, bus: &mut Bus, condition: Operand, op: Operand) {
         // this increments PC
-        let offset = self.get_operand_as_u8(op) as i8 as i16;
+        let offset = self.get_operand_as_u8(bus, op) as i8 as i16;
 
         if self.check_condition(condition) {
             let pc: u16 = self.registers.get_16register(PC);
             self.registers
                 .set_16register(PC, (pc as i16).wrapping_add(offset) as u16);
-            self.clock = self.clock.wrapping_add(12 as u64);
-        } else {
-            self.clock = self.clock.wrapping_add(8 as u64);
+            self.cycle(bus);
         }
     }
 
-    pub(crate) fn jp(&mut self, condition: Operand, address: Operand) {
-        let address = self.get_operand_as_u16(address);
+    pub(crate) fn jp(&mut self, bus: &mut Bus, condition: Operand, address_operand: Operand) {
+        let address = self.get_operand_as_u16(bus, address_operand);
         if self.check_condition(condition) {
             self.registers.set_16register(PC, address);
-            if matches!(R16(HL), address) {
-                self.clock = self.clock.wrapping_add(4);
-            } else {
-                self.clock = self.clock.wrapping_add(16);
+            // JP HL doesn't need an extra cycle to load PC
+            if !matches!(address_operand, R16(HL)) {
+                self.cycle(bus);
             }
-        } else {
-            self.clock = self.clock.wrapping_add(12 as u64);
         }
     }
 
-    pub(crate) fn call(&mut self, condition: Operand, address_operand: Operand) {
-        let addr = self.get_operand_as_u16(address_operand);
+    pub(crate) fn call(&mut self, bus: &mut Bus, condition: Operand, address_operand: Operand) {
+        let addr = self.get_operand_as_u16(bus, address_operand);
         if self.check_condition(condition) {
+            self.cycle(bus);
             let pc = self.registers.get_16register(PC);
             let mut sp = self.registers.get_16register(SP);
 
@@ -590,22 +635,18 @@ impl CPU {
             let (lsb, msb) = CPU::split_u16(pc);
 
             sp = sp.wrapping_sub(1);
-            self.memwrite(sp, msb); // LSB
+            self.memwrite(bus, sp, msb); // LSB
             sp = sp.wrapping_sub(1);
-            self.memwrite(sp, lsb); // MSB
+            self.memwrite(bus, sp, lsb); // MSB
 
             //updating registers accordingly
             self.registers.set_16register(SP, sp);
             self.registers.set_16register(PC, addr);
-
-            self.clock = self.clock.wrapping_add(24 as u64);
-        } else {
-            self.clock = self.clock.wrapping_add(12 as u64);
         }
     }
 
-    pub(crate) fn rst(&mut self, address: Operand) {
-        let addr = self.get_operand_as_u16(address);
+    pub(crate) fn rst(&mut self, bus: &mut Bus, address: Operand) {
+        let addr = self.get_operand_as_u16(bus, address);
         let pc = self.registers.get_16register(PC);
         let mut sp = self.registers.get_16register(SP);
 
@@ -613,124 +654,114 @@ impl CPU {
         let msb = (pc >> 8) as u8;
         let lsb = (pc & 0xFF) as u8;
 
+        self.cycle(bus);
         sp = sp.wrapping_sub(1);
-        self.memwrite(sp, msb);
+        self.memwrite(bus, sp, msb);
         sp = sp.wrapping_sub(1);
-        self.memwrite(sp, lsb);
+        self.memwrite(bus, sp, lsb);
 
         self.registers.set_16register(SP, sp);
         self.registers.set_16register(PC, addr);
-
-        self.clock += 16; // RST always 16 cycles
     }
 
-    pub(crate) fn ret(&mut self, condition: Operand) {
+    pub(crate) fn ret(&mut self, bus: &mut Bus, condition: Operand) {
+        // Checking the condition takes a cycle of its own
+        if !matches!(condition, Flag(None)) {
+            self.cycle(bus);
+        }
         if self.check_condition(condition) {
             let mut sp = self.registers.get_16register(SP);
             //Not sure of ordering here, is the stack little or big endian?
-            let lsb = self.read(sp);
+            let lsb = self.read(bus, sp);
             sp = sp.wrapping_add(1);
-            let msb = self.read(sp);
+            let msb = self.read(bus, sp);
             sp = sp.wrapping_add(1);
 
             let address = CPU::fuse_u8(lsb, msb);
             self.registers.set_16register(SP, sp);
             self.registers.set_16register(PC, address);
-
-            if matches!(Operand::Flag(None), condition) {
-                self.clock = self.clock.wrapping_add(16 as u64);
-                self.clock += 16;
-            } else {
-                self.clock = self.clock.wrapping_add(20 as u64);
-            }
-        } else {
-            self.clock = self.clock.wrapping_add(8 as u64);
+            self.cycle(bus);
         }
     }
 
-    pub(crate) fn reti(&mut self) {
-        self.ret(Flag(None));
-        self.ei();
+    pub(crate) fn reti(&mut self, bus: &mut Bus) {
+        self.ret(bus, Flag(None));
+        // Unlike EI, RETI enables interrupts right away
+        self.ime = true;
     }
 
-    pub(crate) fn push(&mut self, op: Operand) {
-        let value = self.get_operand_as_u16(op);
+    pub(crate) fn push(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u16(bus, op);
         let (lsb, msb) = CPU::split_u16(value);
 
         let mut sp = self.registers.get_16register(SP);
 
+        self.cycle(bus);
         sp = sp.wrapping_sub(1);
-        self.memwrite(sp, msb);
+        self.memwrite(bus, sp, msb);
         sp = sp.wrapping_sub(1);
-        self.memwrite(sp, lsb);
+        self.memwrite(bus, sp, lsb);
 
         self.registers.set_16register(SP, sp);
     }
 
-    pub(crate) fn pop(&mut self, op: Operand) {
+    pub(crate) fn pop(&mut self, bus: &mut Bus, op: Operand) {
         let mut sp = self.registers.get_16register(SP);
 
-        let lsb = self.read(sp);
+        let lsb = self.read(bus, sp);
         sp = sp.wrapping_add(1);
-        let msb = self.read(sp);
+        let msb = self.read(bus, sp);
         sp = sp.wrapping_add(1);
 
         let value = CPU::fuse_u8(lsb, msb);
 
-        self.set_operand_to_u16(op, value);
+        self.set_operand_to_u16(bus, op, value);
         self.registers.set_16register(SP, sp);
     }
 
     // ============= Interrupts and Misc =============
 
-    pub(crate) fn ei(&mut self) {
+    pub(crate) fn ei(&mut self, bus: &mut Bus) {
         self.ime_pending = true;
     }
 
-    pub(crate) fn di(&mut self) {
+    pub(crate) fn di(&mut self, bus: &mut Bus) {
         self.ime = false; // Immediately disable interrupts
         self.ime_pending = false; // Make sure no pending enable remains
     }
 
-    pub(crate) fn nop(&mut self) {}
-
-    pub(crate) fn halt(&mut self) {
-        self.halted = true;
-
-        /*
-
-        FOR FUTURE ME:
-        You need to handle the HALT bug:
-        if IME = 0 and an interrupt is pending,
-        the PC increments incorrectly.
-        while running {
-            if cpu.halted {
-            // Resume execution only if an interrupt is pending
-            if cpu.interrupt_pending() {
-                cpu.halted = false;
-            } else {
-                // CPU is halted, skip instruction fetch/execute
-                cpu.clock += 4; // CPU still increments clock slowly
-                continue;
-            }
+    pub(crate) fn nop(&mut self, bus: &mut Bus) {}
+
+    // HALT waits for an interrupt to be pending, serviced or not depending on IME.
+    // If one already is, the CPU doesn't halt at all:
+    // - IME set: it is serviced right after HALT, as usual
+    // - IME set by an EI just before: the HALT bug happens, so the return address
+    //   pushed by the dispatch is the HALT itself, which runs again after RETI
+    // - IME clear: the HALT bug, PC fails to increment after the next opcode fetch
+    pub(crate) fn halt(&mut self, bus: &mut Bus) {
+        if !self.interrupt_pending(bus) {
+            self.halted = true;
+        } else if !self.ime {
+            self.halt_bug = true;
+        } else if self.ime_delayed {
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
         }
-        */
     }
 
     //set carry flag
-    pub(crate) fn scf(&mut self) {
+    pub(crate) fn scf(&mut self, bus: &mut Bus) {
         let zero = self.registers.get_flag(ZERO);
         self.update_flags(zero, false, false, true);
     }
 
     //complement carry flag
-    pub(crate) fn ccf(&mut self) {
+    pub(crate) fn ccf(&mut self, bus: &mut Bus) {
         let zero = self.registers.get_flag(ZERO);
         let carry = self.registers.get_flag(CARRY);
         self.update_flags(zero, false, false, !carry);
     }
 
-    pub(crate) fn cpl(&mut self) {
+    pub(crate) fn cpl(&mut self, bus: &mut Bus) {
         let a = self.registers.get_u8register(Reg8::A);
         let result = !a;
 
@@ -739,7 +770,7 @@ impl CPU {
         self.registers.set_flag(HALFCARRY, true);
     }
 
-    pub(crate) fn daa(&mut self) {
+    pub(crate) fn daa(&mut self, bus: &mut Bus) {
         let mut a = self.registers.a;
         let mut adjust = 0u8;
 
@@ -767,182 +798,103 @@ impl CPU {
         self.registers.set_flag(HALFCARRY, false);
     }
 
-    pub(crate) fn rlca(&mut self) {
-        self.rlc(R8(Reg8::A));
-        self.clock = self.clock.wrapping_sub(4);
+    pub(crate) fn rlca(&mut self, bus: &mut Bus) {
+        self.rlc(bus, R8(Reg8::A));
         let keep_carry = self.registers.get_flag(CARRY);
         self.update_flags(false, false, false, keep_carry);
     }
-    pub(crate) fn rla(&mut self) {
-        self.rl(R8(Reg8::A));
-        self.clock = self.clock.wrapping_sub(4);
+    pub(crate) fn rla(&mut self, bus: &mut Bus) {
+        self.rl(bus, R8(Reg8::A));
         let keep_carry = self.registers.get_flag(CARRY);
         self.update_flags(false, false, false, keep_carry);
     }
-    pub(crate) fn rrca(&mut self) {
-        self.rrc(R8(Reg8::A));
-        self.clock = self.clock.wrapping_sub(4);
+    pub(crate) fn rrca(&mut self, bus: &mut Bus) {
+        self.rrc(bus, R8(Reg8::A));
         let keep_carry = self.registers.get_flag(CARRY);
         self.update_flags(false, false, false, keep_carry);
     }
-    pub(crate) fn rra(&mut self) {
-        self.rr(R8(Reg8::A));
-        self.clock = self.clock.wrapping_sub(4);
+    pub(crate) fn rra(&mut self, bus: &mut Bus) {
+        self.rr(bus, R8(Reg8::A));
         let keep_carry = self.registers.get_flag(CARRY);
         self.update_flags(false, false, false, keep_carry);
     }
 
-    pub(crate) fn stop(&mut self, op: Operand) {
-        self.reset_div();
-        //println!("STOP");
-        //TODO: RESET DIV ON STOP
-        //panic!("Is STOP really needed?");
-        //This has some weird hardware behavior
-        //Chek a lot of documentation if implemented in future!
+    // What STOP does on DMG depends on the joypad lines and on pending interrupts:
+    // - Button held, interrupt pending: 1 byte long, nothing happens
+    // - Button held, nothing pending: 2 bytes long, the CPU halts
+    // - No button held: STOP mode with DIV reset, 2 bytes long only if nothing is pending
+    pub(crate) fn stop(&mut self, bus: &mut Bus, op: Operand) {
+        let pending = self.interrupt_pending(bus);
+        if !pending {
+            // The byte after STOP is skipped
+            self.get_operand_as_u8(bus, op);
+        }
+
+        if bus.joypad_line_low() {
+            self.halted = !pending;
+        } else {
+            bus.write(DIV, 0, false);
+            self.stopped = true;
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
     }
-    // Call this after every instruction fetch/execute
+
+    // A selected joypad line went low
+    pub fn wake_from_stop(&mut self) {
+        self.stopped = false;
+    }
+
+    // EI only takes effect after the instruction following it, this is called before
+    // each instruction so the interrupt check right after that one still sees IME off
     fn update_ime(&mut self) {
+        self.ime_delayed = self.ime_pending;
         if self.ime_pending {
             self.ime = true;
             self.ime_pending = false;
         }
     }
 
-    fn interrupt_pending(&mut self) -> bool {
-        let (ie, iflag) = self.get_interrupt_registers();
-        (ie & iflag) != 0
-    }
-
-    fn get_interrupt_registers(&mut self) -> (u8, u8) {
-        let ie = self.read(IE);
-        let iflag = self.read(IF);
-        (ie, iflag)
+    fn interrupt_pending(&mut self, bus: &mut Bus) -> bool {
+        bus.pending_interrupts() != 0
     }
 
-    const PRIORITY: [Interrupt; 5] = [
-        Interrupt::VBlank,
-        Interrupt::LCDStat,
-        Interrupt::Timer,
-        Interrupt::Serial,
-        Interrupt::Joypad,
-    ];
-
-    fn handle_interrupts(&mut self) {
-        let (ie, iflag) = self.get_interrupt_registers();
-        let pending = ie & iflag;
-
-        if !self.ime {
-            return;
+    fn handle_interrupts(&mut self, bus: &mut Bus) {
+        if self.ime && self.interrupt_pending(bus) {
+            self.service_interrupt(bus);
         }
+    }
 
-        for (idx, interrupt) in CPU::PRIORITY.iter().enumerate() {
-            let mask = 1 << idx;
-            let is_pending = pending & mask != 0;
-            if is_pending {
-                
-                
-                if matches!(interrupt, Interrupt::Timer) {
-                    continue;
-                }
-                if matches!(interrupt, Interrupt::Joypad) {
-                    println!(
-                        "Servicing : {:?} IE : {} IF : {} Jumps to {:02X}",
-                        interrupt,
-                        self.read(IE),
-                        self.read(IF),
-                        *interrupt as u16
-                    );
-                }
+    // Interrupt takes 5 M-cycles: 2 waiting, 2 for the push and 1 for the jump
+    fn service_interrupt(&mut self, bus: &mut Bus) {
+        self.ime = false;
+        self.ime_pending = false;
 
-                let new_iflag = iflag & !mask;
-                self.write(IF, new_iflag);
-                self.service_interrupt(*interrupt as u16);
-                return;
-            }
-        }
-    }
+        self.cycle(bus);
+        self.cycle(bus);
 
-    fn service_interrupt(&mut self, vector: u16) {
         // Push current PC (points to next instruction)
         let pc = self.registers.pc;
         let (low, high) = (pc as u8, (pc >> 8) as u8);
-
         let mut sp = self.registers.sp;
         sp = sp.wrapping_sub(1);
-        self.memwrite(sp, high);
-        sp = sp.wrapping_sub(1);
-        self.memwrite(sp, low);
-        self.registers.sp = sp;
-
-        self.registers.pc = vector;
-
-        // Interrupt takes 20 cycles total (12 for push + 8 for jump)
-        self.clock = self.clock.wrapping_add(20);
-
-        // Disable interrupts
-        self.ime = false;
-        self.ime_pending = false;
-    }
+        self.memwrite(bus, sp, high);
 
-    //Timer
-    fn reset_div(&mut self) {
-        self.div_counter = 0;
-        self.write(DIV, 0);
-    }
-
-    fn tick_div(&mut self) {
-        self.div_counter = self.div_counter.wrapping_add(1);
-        self.write(DIV, self.div_counter as u8);
-    }
-
-    fn tac_info(&mut self) -> (bool, u8) {
-        let tac = self.read(TAC);
-        let timer_enabled = tac & 0x04 != 0;
-        let clock_select = tac & 0x03;
-        let div_bit_to_count: u8 = {
-            match clock_select {
-                0x00 => 7,
-                0x01 => 1,
-                0x10 => 3,
-                0x11 => 5,
-                _ => 1, //unreachable!(), HOW IS THIS EVEN POSSIBLE?
-            }
+        // The interrupt is only chosen now, if the push overwrote IE and nothing is
+        // left pending, the dispatch is cancelled and jumps to 0x0000
+        let vector = match bus.take_interrupt() {
+            Some(interrupt) => interrupt.vector(),
+            Option::None => 0x0000,
         };
-        (timer_enabled, div_bit_to_count)
-    }
-    fn increment_tima(&mut self) {
-        let tima = self.read(TIMA);
-        if tima == 0xFF {
-            let tma = self.read(TMA);
-            self.write(TIMA, tma);
-            let if_reg = self.read(IF) | 0x04;
-            self.write(IF, if_reg);
-        } else {
-            self.write(TIMA, tima.wrapping_add(1));
-        }
-    }
-
-    fn tick_timer_once(&mut self) {
-        self.tick_div();
 
-        let (enabled, div_bit) = self.tac_info();
-
-        if !enabled {
-            return;
-        }
-
-        let prev = ((self.div_counter.wrapping_sub(1)) >> div_bit) & 1;
-        let curr = (self.div_counter >> div_bit) & 1;
-
-        if prev == 1 && curr == 0 {
-            self.increment_tima();
-        }
-    }
+        sp = sp.wrapping_sub(1);
+        self.memwrite(bus, sp, low);
+        self.registers.sp = sp;
 
-    fn advance_timer(&mut self, cycles: u8) {
-        for i in 0..cycles {
-            self.tick_timer_once();
-        }
+        self.registers.pc = vector;
+        self.cycle(bus);
     }
 
     // ==================== ENDOF NEW AND IMPROVED FUNCS ====================
@@ -950,85 +902,85 @@ impl CPU {
     // $CB Prefixed
 
     //rorates r in cirular manner to the left,7bit is copied to 0 and carry
-    pub(crate) fn rlc(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn rlc(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let msb = value >> 7;
         let result = (value << 1) | msb;
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, msb == 1);
     }
 
-    pub(crate) fn rrc(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn rrc(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let lsb = value & 1;
         let result = (value >> 1) | (lsb << 7);
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, lsb == 1);
     }
 
     // shift to the left, but discard to carry, and use carry to fill
-    pub(crate) fn rl(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn rl(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let carry = self.registers.get_flag(CARRY) as u8;
         let msb = value >> 7;
         let result = (value << 1) | carry;
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, msb == 1)
     }
 
-    pub(crate) fn rr(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn rr(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let carry = self.registers.get_flag(CARRY) as u8;
         let lsb = value & 1;
         let result = (value >> 1) | (carry << 7);
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, lsb == 1);
     }
 
     // same as rl but instead place 0 in the made gap
-    pub(crate) fn sla(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn sla(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let msb = value >> 7;
         let result = value << 1;
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, msb == 1)
     }
-    pub(crate) fn sra(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn sra(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let msb = value >> 7;
         let lsb = value & 1;
 
         //not certain about this special case
         let result = value >> 1 | (msb << 7);
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, lsb == 1)
     }
 
-    pub(crate) fn swap(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
-        let result = (value >> 4) | (value << 4);
-        self.set_operand_from_u8(op, result);
+    pub(crate) fn swap(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
+        let result = value.rotate_left(4);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, false);
     }
 
-    pub(crate) fn srl(&mut self, op: Operand) {
-        let value = self.get_operand_as_u8(op);
+    pub(crate) fn srl(&mut self, bus: &mut Bus, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
         let lsb = value & 1;
         let result = value >> 1;
 
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
         self.update_flags(result == 0, false, false, lsb == 1);
     }
 
-    pub(crate) fn bit(&mut self, bit_idx: Operand, op: Operand) {
-        let value = self.get_operand_as_u8(op);
-        let bit_index = self.get_operand_as_u8(bit_idx);
+    pub(crate) fn bit(&mut self, bus: &mut Bus, bit_idx: Operand, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
+        let bit_index = self.get_operand_as_u8(bus, bit_idx);
 
         let bit_is_0 = (value >> bit_index) & 1 == 0;
         self.registers.set_flag(ZERO, bit_is_0);
@@ -1036,24 +988,25 @@ impl CPU {
         self.registers.set_flag(HALFCARRY, true);
     }
 
-    pub(crate) fn res(&mut self, bit_idx: Operand, op: Operand) {
-        let value = self.get_operand_as_u8(op);
-        let bit_index = self.get_operand_as_u8(bit_idx);
+    pub(crate) fn res(&mut self, bus: &mut Bus, bit_idx: Operand, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
+        let bit_index = self.get_operand_as_u8(bus, bit_idx);
 
         let result = value & !(1 << bit_index);
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
     } //set nth bit to 0 
-    pub(crate) fn set(&mut self, bit_idx: Operand, op: Operand) {
-        let value = self.get_operand_as_u8(op);
-        let bit_index = self.get_operand_as_u8(bit_idx);
+    pub(crate) fn set(&mut self, bus: &mut Bus, bit_idx: Operand, op: Operand) {
+        let value = self.get_operand_as_u8(bus, op);
+        let bit_index = self.get_operand_as_u8(bus, bit_idx);
 
         let result = value | (1 << bit_index);
-        self.set_operand_from_u8(op, result);
+        self.set_operand_from_u8(bus, op, result);
     }
 }
 
 // ================================== REGISTERS =============================
 
+#[derive(Clone)]
 pub struct Registers {
     a: u8,
     f: u8, // Flags register 4: carry 5: half_carry 6: sub 7: zero
@@ -1079,9 +1032,9 @@ impl Registers {
     //Think about maybe using #[inline(always)]
     fn set_flag(&mut self, bit_idx: u8, flag: bool) {
         if flag {
-            self.f = self.f | ((flag as u8) << bit_idx); //Bitwise or 
+            self.f |= (flag as u8) << bit_idx; //Bitwise or
         } else {
-            self.f = self.f & !(1 << bit_idx);
+            self.f &= !(1 << bit_idx);
         }
     }
 
@@ -1152,3 +1105,276 @@ impl Registers {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Button, InputState};
+    use crate::ppu::RendererKind;
+
+    const STAT: u16 = 0xFF41;
+
+    // The CPU starts at 0x0100, where the program goes
+    fn machine(program: &[u8]) -> (CPU, Bus) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let mut bus = Bus::new(RendererKind::Fifo);
+        bus.load_rom_data(&rom);
+        let cpu = CPU::new(&mut bus);
+        (cpu, bus)
+    }
+
+    // What a register reads after each of the next M-cycles, on a copy of the bus
+    fn reads_after(bus: &Bus, address: u16, m_cycles: usize) -> Vec<u8> {
+        let mut bus = bus.clone();
+        (0..m_cycles)
+            .map(|_| {
+                bus.tick(4);
+                bus.read(address, false)
+            })
+            .collect()
+    }
+
+    // Idles until the register changes between the given M-cycle and the next one
+    fn idle_until_change(bus: &mut Bus, address: u16, m_cycle: usize) -> (u8, u8) {
+        for _ in 0..1000 {
+            let reads = reads_after(bus, address, m_cycle + 1);
+            if reads[m_cycle - 1] != reads[m_cycle] {
+                return (reads[m_cycle - 1], reads[m_cycle]);
+            }
+            bus.tick(4);
+        }
+        panic!("{address:04X} never changes");
+    }
+
+    // LDH A,(n) reads in its third M-cycle, so it sees TIMA go up right before it
+    #[test]
+    fn read_sees_timer_mid_instruction() {
+        let (mut cpu, mut bus) = machine(&[0xF0, 0x05]); // LDH A,(TIMA)
+        bus.write(TAC, 0x05, false); // TIMA goes up every 16 T-cycles
+        let (before, after) = idle_until_change(&mut bus, TIMA, 2);
+
+        assert_eq!(cpu.step(&mut bus), 12);
+        assert_ne!(before, after);
+        assert_eq!(cpu.registers.a, after);
+    }
+
+    // The PPU is caught up to the M-cycle of the read, not to the start of the
+    // instruction
+    #[test]
+    fn read_sees_ppu_mode_mid_instruction() {
+        let (mut cpu, mut bus) = machine(&[0xF0, 0x41]); // LDH A,(STAT)
+        let (before, after) = idle_until_change(&mut bus, STAT, 2);
+
+        cpu.step(&mut bus);
+        assert_ne!(before, after);
+        assert_eq!(cpu.registers.a, after);
+    }
+
+    // LDH (n),A writes in its third M-cycle: DIV, reset then, goes up 256 T-cycles later
+    #[test]
+    fn write_lands_on_its_m_cycle() {
+        let (mut cpu, mut bus) = machine(&[0xE0, 0x04]); // LDH (DIV),A
+        cpu.step(&mut bus);
+
+        let reads = reads_after(&bus, DIV, 64);
+        assert_eq!(reads[62], 0);
+        assert_eq!(reads[63], 1);
+    }
+
+    // HALT with an interrupt pending but IME off doesn't halt, and the byte after it is
+    // read twice
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        let (mut cpu, mut bus) = machine(&[0x76, 0x3C, 0x00]); // HALT, INC A, NOP
+        bus.write(IE, 0x04, false);
+        bus.write(IF, 0x04, false);
+        let a = cpu.registers.a;
+
+        cpu.step(&mut bus);
+        assert!(!cpu.halted);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0101);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0102);
+        assert_eq!(cpu.registers.a, a.wrapping_add(2));
+    }
+
+    // Right after EI, HALT with an interrupt pending dispatches it at once, and it
+    // returns to the HALT
+    #[test]
+    fn ei_then_halt_returns_to_the_halt() {
+        let (mut cpu, mut bus) = machine(&[0xFB, 0x76]); // EI, HALT
+        bus.write(IE, 0x04, false);
+        bus.write(IF, 0x04, false);
+
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0050);
+        let sp = cpu.registers.sp;
+        assert_eq!(bus.read(sp, false), 0x01);
+        assert_eq!(bus.read(sp.wrapping_add(1), false), 0x01);
+    }
+
+    // An interrupt raised between steps wakes the CPU on the next one, without idling
+    // until the next event: one M-cycle to wake up, five for the dispatch
+    #[test]
+    fn halt_wakes_on_interrupt_raised_between_steps() {
+        let (mut cpu, mut bus) = machine(&[0xFB, 0x76]); // EI, HALT
+        bus.write(IE, 0x04, false);
+
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert!(cpu.halted);
+        bus.write(IF, 0x04, false);
+
+        assert_eq!(cpu.step(&mut bus), 24);
+        assert_eq!(cpu.registers.pc, 0x0050);
+    }
+
+    // The dispatch after an instruction takes 5 M-cycles and pushes the address of the
+    // next one
+    #[test]
+    fn dispatch_takes_five_m_cycles() {
+        let (mut cpu, mut bus) = machine(&[0x00]); // NOP
+        cpu.ime = true;
+        bus.write(IE, 0x04, false);
+        bus.write(IF, 0x04, false);
+
+        assert_eq!(cpu.step(&mut bus), 4 + 20);
+        assert_eq!(cpu.registers.pc, 0x0050);
+        assert!(!cpu.ime);
+        assert_eq!(bus.read(IF, false) & 0x1F, 0);
+        let sp = cpu.registers.sp;
+        assert_eq!(bus.read(sp, false), 0x01);
+        assert_eq!(bus.read(sp.wrapping_add(1), false), 0x01);
+    }
+
+    // The lowest bit goes first, each dispatch only clears its own bit in IF
+    #[test]
+    fn interrupts_are_dispatched_by_priority() {
+        let (mut cpu, mut bus) = machine(&[0x00]); // NOP, the vectors are NOPs too
+        bus.write(IE, 0x1F, false);
+        bus.write(IF, 0x1A, false); // LCD STAT, serial and joypad
+
+        for (vector, left) in [(0x0048, 0x18), (0x0058, 0x10), (0x0060, 0x00)] {
+            cpu.ime = true;
+            cpu.step(&mut bus);
+            assert_eq!(cpu.registers.pc, vector);
+            assert_eq!(bus.read(IF, false) & 0x1F, left);
+        }
+    }
+
+    // With SP at 0x0000 the high byte of PC, 0x01, is pushed onto IE: the timer
+    // interrupt is no longer enabled, the dispatch is cancelled and jumps to 0x0000
+    // without acknowledging it
+    #[test]
+    fn ie_overwritten_by_the_push_cancels_dispatch() {
+        let (mut cpu, mut bus) = machine(&[0x00]); // NOP
+        cpu.ime = true;
+        cpu.registers.sp = 0x0000;
+        bus.write(IE, 0x04, false);
+        bus.write(IF, 0x04, false);
+
+        assert_eq!(cpu.step(&mut bus), 4 + 20);
+        assert_eq!(cpu.registers.pc, 0x0000);
+        assert_eq!(bus.read(IE, false), 0x01);
+        assert_eq!(bus.read(IF, false) & 0x1F, 0x04);
+    }
+
+    // The same push leaves V-blank enabled, so that one is still dispatched
+    #[test]
+    fn ie_push_keeping_the_interrupt_enabled_dispatches_it() {
+        let (mut cpu, mut bus) = machine(&[0x00]); // NOP
+        cpu.ime = true;
+        cpu.registers.sp = 0x0000;
+        bus.write(IE, 0x01, false);
+        bus.write(IF, 0x01, false);
+
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0040);
+        assert_eq!(bus.read(IF, false) & 0x1F, 0);
+    }
+
+    // STOP with a button of a selected row held, as in the table above stop()
+    fn stop_machine(button: bool, pending: bool) -> (CPU, Bus) {
+        let (cpu, mut bus) = machine(&[0x10, 0x3C, 0x3C]); // STOP, INC A, INC A
+        bus.write(0xFF00, 0x10, false); // Action buttons selected
+        let mut input = InputState::new();
+        input.set(Button::A, button);
+        bus.set_input(input);
+        bus.write(IF, if pending { 0x04 } else { 0x00 }, false);
+        bus.write(IE, 0x04, false);
+        bus.tick(1024);
+        assert_ne!(bus.read(DIV, false), 0);
+        (cpu, bus)
+    }
+
+    #[test]
+    fn stop_with_button_held_and_interrupt_pending_is_a_nop() {
+        let (mut cpu, mut bus) = stop_machine(true, true);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0101);
+        assert!(!cpu.halted && !cpu.is_stopped());
+        assert_ne!(bus.read(DIV, false), 0);
+    }
+
+    #[test]
+    fn stop_with_button_held_halts() {
+        let (mut cpu, mut bus) = stop_machine(true, false);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0102);
+        assert!(cpu.halted && !cpu.is_stopped());
+        assert_ne!(bus.read(DIV, false), 0);
+    }
+
+    // The byte after STOP is skipped, DIV is reset and the clock doesn't run until a
+    // button wakes the CPU up
+    #[test]
+    fn stop_without_button_stops_the_clock() {
+        let (mut cpu, mut bus) = stop_machine(false, false);
+        let a = cpu.registers.a;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0102);
+        assert!(cpu.is_stopped());
+        assert_eq!(bus.read(DIV, false), 0);
+
+        let now = bus.now();
+        assert_eq!(cpu.step(&mut bus), 0);
+        assert_eq!(bus.now(), now);
+
+        cpu.wake_from_stop();
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.a, a.wrapping_add(1));
+    }
+
+    // With an interrupt pending, the byte after STOP isn't skipped
+    #[test]
+    fn stop_without_button_and_interrupt_pending_is_one_byte() {
+        let (mut cpu, mut bus) = stop_machine(false, true);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.registers.pc, 0x0101);
+        assert!(cpu.is_stopped());
+        assert_eq!(bus.read(DIV, false), 0);
+    }
+
+    // A button press requests the joypad interrupt, which wakes the CPU: with IME off,
+    // the instruction after HALT runs within 5 M-cycles
+    #[test]
+    fn halt_wakes_on_button_press() {
+        let (mut cpu, mut bus) = machine(&[0x76, 0x00, 0x00]); // HALT, NOP, NOP
+        bus.write(0xFF00, 0x10, false); // Action buttons selected
+        bus.write(IE, 0x10, false);
+        cpu.step(&mut bus);
+        assert!(cpu.halted);
+
+        let mut input = InputState::new();
+        input.press(Button::A);
+        bus.set_input(input);
+        let mut cycles = 0;
+        while cpu.registers.pc <= 0x0101 {
+            cycles += cpu.step(&mut bus);
+            assert!(cycles <= 20, "still halted after {cycles} T-cycles");
+        }
+    }
+}
@@ -1,12 +1,13 @@
 #![allow(dead_code, unused_imports)]
 use super::CPU;
+use crate::bus::Bus;
 use super::{FlagCondition, MemAdress, Operand, Reg8, Reg16};
 
 #[derive(Copy, Clone, Debug)]
 pub enum InstrPointer {
-    Binop(fn(&mut CPU, Operand, Operand), Operand, Operand, u16),
-    Unop(fn(&mut CPU, Operand), Operand, u16),
-    Const(fn(&mut CPU), u16),
+    Binop(fn(&mut CPU, &mut Bus, Operand, Operand), Operand, Operand, u16),
+    Unop(fn(&mut CPU, &mut Bus, Operand), Operand, u16),
+    Const(fn(&mut CPU, &mut Bus), u16),
     None, //For non implemented funcs
 }
 
@@ -195,7 +196,7 @@ impl CPU {
 
         //ARITHMETIC
         for (i, func) in arith_funcs.iter().enumerate() {
-            let opcode = 0xC6 + i * 0x08 as usize;
+            let opcode = 0xC6 + i * 0x08;
             table[opcode] = Binop(*func, R8(A), Imm8, 8);
         }
 
@@ -291,7 +292,7 @@ impl CPU {
         let cb_timing1: [u16; 8] = [8, 8, 8, 8, 8, 8, 16, 8];
         let cb_timing2: [u16; 8] = [8, 8, 8, 8, 8, 8, 12, 8];
         for (i, func) in func_order.iter().enumerate() {
-            let start = 0x00 + i * 0x08;
+            let start = i * 0x08;
             CPU::init_instr(
                 &mut cb_table,
                 Unop(*func, R8(A), 0),
@@ -306,17 +307,17 @@ impl CPU {
         //weird error here if i didn't use the "as ..."
         let cb_ops = [
             (
-                CPU::bit as fn(&mut CPU, Operand, Operand),
+                CPU::bit as fn(&mut CPU, &mut Bus, Operand, Operand),
                 0x40,
                 &cb_timing2,
             ),
             (
-                CPU::res as fn(&mut CPU, Operand, Operand),
+                CPU::res as fn(&mut CPU, &mut Bus, Operand, Operand),
                 0x80,
                 &cb_timing1,
             ),
             (
-                CPU::set as fn(&mut CPU, Operand, Operand),
+                CPU::set as fn(&mut CPU, &mut Bus, Operand, Operand),
                 0xC0,
                 &cb_timing1,
             ),
@@ -396,9 +397,9 @@ impl CPU {
         match func {
             Binop(f, lop, rop, _) => {
                 if replace_left {
-                    return Binop(f, given_op, rop, clock_cycles);
+                    Binop(f, given_op, rop, clock_cycles)
                 } else {
-                    return Binop(f, lop, given_op, clock_cycles);
+                    Binop(f, lop, given_op, clock_cycles)
                 }
             }
             Unop(f, _, _) => Unop(f, given_op, clock_cycles),
@@ -418,9 +419,9 @@ impl CPU {
 * Represent everything as an enum
 *
 *pub enum InstrPointer {
-*    Binop(fn(&mut CPU, Operand, Operand), Operand, Operand, u16),
-*    Unop(fn(&mut CPU, Operand), Operand, u16),
-*    Const(fn(&mut CPU), u16),
+*    Binop(fn(&mut CPU, &mut Bus, Operand, Operand), Operand, Operand, u16),
+*    Unop(fn(&mut CPU, &mut Bus, Operand), Operand, u16),
+*    Const(fn(&mut CPU, &mut Bus), u16),
 *    None, //For non implemented funcs
 *}
 *
@@ -446,7 +447,7 @@ struct InstructionEntry {
     func_index: u8,
 }
 
-type InstrFn = fn(&mut CPU, Operand, Operand);
+type InstrFn = fn(&mut CPU, &mut Bus, Operand, Operand);
 static INSTR_FUNCS: &[InstrFn] = &[
     CPU::nop,
     CPU::ld_u8,
@@ -0,0 +1,384 @@
+use crate::gameboi::GameBoi;
+use crate::serial::SerialDevice;
+use std::sync::{Arc, Mutex};
+
+pub const MAX_PLAYERS: usize = 4;
+
+// Same as a linked pair, the Game Boys take turns by slices of a bit at 8192 Hz
+const SLICE_CYCLES: u64 = 512;
+
+// The adapter drives the clock of every Game Boy, one byte at a time. These are close
+// to what the hardware does, not exact
+const PING_BYTE_CYCLES: u64 = 16384;
+// In transmission, the lower nibble of RATE adds to the delay between bytes
+const TRANSMISSION_BYTE_CYCLES: u64 = 4096;
+const RATE_UNIT_CYCLES: u64 = 1024;
+
+// Ping packets start with this header, followed by 3 status bytes
+const PING_HEADER: u8 = 0xFE;
+// Answered by each Game Boy to the first 2 bytes of a ping packet
+const ACK: u8 = 0x88;
+// Player 1 answers a whole ping packet with this to start the transmission phase...
+const START: u8 = 0xAA;
+// ...which the adapter announces with 4 of these
+const TRANSMISSION_HEADER: u8 = 0xCC;
+// A round where player 1 only sends this takes everyone back to the ping phase
+const RESTART: u8 = 0xFF;
+
+// ============ DMG-07 Adapter ============
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Ping { index: usize },         // Byte of the 4 byte ping packet
+    Starting { index: usize },     // Sending the 4 transmission headers
+    Transmission { index: usize }, // Byte of the round, 4 * size long
+}
+
+// The adapter is the master of every Game Boy on it.
+// Ping phase: each player gets 0xFE then 3 times its status, the player number in the
+// upper nibble and a bit for each connected player in the lower one. Game Boys answer
+// ACK, ACK, then player 1 sets the transmission speed (RATE) and the packet size (SIZE).
+// Transmission phase: in each round every player sends SIZE bytes, then gets everything
+// sent in the previous round, player 1's bytes first, the rest of the round is padding.
+struct Adapter {
+    phase: Phase,
+    next_byte_at: u64,
+    connected: u8, // Bit per player, set once a player answered a ping
+
+    rate: u8,
+    size: usize,
+    start_answers: usize, // START bytes from player 1 in the current ping packet
+    sent: Vec<u8>,        // This round, every player's bytes
+    received: Vec<u8>,    // Last round, sent back to everyone
+
+    // Same as a link cable, a port waiting on the adapter's clock with SB, and the
+    // byte clocked in that it hasn't picked up yet
+    waiting: [Option<u8>; MAX_PLAYERS],
+    incoming: [Option<u8>; MAX_PLAYERS],
+}
+
+impl Adapter {
+    fn new() -> Self {
+        Self {
+            phase: Phase::Ping { index: 0 },
+            next_byte_at: PING_BYTE_CYCLES,
+            connected: 0,
+            rate: 0,
+            size: 1,
+            start_answers: 0,
+            sent: Vec::new(),
+            received: Vec::new(),
+            waiting: [None; MAX_PLAYERS],
+            incoming: [None; MAX_PLAYERS],
+        }
+    }
+
+    fn run_until(&mut self, now: u64) {
+        while self.next_byte_at <= now {
+            self.clock_byte();
+            let cycles = match self.phase {
+                Phase::Transmission { .. } => {
+                    TRANSMISSION_BYTE_CYCLES + (self.rate & 0x0F) as u64 * RATE_UNIT_CYCLES
+                }
+                _ => PING_BYTE_CYCLES,
+            };
+            self.next_byte_at += cycles;
+        }
+    }
+
+    // Shifts a byte into every player at once, and their SB out
+    fn clock_byte(&mut self) {
+        let mut answers = [None; MAX_PLAYERS];
+        for (player, answer) in answers.iter_mut().enumerate() {
+            let byte = self.byte_for(player);
+            if let Some(sb) = self.waiting[player].take() {
+                self.incoming[player] = Some(byte);
+                *answer = Some(sb);
+            }
+        }
+        self.phase = self.next_phase(answers);
+    }
+
+    fn byte_for(&self, player: usize) -> u8 {
+        match self.phase {
+            Phase::Ping { index: 0 } => PING_HEADER,
+            Phase::Ping { .. } => ((player as u8 + 1) << 4) | self.connected,
+            Phase::Starting { .. } => TRANSMISSION_HEADER,
+            Phase::Transmission { index } => self.received.get(index).copied().unwrap_or(0),
+        }
+    }
+
+    fn next_phase(&mut self, answers: [Option<u8>; MAX_PLAYERS]) -> Phase {
+        match self.phase {
+            Phase::Ping { index } => {
+                for (player, answer) in answers.iter().enumerate() {
+                    if index == 0 && *answer == Some(ACK) {
+                        self.connected |= 1 << player;
+                    }
+                }
+                match answers[0] {
+                    Some(START) => self.start_answers += 1,
+                    Some(rate) if index == 2 => self.rate = rate,
+                    Some(size) if index == 3 => self.size = (size as usize).max(1),
+                    _ => {}
+                }
+
+                if index < 3 {
+                    return Phase::Ping { index: index + 1 };
+                }
+                let start = self.start_answers == 4;
+                self.start_answers = 0;
+                if start {
+                    Phase::Starting { index: 0 }
+                } else {
+                    Phase::Ping { index: 0 }
+                }
+            }
+            Phase::Starting { index } if index < 3 => Phase::Starting { index: index + 1 },
+            Phase::Starting { .. } => {
+                self.sent = vec![0; MAX_PLAYERS * self.size];
+                self.received = vec![0; MAX_PLAYERS * self.size];
+                Phase::Transmission { index: 0 }
+            }
+            Phase::Transmission { index } => {
+                // Players send their bytes at the start of the round
+                if index < self.size {
+                    for (player, answer) in answers.iter().enumerate() {
+                        self.sent[player * self.size + index] = answer.unwrap_or(0);
+                    }
+                }
+
+                if index + 1 < MAX_PLAYERS * self.size {
+                    return Phase::Transmission { index: index + 1 };
+                }
+                if self.sent[..self.size].iter().all(|&byte| byte == RESTART) {
+                    self.connected = 0;
+                    return Phase::Ping { index: 0 };
+                }
+                self.received = std::mem::replace(&mut self.sent, vec![0; MAX_PLAYERS * self.size]);
+                Phase::Transmission { index: 0 }
+            }
+        }
+    }
+}
+
+// What each Game Boy has plugged in its link port
+#[derive(Clone)]
+struct Port {
+    adapter: Arc<Mutex<Adapter>>,
+    player: usize,
+}
+
+impl SerialDevice for Port {
+    // The adapter is the master, a Game Boy driving the clock gets nothing back
+    fn exchange(&mut self, _byte: u8, _done_at: u64) -> u8 {
+        0xFF
+    }
+
+    fn external_clock(&mut self, byte: u8, now: u64) -> Option<(u8, u64)> {
+        let mut adapter = self.adapter.lock().unwrap();
+        let incoming = adapter.incoming[self.player].take();
+        adapter.waiting[self.player] = incoming.is_none().then_some(byte);
+        incoming.map(|incoming| (incoming, now))
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+// ============ Four Players ============
+
+// Up to 4 Game Boys on a DMG-07, kept in lockstep by their master clocks, the adapter
+// following the one furthest behind
+pub struct Dmg07 {
+    gameboys: Vec<GameBoi>,
+    adapter: Arc<Mutex<Adapter>>,
+}
+
+impl Dmg07 {
+    // The Game Boys are players 1 to 4, in order
+    pub fn new(mut gameboys: Vec<GameBoi>) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&gameboys.len()),
+            "the DMG-07 takes 1 to 4 Game Boys"
+        );
+        let adapter = Arc::new(Mutex::new(Adapter::new()));
+        for (player, gameboi) in gameboys.iter_mut().enumerate() {
+            gameboi.connect_serial(Box::new(Port {
+                adapter: adapter.clone(),
+                player,
+            }));
+        }
+        Self { gameboys, adapter }
+    }
+
+    pub fn players(&self) -> usize {
+        self.gameboys.len()
+    }
+
+    pub fn gameboi(&mut self, player: usize) -> &mut GameBoi {
+        &mut self.gameboys[player]
+    }
+
+    // Runs until every Game Boy completes a frame
+    pub fn step(&mut self) -> Vec<[u8; 23040]> {
+        let mut frames = vec![None; self.gameboys.len()];
+        while frames.iter().any(Option::is_none) {
+            // Stopped Game Boys don't move their clock, they only hand out a blank frame
+            let player = (0..self.gameboys.len())
+                .filter(|&i| !(self.gameboys[i].is_stopped() && frames[i].is_some()))
+                .min_by_key(|&i| self.gameboys[i].clock())
+                .unwrap();
+
+            if let Some(frame) = self.gameboys[player].run_for(SLICE_CYCLES) {
+                frames[player] = Some(frame);
+            }
+
+            let running = self.gameboys.iter().filter(|gameboi| !gameboi.is_stopped());
+            if let Some(behind) = running.map(GameBoi::clock).min() {
+                self.adapter.lock().unwrap().run_until(behind);
+            }
+        }
+        frames.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Players 1 to 3 are plugged in, the fourth port has nothing waiting on it
+    const PLAYERS: usize = 3;
+
+    fn ports(adapter: &Arc<Mutex<Adapter>>) -> Vec<Port> {
+        (0..PLAYERS)
+            .map(|player| Port {
+                adapter: adapter.clone(),
+                player,
+            })
+            .collect()
+    }
+
+    // Each player waits with its byte in SB, the adapter clocks one byte, and every
+    // player picks up what it was sent
+    fn clock(adapter: &Arc<Mutex<Adapter>>, ports: &mut [Port], sb: [u8; PLAYERS]) -> Vec<u8> {
+        let now = adapter.lock().unwrap().next_byte_at;
+        for (port, &byte) in ports.iter_mut().zip(&sb) {
+            assert_eq!(port.external_clock(byte, now), None);
+        }
+        adapter.lock().unwrap().run_until(now);
+        ports
+            .iter_mut()
+            .map(|port| port.external_clock(0, now).unwrap())
+            .map(|(byte, done_at)| {
+                assert_eq!(done_at, now);
+                byte
+            })
+            .collect()
+    }
+
+    // A ping packet, player 1 answering with its own bytes and the others with ACK
+    fn ping(adapter: &Arc<Mutex<Adapter>>, ports: &mut [Port], player1: [u8; 4]) -> Vec<Vec<u8>> {
+        player1
+            .iter()
+            .map(|&byte| clock(adapter, ports, [byte, ACK, ACK]))
+            .collect()
+    }
+
+    // Ping packets, then START, then the rounds of a packet size of 2 bytes
+    #[test]
+    fn bytes_go_round_every_player() {
+        let adapter = Arc::new(Mutex::new(Adapter::new()));
+        let mut ports = ports(&adapter);
+
+        // Players show as connected from the first ACK, the status has a bit for each
+        let packet = ping(&adapter, &mut ports, [ACK, ACK, 0x00, 2]);
+        assert_eq!(packet[0], [PING_HEADER; PLAYERS]);
+        for byte in &packet[1..] {
+            assert_eq!(byte, &[0x17, 0x27, 0x37]);
+        }
+        assert_eq!(adapter.lock().unwrap().size, 2);
+
+        let packet = ping(&adapter, &mut ports, [START; 4]);
+        assert_eq!(packet[0], [PING_HEADER; PLAYERS]);
+        for _ in 0..4 {
+            assert_eq!(
+                clock(&adapter, &mut ports, [0; PLAYERS]),
+                [TRANSMISSION_HEADER; 3]
+            );
+        }
+
+        // Each player sends 2 bytes at the start of a round, and gets the last round
+        let round = |ports: &mut [Port], sent: [[u8; 2]; PLAYERS]| -> Vec<Vec<u8>> {
+            (0..MAX_PLAYERS * 2)
+                .map(|index| {
+                    let sb = sent.map(|bytes| bytes.get(index).copied().unwrap_or(0));
+                    clock(&adapter, ports, sb)
+                })
+                .collect()
+        };
+        let first = round(&mut ports, [[0x11, 0x12], [0x21, 0x22], [0x31, 0x32]]);
+        assert!(first.iter().flatten().all(|&byte| byte == 0));
+
+        let second = round(&mut ports, [[0x01, 0x02]; PLAYERS]);
+        let expected = [0x11, 0x12, 0x21, 0x22, 0x31, 0x32, 0x00, 0x00];
+        for player in 0..PLAYERS {
+            let received: Vec<u8> = second.iter().map(|bytes| bytes[player]).collect();
+            assert_eq!(received, expected);
+        }
+
+        // Player 1 sends RESTART for the whole round, everyone goes back to pinging and
+        // has to answer again to show as connected, player 3 doesn't
+        round(&mut ports, [[RESTART; 2], [0x21, 0x22], [0x31, 0x32]]);
+        let header = clock(&adapter, &mut ports, [ACK, ACK, 0x00]);
+        assert_eq!(header, [PING_HEADER; PLAYERS]);
+        assert_eq!(
+            clock(&adapter, &mut ports, [ACK; PLAYERS]),
+            [0x13, 0x23, 0x33]
+        );
+    }
+
+    // Without START from player 1 the adapter keeps pinging
+    #[test]
+    fn pings_until_start() {
+        let adapter = Arc::new(Mutex::new(Adapter::new()));
+        let mut ports = ports(&adapter);
+        for _ in 0..3 {
+            let packet = ping(&adapter, &mut ports, [ACK, ACK, 0x00, 1]);
+            assert_eq!(packet[0], [PING_HEADER; PLAYERS]);
+        }
+        let packet = ping(&adapter, &mut ports, [START, START, START, ACK]);
+        assert_eq!(packet[0], [PING_HEADER; PLAYERS]);
+        assert_eq!(clock(&adapter, &mut ports, [ACK; PLAYERS])[0], PING_HEADER);
+    }
+
+    // Game Boys with nothing to run are kept within a slice of each other, and each
+    // hands out a frame per step
+    #[test]
+    fn steps_every_player() {
+        let gameboys = (0..MAX_PLAYERS)
+            .map(|_| {
+                let mut gameboi = GameBoi::new();
+                gameboi.load_rom_from_data(&[0; 0x8000]);
+                gameboi
+            })
+            .collect();
+        let mut dmg07 = Dmg07::new(gameboys);
+        assert_eq!(dmg07.players(), MAX_PLAYERS);
+
+        for _ in 0..3 {
+            assert_eq!(dmg07.step().len(), MAX_PLAYERS);
+            let clocks: Vec<u64> = (0..MAX_PLAYERS).map(|i| dmg07.gameboi(i).clock()).collect();
+            let spread = clocks.iter().max().unwrap() - clocks.iter().min().unwrap();
+            assert!(spread <= 2 * SLICE_CYCLES, "clocks {clocks:?}");
+        }
+        // The adapter follows them, a ping byte at a time
+        let behind = (0..MAX_PLAYERS)
+            .map(|i| dmg07.gameboi(i).clock())
+            .min()
+            .unwrap();
+        let next_byte_at = dmg07.adapter.lock().unwrap().next_byte_at;
+        assert!(next_byte_at > behind && next_byte_at <= behind + PING_BYTE_CYCLES);
+    }
+}
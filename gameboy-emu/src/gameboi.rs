@@ -1,65 +1,279 @@
 use crate::bus::Bus;
 use crate::cpu::CPU;
-use crate::ppu::PPU;
-use std::cell::RefCell;
-use std::rc::Rc;
-const IF: u16 = 0xFF0F;
-const IE: u16 = 0xFFFF; //Interrupt enable
+use crate::input::InputState;
+use crate::ppu::RendererKind;
+use crate::rewind::Rewind;
+use crate::savestate::{self, StateError, StateReader, StateWriter};
+use crate::serial::SerialDevice;
+use crate::tas;
 
+// Everything is owned, so a GameBoi can be cloned or sent to another thread
+#[derive(Clone)]
 pub struct GameBoi {
     cpu: CPU,
-    ppu: PPU,
-    bus: Rc<RefCell<Bus>>,
+    bus: Bus,
+    frames: u64, // Handed out since power on
+    rewind: Option<Rewind>,
+    run_ahead: u32, // Frames
+    lag_frames: u64,
+    lagged: bool,        // The last frame was a lag frame
+    input_display: bool, // Buttons held are drawn over the frames
+}
+
+impl Default for GameBoi {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GameBoi {
     pub fn new() -> Self {
-        let bus = Bus::empty();
-        let ppu = PPU::new(bus.clone());
-        let cpu = CPU::new(bus.clone());
-        Self { cpu, ppu, bus }
+        Self::with_renderer(RendererKind::Fifo)
+    }
+
+    // The scanline renderer is much faster, but ignores register writes in the middle of a line
+    pub fn with_renderer(renderer: RendererKind) -> Self {
+        let mut bus = Bus::new(renderer);
+        let cpu = CPU::new(&mut bus);
+        Self {
+            cpu,
+            bus,
+            frames: 0,
+            rewind: None,
+            run_ahead: 0,
+            lag_frames: 0,
+            lagged: false,
+            input_display: false,
+        }
     }
 
     pub fn load_rom_from_path(&mut self, rom_path: &str) {
-        self.bus.borrow_mut().load_rom(rom_path);
+        self.bus.load_rom(rom_path);
     }
 
     pub fn load_rom_from_data(&mut self, rom_data: &[u8]) {
-        self.bus.borrow_mut().load_rom_data(rom_data);
-    }
-
-    pub fn receive_input(&mut self, pressed_mask: u8) {
-        let mut bus = self.bus.borrow_mut();
-        bus.set_joypad(pressed_mask);
-
-        /*
-        // Trigger joypad interrupt on any falling edge in lower 4 bits
-        if (old & 0x0F) == 0x0F && (joyp & 0x0F) != 0x0F {
-            let if_reg = bus.read(IF, false);
-            bus.write(IF, if_reg | 0x10, false);
-            //let ie = bus.read(IE, false);
-            //bus.write(IE, 0b10000 | ie, false);
-
-            println!(
-                "INPUT INTERRUPT SET {:0b} 0xFF00 : {:0b} IE : {:0b} IF : {:0b}",
-                pressed_mask,
-                bus.read(0xFF00, false),
-                bus.read(IE, false),
-                bus.read(IF, false)
-            );
+        self.bus.load_rom_data(rom_data);
+    }
+
+    // Plugs a device in the serial port, returning the one that was there
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.bus.connect_serial(device)
+    }
+
+    pub fn disconnect_serial(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.bus.disconnect_serial()
+    }
+
+    // The buttons held from now on
+    pub fn set_input(&mut self, input: InputState) {
+        self.bus.set_input(input);
+        self.wake_on_input();
+    }
+
+    // The buttons held once the given number of T-cycles have run, which can be in
+    // the middle of the next frame (a frame is 70224 T-cycles)
+    pub fn queue_input(&mut self, cycles: u64, input: InputState) {
+        self.bus.queue_input(cycles, input);
+    }
+
+    fn wake_on_input(&mut self) {
+        if self.cpu.is_stopped() && self.bus.joypad_line_low() {
+            self.cpu.wake_from_stop();
         }
-        */
     }
 
-    pub fn step(&mut self) -> [u8; 23040] {
-        while !self.ppu.is_frame_ready() {
-            let cycles = self.cpu.step();
-            //self.cpu.print_state();
-            self.ppu.step(cycles * 4);//TODO THIS SHOULD BE 4 BUT I AM GETTING OVERFLOWS
-            //ppu.print_state();
+    // T-cycles run since power on
+    pub fn clock(&self) -> u64 {
+        self.bus.now()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.is_stopped()
+    }
+
+    // A snapshot of the whole machine, see savestate.rs for the format. The device in
+    // the serial port isn't part of it
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+        state.u64(self.frames);
+        state.u64(self.lag_frames);
+        state.bool(self.lagged);
+        state.finish()
+    }
+
+    // Two GameBois with the same hash are in the same state, as far as save states go
+    pub fn state_hash(&self) -> u64 {
+        savestate::hash(&self.save_state())
+    }
+
+    // Tells ROMs apart, for movies and netplay
+    pub fn rom_hash(&self) -> u64 {
+        self.bus.rom_hash()
+    }
+
+    // Running from a loaded state gives the same frames as running from where it was
+    // saved. On error, the GameBoi is left as it was. Rewind snapshots taken after the
+    // loaded frame are dropped, the ones before it stay: frontends doing run-ahead load
+    // a state every frame
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.restore(data)?;
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.truncate(self.frames);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        let (mut cpu, mut bus) = (self.cpu.clone(), self.bus.clone());
+        cpu.load_state(&mut state)?;
+        bus.load_state(&mut state)?;
+        let frames = state.u64()?;
+        let lag_frames = state.u64()?;
+        let lagged = state.bool()?;
+        state.finish()?;
+        (self.cpu, self.bus) = (cpu, bus);
+        (self.frames, self.lag_frames, self.lagged) = (frames, lag_frames, lagged);
+        Ok(())
+    }
+
+    // ============ Rewind ============
+
+    // Keeps a snapshot every `interval` frames, within `budget` bytes, the oldest ones
+    // being dropped. A snapshot of every frame takes a few KB
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize) {
+        self.rewind = Some(Rewind::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Goes back to the latest snapshot at least `frames` frames ago, the next step
+    // runs from there. Returns how many frames were undone, 0 without history
+    pub fn rewind(&mut self, frames: u64) -> u64 {
+        let target = self.frames.saturating_sub(frames);
+        let Some((frame, state)) = self.rewind.as_mut().and_then(|rewind| rewind.rewind_to(target))
+        else {
+            return 0;
+        };
+        let undone = self.frames - frame;
+        self.restore(&state).expect("rewind snapshots are our own states");
+        undone
+    }
+
+    // Frames handed out since power on, save states keep the count
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn frame_done(&mut self) {
+        self.frames += 1;
+        self.lagged = !self.bus.take_joypad_polled();
+        if self.lagged {
+            self.lag_frames += 1;
+        }
+        if self.rewind.as_ref().is_some_and(|rewind| rewind.wants_snapshot(self.frames)) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(self.frames, state);
+        }
+    }
+
+    // ============ TAS Tools ============
+
+    // A lag frame is one where the game never read the joypad, input given for it had
+    // no effect. Counted since power on, save states keep the count
+    pub fn is_lag_frame(&self) -> bool {
+        self.lagged
+    }
+
+    pub fn lag_frames(&self) -> u64 {
+        self.lag_frames
+    }
+
+    // Draws the buttons held, and a marker on lag frames, in the bottom left corner of
+    // the frames handed out. The emulation doesn't see it
+    pub fn set_input_display(&mut self, enabled: bool) {
+        self.input_display = enabled;
+    }
+
+    fn present(&self, mut frame: [u8; 23040]) -> [u8; 23040] {
+        if self.input_display {
+            tas::draw_input(&mut frame, self.bus.input(), self.lagged);
         }
-        let frame = self.ppu.yield_frame();
-        self.ppu.clear_buffer();
         frame
     }
+
+    // ============ Run-ahead ============
+
+    // With run-ahead, step also runs this many frames further with the same input and
+    // shows the last one, then goes back. A game reacting to input a frame or two late
+    // looks like it reacts at once, for the cost of emulating 1 + frames frames and
+    // saving and loading a state on every step (see the run_ahead example)
+    pub fn set_run_ahead(&mut self, frames: u32) {
+        self.run_ahead = frames;
+    }
+
+    fn look_ahead(&mut self) -> [u8; 23040] {
+        let state = self.save_state();
+        // The device in the serial port would see bytes from a future that gets thrown
+        // away, it is left out while running ahead
+        let device = self.bus.disconnect_serial();
+        let mut frame = [0; 23040];
+        for _ in 0..self.run_ahead {
+            frame = self.run_until_frame(u64::MAX).unwrap();
+        }
+        self.restore(&state).expect("run-ahead states are our own");
+        if let Some(device) = device {
+            self.bus.connect_serial(device);
+        }
+        self.present(frame)
+    }
+
+    // ============ Running ============
+
+    // Runs a whole frame
+    pub fn step(&mut self) -> [u8; 23040] {
+        let frame = self.run_for(u64::MAX).unwrap();
+        if self.run_ahead == 0 {
+            return frame;
+        }
+        self.look_ahead()
+    }
+
+    // Runs for about the given number of T-cycles (whole instructions), returning early
+    // with the frame if one gets completed
+    pub fn run_for(&mut self, cycles: u64) -> Option<[u8; 23040]> {
+        let frame = self.run_until_frame(cycles)?;
+        self.frame_done();
+        Some(self.present(frame))
+    }
+
+    fn run_until_frame(&mut self, cycles: u64) -> Option<[u8; 23040]> {
+        let end = self.bus.now().saturating_add(cycles);
+        while !self.bus.ppu().is_frame_ready() {
+            if self.cpu.is_stopped() {
+                // The clock doesn't run while stopped, so queued input can't wait for it
+                self.bus.flush_input();
+                self.wake_on_input();
+            }
+            if self.cpu.is_stopped() {
+                // The LCD is stopped along with the CPU, blank frames are handed out
+                // until a button press wakes it up
+                return Some([0; 23040]);
+            }
+            if self.bus.now() >= end {
+                return None;
+            }
+            // The CPU ticks the bus itself, on every M-cycle
+            self.cpu.step(&mut self.bus);
+            //self.cpu.print_state(&mut self.bus);
+        }
+        let ppu = self.bus.ppu();
+        let frame = ppu.yield_frame();
+        ppu.clear_buffer();
+        Some(frame)
+    }
 }
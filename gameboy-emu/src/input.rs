@@ -0,0 +1,84 @@
+// ============ Input ============
+
+// The discriminant is the bit of the button in the joypad state, the action buttons
+// are read through P14 being low, the directions through P15
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+    ];
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// The buttons held at some point, nothing is pressed by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputState {
+    pressed: u8, // 1 == pressed, unlike the hardware
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.pressed |= button.mask();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.press(button);
+        } else {
+            self.release(button);
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.mask() != 0
+    }
+
+    // For building a state in one go: InputState::new().with(Button::Start)
+    pub fn with(mut self, button: Button) -> Self {
+        self.press(button);
+        self
+    }
+
+    // A bit per button, numbered as in Button, for storing input
+    pub fn bits(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self { pressed: bits }
+    }
+
+    // Action buttons in the lower nibble, directions in the upper one, 0 == pressed
+    pub(crate) fn joypad_lines(&self) -> u8 {
+        !self.pressed
+    }
+}
@@ -0,0 +1,58 @@
+use crate::bus::{BusAccess, Memory};
+
+pub const IF: u16 = 0xFF0F; //Interrupt flag
+pub const IE: u16 = 0xFFFF; //Interrupt enable
+
+// ============ Interrupt Controller ============
+
+// Declared by priority, the discriminant is the bit in IF and IE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    LCDStat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    const PRIORITY: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LCDStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self as u16
+    }
+}
+
+// Requests are kept in IF, components raise them through here
+pub fn request(mem: &mut Memory, interrupt: Interrupt) {
+    let if_reg = mem.read(IF) | interrupt.mask();
+    mem.write(IF, if_reg);
+}
+
+// Requested and enabled interrupts, whether IME is set or not
+pub fn pending(mem: &Memory) -> u8 {
+    mem.read(IE) & mem.read(IF) & 0x1F
+}
+
+// The interrupt to service, the lowest bit wins
+pub fn highest_pending(mem: &Memory) -> Option<Interrupt> {
+    let pending = pending(mem);
+    Interrupt::PRIORITY
+        .into_iter()
+        .find(|interrupt| pending & interrupt.mask() != 0)
+}
+
+pub fn acknowledge(mem: &mut Memory, interrupt: Interrupt) {
+    let if_reg = mem.read(IF) & !interrupt.mask();
+    mem.write(IF, if_reg);
+}
@@ -1,13 +1,45 @@
+#![allow(clippy::upper_case_acronyms)]
 use libretro_rs::{
     RetroAudioInfo, RetroCore, RetroEnvironment, RetroGame, RetroJoypadButton, RetroLoadGameResult,
     RetroRuntime, RetroSystemInfo, RetroVideoInfo, libretro_core,
 };
+use libretro_rs::sys::{
+    RETRO_ENVIRONMENT_GET_VARIABLE, RETRO_ENVIRONMENT_SET_VARIABLES, retro_variable,
+};
+use std::ffi::CStr;
 
 mod bus;
 mod cpu;
+mod dmg07;
 mod gameboi;
+mod input;
+mod interrupts;
+mod link;
+mod movie;
+mod mobile;
+mod netplay;
 mod ppu;
-use crate::gameboi::*;
+mod printer;
+mod rewind;
+mod savestate;
+mod scheduler;
+mod serial;
+mod tas;
+mod timer;
+
+// For embedding the emulator from Rust, the examples use these
+pub use crate::dmg07::Dmg07;
+pub use crate::gameboi::GameBoi;
+pub use crate::input::{Button, InputState};
+pub use crate::link::{LinkPort, LinkedPair, SocketLink, link_cable};
+pub use crate::mobile::{MobileAdapter, MobileConfig};
+pub use crate::movie::{Movie, MovieError, MoviePlayer, MovieRecorder};
+pub use crate::netplay::NetplaySession;
+pub use crate::ppu::RendererKind;
+pub use crate::printer::Printer;
+pub use crate::savestate::StateError;
+pub use crate::serial::{BlarggOutput, SerialDevice};
+use crate::tas::TasKeys;
 
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
@@ -36,17 +68,107 @@ https://www.libretro.com/index.php/api/
 Run like so :  retroarch --verbose -L ./target/debug/libgameboy_emu.so ../cpu_instrs.gb
 */
 
+fn retro_button(button: Button) -> RetroJoypadButton {
+    match button {
+        Button::A => A,
+        Button::B => B,
+        Button::Select => Select,
+        Button::Start => Start,
+        Button::Right => Right,
+        Button::Left => Left,
+        Button::Up => Up,
+        Button::Down => Down,
+    }
+}
+
+// Libretro wants states of the same size every time, while ours vary a bit: a frame
+// waiting to be handed out, the objects of the line, the pixels in the FIFOs... States
+// are stored with their length first, then padded up to the size of a state with room
+// for all of these
+const STATE_SLACK: usize = WIDTH * HEIGHT + 1024;
+
+fn state_size(gameboi: &GameBoi) -> usize {
+    4 + gameboi.save_state().len() + STATE_SLACK
+}
+
+// Holding L rewinds, a frame at a time. A snapshot of every frame is kept, in a
+// budget of a few minutes of Tetris
+const REWIND_KEY: RetroJoypadButton = L1;
+const REWIND_BUDGET: usize = 64 << 20;
+
+// For TAS work: R2 pauses, R1 then advances a frame at a time, and L2 shows the
+// buttons held and lag frames over the screen. They act when pressed, not held
+const PAUSE_KEY: RetroJoypadButton = R2;
+const FRAME_ADVANCE_KEY: RetroJoypadButton = R1;
+const INPUT_DISPLAY_KEY: RetroJoypadButton = L2;
+
+// Core options, set from the frontend's menu. The renderer is picked when a game loads
+const RENDERER_OPTION: &CStr = c"rustboi_renderer";
+
+fn set_options(env: &RetroEnvironment) {
+    let variables = [
+        retro_variable {
+            key: RENDERER_OPTION.as_ptr(),
+            value: c"Renderer (restart); fifo|scanline".as_ptr(),
+        },
+        retro_variable {
+            key: std::ptr::null(),
+            value: std::ptr::null(),
+        },
+    ];
+    // SAFETY: the list ends with a null entry, the frontend copies what it needs
+    unsafe { env.set_raw(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_ptr()) };
+}
+
+fn renderer_option(env: &RetroEnvironment) -> RendererKind {
+    let mut variable = retro_variable {
+        key: RENDERER_OPTION.as_ptr(),
+        value: std::ptr::null(),
+    };
+    // SAFETY: the frontend points value to a string of its own, or leaves it null
+    let value = unsafe {
+        env.set_raw(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut retro_variable);
+        (!variable.value.is_null()).then(|| CStr::from_ptr(variable.value))
+    };
+    match value.map(CStr::to_bytes) {
+        Some(b"scanline") => RendererKind::Scanline,
+        _ => RendererKind::Fifo,
+    }
+}
+
+fn upload_frame(runtime: &RetroRuntime, framebuffer: &[u16; WIDTH * HEIGHT]) {
+    // SAFETY: &[u16] has the same memory layout as &[u8] with double the length
+    // This is safe because u16 has no padding and alignment is fine on all platforms
+    let bytes: &[u8] = unsafe {
+        std::slice::from_raw_parts(
+            framebuffer.as_ptr() as *const u8,
+            framebuffer.len() * std::mem::size_of::<u16>(),
+        )
+    };
+
+    // Now upload as raw bytes with correct pitch
+    runtime.upload_video_frame(bytes, WIDTH as u32, HEIGHT as u32, WIDTH * 2);
+}
+
 struct RustBoiCore {
     framebuffer: [u16; WIDTH * HEIGHT],
     gameboi: GameBoi,
+    renderer: RendererKind,
+    state_size: usize, // Worked out once, the frontend relies on it not changing
+    tas_keys: TasKeys,
 }
 
 use RetroJoypadButton::*;
 impl RetroCore for RustBoiCore {
-    fn init(_env: &RetroEnvironment) -> Self {
+    fn init(env: &RetroEnvironment) -> Self {
+        set_options(env);
+        let gameboi = GameBoi::new();
         let mut core = Self {
             framebuffer: [0; WIDTH * HEIGHT],
-            gameboi: GameBoi::new(),
+            state_size: state_size(&gameboi),
+            gameboi,
+            renderer: RendererKind::default(),
+            tas_keys: TasKeys::default(),
         };
         println!("INIT!");
         core.gameboi.load_rom_from_path("dmg-acid2.gb");
@@ -77,23 +199,32 @@ impl RetroCore for RustBoiCore {
 
     fn reset(&mut self, _env: &RetroEnvironment) {
         self.framebuffer = [0xFF; WIDTH * HEIGHT];
-        self.gameboi = GameBoi::new();
+        self.gameboi = GameBoi::with_renderer(self.renderer);
+        self.gameboi.enable_rewind(1, REWIND_BUDGET);
+        self.gameboi.set_input_display(self.tas_keys.input_display());
     }
     fn run(&mut self, _env: &RetroEnvironment, runtime: &RetroRuntime) {
+        let mut input = InputState::new();
+        for button in Button::ALL {
+            input.set(button, runtime.is_joypad_button_pressed(0, retro_button(button)));
+        }
+        self.gameboi.set_input(input);
 
-        let mut pressed = 0xFF;
-        // Set bits for pressed buttons (bit = pressed)
-        if runtime.is_joypad_button_pressed(0, A) { pressed &= 0b1111_1110};
-        if runtime.is_joypad_button_pressed(0, B) {pressed &= 0b1111_1101};
-        if runtime.is_joypad_button_pressed(0, Select) {pressed &= 0b1111_1011};
-        if runtime.is_joypad_button_pressed(0, Start) {pressed &= 0b1111_0111};
-
-        if runtime.is_joypad_button_pressed(0, Right){pressed &= 0b1110_1111};
-        if runtime.is_joypad_button_pressed(0, Left){pressed &= 0b1101_1111};
-        if runtime.is_joypad_button_pressed(0, Up) {pressed &= 0b1011_1111};
-        if runtime.is_joypad_button_pressed(0, Down){pressed &= 0b0111_1111};
+        let keys = [PAUSE_KEY, FRAME_ADVANCE_KEY, INPUT_DISPLAY_KEY]
+            .map(|key| runtime.is_joypad_button_pressed(0, key));
+        let runs = self.tas_keys.update(keys);
+        self.gameboi.set_input_display(self.tas_keys.input_display());
+        if !runs {
+            upload_frame(runtime, &self.framebuffer);
+            return;
+        }
 
-        self.gameboi.receive_input(pressed);
+        // Going back 2 frames and running 1 shows the frames in reverse, at the start of
+        // the history the last frame stays on screen
+        if runtime.is_joypad_button_pressed(0, REWIND_KEY) && self.gameboi.rewind(2) == 0 {
+            upload_frame(runtime, &self.framebuffer);
+            return;
+        }
 
         // Run one full frame → you get [u8; 23040] of color indices (0-3)
         let raw_frame: [u8; WIDTH * HEIGHT] = self.gameboi.step();
@@ -103,21 +234,43 @@ impl RetroCore for RustBoiCore {
             self.framebuffer[i] = dmg_to_rgb565(color_index);
         }
 
-        // SAFETY: &[u16] has the same memory layout as &[u8] with double the length
-        // This is safe because u16 has no padding and alignment is fine on all platforms
-        let bytes: &[u8] = unsafe {
-            std::slice::from_raw_parts(
-                self.framebuffer.as_ptr() as *const u8,
-                self.framebuffer.len() * std::mem::size_of::<u16>(),
-            )
-        };
+        upload_frame(runtime, &self.framebuffer);
+    }
+
+    fn serialize_size(&self, _env: &RetroEnvironment) -> usize {
+        self.state_size
+    }
+
+    fn serialize(&self, _env: &RetroEnvironment, data: *mut (), size: usize) -> bool {
+        let state = self.gameboi.save_state();
+        if size < 4 + state.len() {
+            return false;
+        }
+        // SAFETY: the frontend hands us a buffer of `size` bytes
+        let data = unsafe { std::slice::from_raw_parts_mut(data as *mut u8, size) };
+        data[..4].copy_from_slice(&(state.len() as u32).to_le_bytes());
+        data[4..4 + state.len()].copy_from_slice(&state);
+        data[4 + state.len()..].fill(0);
+        true
+    }
 
-        // Now upload as raw bytes with correct pitch
-        runtime.upload_video_frame(bytes, WIDTH as u32, HEIGHT as u32, WIDTH * 2);
+    fn unserialize(&mut self, _env: &RetroEnvironment, data: *const (), size: usize) -> bool {
+        if size < 4 {
+            return false;
+        }
+        // SAFETY: the frontend hands us a buffer of `size` bytes
+        let data = unsafe { std::slice::from_raw_parts(data as *const u8, size) };
+        let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        match data[4..].get(..length) {
+            Some(state) => self.gameboi.load_state(state).is_ok(),
+            None => false,
+        }
     }
 
-    fn load_game(&mut self, _env: &RetroEnvironment, game: RetroGame) -> RetroLoadGameResult {
+    fn load_game(&mut self, env: &RetroEnvironment, game: RetroGame) -> RetroLoadGameResult {
         println!("LOADING!");
+        self.renderer = renderer_option(env);
+        self.gameboi = GameBoi::with_renderer(self.renderer);
         match game {
             RetroGame::Path { path, .. } => {
                 println!("Path!");
@@ -138,6 +291,8 @@ impl RetroCore for RustBoiCore {
         .with_pixel_format(libretro_rs::RetroPixelFormat::RGB565);
 
         let audio = RetroAudioInfo::new(44100.0);
+        self.state_size = state_size(&self.gameboi);
+        self.gameboi.enable_rewind(1, REWIND_BUDGET);
 
         RetroLoadGameResult::Success { audio, video }
     }
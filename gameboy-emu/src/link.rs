@@ -0,0 +1,360 @@
+use crate::gameboi::GameBoi;
+use crate::savestate::{self, StateError, StateReader, StateWriter};
+use crate::serial::SerialDevice;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Both Game Boys run in slices of this many T-cycles, a bit at 8192 Hz, so neither
+// gets more than a bit ahead of the other
+const SLICE_CYCLES: u64 = 512;
+
+// ============ Link Cable ============
+
+// What each side has put on the cable, indexed by side
+#[derive(Default, Clone, Copy)]
+struct Wire {
+    waiting: [Option<u8>; 2], // Waiting on the other side's clock, with this byte in SB
+    incoming: [Option<(u8, u64)>; 2], // Clocked in by the other side, and when it's done
+}
+
+// One end of a cable, to be plugged in a Game Boy's serial port.
+// Whichever side starts a transfer on its internal clock is the master, its byte is
+// swapped with the other side's SB if that side is waiting on an external clock, as
+// every bit is shifted both ways. Otherwise the master shifts in 0xFF.
+// The waiting side hears of the transfer on its next poll, within 512 T-cycles, and
+// completes it when the master does, both clocks running in lockstep.
+// Clones stay on the same cable
+#[derive(Clone)]
+pub struct LinkPort {
+    wire: Arc<Mutex<Wire>>,
+    side: usize,
+}
+
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let wire = Arc::new(Mutex::new(Wire::default()));
+    let port = |side| LinkPort {
+        wire: wire.clone(),
+        side,
+    };
+    (port(0), port(1))
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, byte: u8, done_at: u64) -> u8 {
+        let other = 1 - self.side;
+        let mut wire = self.wire.lock().unwrap();
+        match wire.waiting[other].take() {
+            Some(reply) => {
+                wire.incoming[other] = Some((byte, done_at));
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn external_clock(&mut self, byte: u8, _now: u64) -> Option<(u8, u64)> {
+        let mut wire = self.wire.lock().unwrap();
+        let incoming = wire.incoming[self.side].take();
+        // Until the master clocks, it sees the latest SB
+        wire.waiting[self.side] = incoming.is_none().then_some(byte);
+        incoming
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+// ============ Linked Pair ============
+
+// Two Game Boys connected by a cable, kept in lockstep by their master clocks
+pub struct LinkedPair {
+    gameboys: [GameBoi; 2],
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl LinkedPair {
+    pub fn new(mut first: GameBoi, mut second: GameBoi) -> Self {
+        let (port1, port2) = link_cable();
+        let wire = port1.wire.clone();
+        first.connect_serial(Box::new(port1));
+        second.connect_serial(Box::new(port2));
+        Self {
+            gameboys: [first, second],
+            wire,
+        }
+    }
+
+    pub fn gameboi(&mut self, side: usize) -> &mut GameBoi {
+        &mut self.gameboys[side]
+    }
+
+    // Runs until both Game Boys complete a frame, the one behind always running next
+    pub fn step(&mut self) -> [[u8; 23040]; 2] {
+        let mut frames = [None, None];
+        while frames.iter().any(Option::is_none) {
+            // A stopped Game Boy's clock doesn't move, once it has handed out its blank
+            // frame the other one runs
+            let done = |side: usize| self.gameboys[side].is_stopped() && frames[side].is_some();
+            let behind = self.gameboys[1].clock() < self.gameboys[0].clock();
+            let side = if done(0) || (behind && !done(1)) {
+                1
+            } else {
+                0
+            };
+
+            // A Game Boy ahead by a frame replaces its frame with the newer one
+            if let Some(frame) = self.gameboys[side].run_for(SLICE_CYCLES) {
+                frames[side] = Some(frame);
+            }
+        }
+        frames.map(Option::unwrap)
+    }
+
+    // Both Game Boys' states and what is on the cable between them
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        let wire = *self.wire.lock().unwrap();
+        for byte in wire.waiting {
+            state.bool(byte.is_some());
+            state.u8(byte.unwrap_or(0xFF));
+        }
+        for incoming in wire.incoming {
+            let (byte, done_at) = incoming.unwrap_or((0xFF, 0));
+            state.bool(incoming.is_some());
+            state.u8(byte);
+            state.u64(done_at);
+        }
+        for gameboi in &self.gameboys {
+            let data = gameboi.save_state();
+            state.u32(data.len() as u32);
+            state.bytes(&data);
+        }
+        state.finish()
+    }
+
+    pub fn state_hash(&self) -> u64 {
+        savestate::hash(&self.save_state())
+    }
+
+    // On error, the pair is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        let mut wire = Wire::default();
+        for byte in &mut wire.waiting {
+            let some = state.bool()?;
+            *byte = some.then_some(state.u8()?);
+        }
+        for incoming in &mut wire.incoming {
+            let some = state.bool()?;
+            let byte = state.u8()?;
+            *incoming = some.then_some((byte, state.u64()?));
+        }
+        let mut gameboys = self.gameboys.clone();
+        for gameboi in &mut gameboys {
+            let length = state.u32()? as usize;
+            gameboi.load_state(state.slice(length)?)?;
+        }
+        state.finish()?;
+
+        self.gameboys = gameboys;
+        *self.wire.lock().unwrap() = wire;
+        Ok(())
+    }
+}
+
+// ============ Socket Link ============
+
+// Both sides stop every SOCKET_SYNC_CYCLES to swap what happened on their serial port,
+// a byte's worth of transfer at 8192 Hz. Each only goes on once it has heard from the
+// other, so the two emulators stay in lockstep and everything they see of each other is
+// decided at those boundaries, which keeps both deterministic:
+// - A master gets the SB the other side was waiting with at the last boundary
+// - A slave gets the master's byte on its first poll after the next boundary
+const SOCKET_SYNC_CYCLES: u64 = 4096;
+// Nothing heard for this long at a boundary, the other side is gone
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sent at every boundary: flags, SB when waiting, byte sent as master
+const WAITING: u8 = 0x01;
+const SENT: u8 = 0x02;
+
+struct Connection {
+    stream: Option<TcpStream>, // None once the other side is gone
+    peer_waiting: Option<u8>,  // The other side's SB, if it waited at the last boundary
+    outgoing: Option<u8>,      // Sent as master since the last boundary
+    incoming: Option<u8>,      // Sent by the other side as master, not yet picked up
+}
+
+// A link cable to another process, over TCP. One side listens, the other connects,
+// which side is the master is still up to the games. Clones share the connection
+#[derive(Clone)]
+pub struct SocketLink {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SocketLink {
+    // Waits for the other side to connect
+    pub fn listen(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        Self::new(stream)
+    }
+
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(TcpStream::connect(address)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        // Every boundary is a round trip, small messages must not wait around
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(Connection {
+                stream: Some(stream),
+                peer_waiting: None,
+                outgoing: None,
+                incoming: None,
+            })),
+        })
+    }
+
+    // How long to wait on the other side at a boundary before dropping the connection,
+    // 5 seconds by default
+    pub fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match &self.connection.lock().unwrap().stream {
+            Some(stream) => stream.set_read_timeout(Some(timeout)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Connection {
+    fn swap(&mut self, message: [u8; 3]) -> io::Result<[u8; 3]> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        stream.write_all(&message)?;
+        let mut reply = [0; 3];
+        stream.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+}
+
+impl SerialDevice for SocketLink {
+    fn exchange(&mut self, byte: u8, _done_at: u64) -> u8 {
+        let mut connection = self.connection.lock().unwrap();
+        match connection.peer_waiting.take() {
+            Some(reply) => {
+                connection.outgoing = Some(byte);
+                reply
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn external_clock(&mut self, _byte: u8, now: u64) -> Option<(u8, u64)> {
+        let incoming = self.connection.lock().unwrap().incoming.take();
+        incoming.map(|incoming| (incoming, now))
+    }
+
+    fn sync_interval(&self) -> Option<u64> {
+        Some(SOCKET_SYNC_CYCLES)
+    }
+
+    fn sync(&mut self, waiting: Option<u8>) {
+        let mut connection = self.connection.lock().unwrap();
+        let outgoing = connection.outgoing.take();
+        let mut flags = 0;
+        if waiting.is_some() {
+            flags |= WAITING;
+        }
+        if outgoing.is_some() {
+            flags |= SENT;
+        }
+        let message = [flags, waiting.unwrap_or(0xFF), outgoing.unwrap_or(0xFF)];
+
+        match connection.swap(message) {
+            Ok([flags, sb, sent]) => {
+                connection.peer_waiting = (flags & WAITING != 0).then_some(sb);
+                // The byte was sent because we waited at the last boundary, only a port
+                // still waiting picks it up
+                connection.incoming = (flags & SENT != 0 && waiting.is_some()).then_some(sent);
+            }
+            Err(_) => {
+                // Like a cable being pulled out, nothing answers anymore. A timeout ends
+                // up here too, the reply may be half read and can't be trusted
+                connection.stream = None;
+                connection.peer_waiting = None;
+                connection.incoming = None;
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{BusAccess, Memory};
+    use crate::interrupts::IF;
+    use crate::serial::{SB, SC, Serial};
+
+    fn port(device: LinkPort) -> (Serial, Memory) {
+        let mut mem = Memory::new(vec![]);
+        let mut serial = Serial::new(&mut mem);
+        serial.connect(Box::new(device), 0);
+        mem.write(IF, 0);
+        (serial, mem)
+    }
+
+    // The waiting side picks the transfer up on its next poll, but only completes it
+    // with the master, 8 bits after the master started
+    #[test]
+    fn slave_completes_with_the_master() {
+        let (first, second) = link_cable();
+        let (mut master, mut master_mem) = port(first);
+        let (mut slave, mut slave_mem) = port(second);
+
+        slave.write(&mut slave_mem, 0, SB, 0x42);
+        slave.write(&mut slave_mem, 0, SC, 0x80);
+        slave.sync(&mut slave_mem, 0);
+        master.write(&mut master_mem, 100, SB, 0x99);
+        master.write(&mut master_mem, 100, SC, 0x81);
+        let done_at = 100 + 8 * 512;
+
+        slave.sync(&mut slave_mem, 512);
+        assert_eq!(slave.next_event(512), Some(done_at - 512));
+        slave.sync(&mut slave_mem, done_at - 1);
+        assert_eq!(slave_mem.read(SC) & 0x80, 0x80);
+
+        slave.sync(&mut slave_mem, done_at);
+        master.sync(&mut master_mem, done_at);
+        assert_eq!(slave_mem.read(SB), 0x99);
+        assert_eq!(master_mem.read(SB), 0x42);
+        for mem in [&slave_mem, &master_mem] {
+            assert_eq!(mem.read(SC) & 0x80, 0);
+            assert_eq!(mem.read(IF) & 0x08, 0x08);
+        }
+    }
+
+    // A side that never answers is dropped after the timeout, like a pulled cable
+    #[test]
+    fn silent_socket_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut link = SocketLink::connect(listener.local_addr().unwrap()).unwrap();
+        let _silent = listener.accept().unwrap();
+        link.set_timeout(Duration::from_millis(50)).unwrap();
+
+        let start = std::time::Instant::now();
+        link.sync(Some(0x42));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(link.connection.lock().unwrap().stream.is_none());
+        assert_eq!(link.exchange(0x42, 0), 0xFF);
+    }
+}
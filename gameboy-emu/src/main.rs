@@ -1,14 +1,30 @@
-#![allow(dead_code)]
+#![allow(dead_code, clippy::upper_case_acronyms)]
 mod bus;
 mod cpu;
+mod dmg07;
 mod gameboi;
+mod input;
+mod interrupts;
+mod link;
+mod movie;
+mod mobile;
+mod netplay;
 mod ppu;
+mod printer;
+mod rewind;
+mod savestate;
+mod scheduler;
+mod serial;
+mod tas;
+mod timer;
 use crate::gameboi::GameBoi;
+use crate::serial::BlarggOutput;
 
 //TODO: Implement loop for running the cpu and ppu,
 //And a function to load various roms in the cpu,
 fn main() {
     let mut rustboi = GameBoi::new();
+    rustboi.connect_serial(Box::new(BlarggOutput::new()));
     rustboi.load_rom_from_path("gb-test-roms/cpu_instrs/individual/01-special.gb");
     loop {
         rustboi.step();
@@ -0,0 +1,403 @@
+use crate::serial::SerialDevice;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// Commands, answered with the command | 0x80
+const BEGIN_SESSION: u8 = 0x10;
+const END_SESSION: u8 = 0x11;
+const DIAL: u8 = 0x12;
+const HANG_UP: u8 = 0x13;
+const TRANSFER_DATA: u8 = 0x15;
+const RESET: u8 = 0x16;
+const TELEPHONE_STATUS: u8 = 0x17;
+const READ_CONFIG: u8 = 0x19;
+const WRITE_CONFIG: u8 = 0x1A;
+const TRANSFER_DATA_END: u8 = 0x1F; // Answer to TRANSFER_DATA once the other end closed
+const ISP_LOGIN: u8 = 0x21;
+const ISP_LOGOUT: u8 = 0x22;
+const TCP_CONNECT: u8 = 0x23;
+const TCP_CLOSE: u8 = 0x24;
+const DNS_QUERY: u8 = 0x28;
+const ERROR: u8 = 0x6E;
+
+// Answered while the adapter has nothing to say
+const IDLE: u8 = 0xD2;
+// The adapter's answer to the Game Boy's ID after a packet, a blue (PDC) adapter
+const ADAPTER_ID: u8 = 0x88;
+const CHECKSUM_ERROR: u8 = 0xF1;
+
+// The connection made by dialing, TCP connections get the ids below
+const PHONE_CONNECTION: u8 = 0xFF;
+const MAX_CONNECTIONS: u8 = 2;
+const CONFIG_SIZE: usize = 192;
+// The IP handed out by ISP login
+const ADAPTER_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+// Bytes read from a connection for a single TRANSFER_DATA answer
+const MAX_TRANSFER: usize = 254;
+
+// ============ Configuration ============
+
+// Where the adapter's calls end up, nothing reaches the outside unless listed here:
+// phone numbers and the servers games connect to are sent to local endpoints, like a
+// fan server or a mock one
+#[derive(Clone, Default)]
+pub struct MobileConfig {
+    dns: HashMap<String, Ipv4Addr>,
+    servers: HashMap<(Ipv4Addr, u16), String>,
+    phones: HashMap<String, String>,
+}
+
+impl MobileConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // DNS queries for this name get this address
+    pub fn host(mut self, name: &str, ip: Ipv4Addr) -> Self {
+        self.dns.insert(name.to_string(), ip);
+        self
+    }
+
+    // TCP connections to ip:port go to the endpoint instead ("127.0.0.1:8080")
+    pub fn server(mut self, ip: Ipv4Addr, port: u16, endpoint: &str) -> Self {
+        self.servers.insert((ip, port), endpoint.to_string());
+        self
+    }
+
+    // Dialing this number connects to the endpoint
+    pub fn phone(mut self, number: &str, endpoint: &str) -> Self {
+        self.phones.insert(number.to_string(), endpoint.to_string());
+        self
+    }
+}
+
+// ============ Mobile Adapter GB ============
+
+// Where the adapter is in the exchange: receiving a packet from the Game Boy (magic,
+// header, data, checksum, then the 2 bytes that identify both sides), or sending its
+// answer back, a byte for each byte the Game Boy clocks
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Magic1,
+    Magic2,
+    Header(usize),
+    Data,
+    ChecksumHigh,
+    ChecksumLow,
+    DeviceId,
+    Acknowledge,
+    Answer,
+}
+
+struct Session {
+    config: MobileConfig,
+    config_data: [u8; CONFIG_SIZE], // The adapter's own memory, the games keep settings in it
+    started: bool,
+    logged_in: bool,
+    connections: HashMap<u8, TcpStream>,
+}
+
+// The Game Boy is the master, it sends a packet and then clocks the answer out.
+// Blocking network calls (connecting) stall the emulation for as long as they take, data
+// is only read if it's already there. Clones share the connections
+#[derive(Clone)]
+pub struct MobileAdapter {
+    phase: Phase,
+    header: [u8; 4], // Command, 0, length (big endian)
+    data: Vec<u8>,
+    checksum: u16,
+    checksum_ok: bool,
+    answer: Vec<u8>,
+    answered: usize,
+
+    session: Arc<Mutex<Session>>,
+}
+
+impl MobileAdapter {
+    pub fn new(config: MobileConfig) -> Self {
+        Self {
+            phase: Phase::Magic1,
+            header: [0; 4],
+            data: Vec::new(),
+            checksum: 0,
+            checksum_ok: false,
+            answer: Vec::new(),
+            answered: 0,
+            session: Arc::new(Mutex::new(Session {
+                config,
+                config_data: [0; CONFIG_SIZE],
+                started: false,
+                logged_in: false,
+                connections: HashMap::new(),
+            })),
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        use Phase::*;
+        let mut reply = IDLE;
+        self.phase = match self.phase {
+            Magic1 if byte == 0x99 => Magic2,
+            Magic1 => Magic1,
+            Magic2 if byte == 0x66 => Header(0),
+            Magic2 => Magic1,
+            Header(index) => {
+                self.header[index] = byte;
+                if index < 3 {
+                    Header(index + 1)
+                } else {
+                    self.data.clear();
+                    if self.length() == 0 {
+                        ChecksumHigh
+                    } else {
+                        Data
+                    }
+                }
+            }
+            Data => {
+                self.data.push(byte);
+                if self.data.len() == self.length() {
+                    ChecksumHigh
+                } else {
+                    Data
+                }
+            }
+            ChecksumHigh => {
+                self.checksum = (byte as u16) << 8;
+                ChecksumLow
+            }
+            ChecksumLow => {
+                self.checksum |= byte as u16;
+                self.checksum_ok = self.checksum == checksum(&self.header, &self.data);
+                DeviceId
+            }
+            DeviceId => {
+                reply = ADAPTER_ID;
+                Acknowledge
+            }
+            Acknowledge => {
+                if !self.checksum_ok {
+                    reply = CHECKSUM_ERROR;
+                    Magic1
+                } else {
+                    let command = self.header[0];
+                    reply = command | 0x80;
+                    let (command, data) = self.session.lock().unwrap().execute(command, &self.data);
+                    self.answer = packet(command, &data);
+                    self.answered = 0;
+                    Answer
+                }
+            }
+            Answer => {
+                reply = self.answer[self.answered];
+                self.answered += 1;
+                if self.answered == self.answer.len() {
+                    Magic1
+                } else {
+                    Answer
+                }
+            }
+        };
+        reply
+    }
+
+    fn length(&self) -> usize {
+        u16::from_be_bytes([self.header[2], self.header[3]]) as usize
+    }
+}
+
+impl SerialDevice for MobileAdapter {
+    fn exchange(&mut self, byte: u8, _done_at: u64) -> u8 {
+        self.receive(byte)
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+fn checksum(header: &[u8], data: &[u8]) -> u16 {
+    header
+        .iter()
+        .chain(data)
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+}
+
+// An answer to the Game Boy, followed by the adapter's ID and a 0 while the Game Boy
+// sends its own ID and acknowledges
+fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+    let header = [command | 0x80, 0, 0, data.len() as u8];
+    let mut packet = vec![0x99, 0x66];
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&checksum(&header, data).to_be_bytes());
+    packet.extend_from_slice(&[ADAPTER_ID, 0x00]);
+    packet
+}
+
+impl Session {
+    // Returns the command and data to answer with
+    fn execute(&mut self, command: u8, data: &[u8]) -> (u8, Vec<u8>) {
+        let error = |code: u8| (ERROR, vec![command, code]);
+        if !self.started && command != BEGIN_SESSION {
+            return error(1);
+        }
+
+        match command {
+            BEGIN_SESSION => {
+                self.started = true;
+                (command, data.to_vec())
+            }
+            END_SESSION | RESET => {
+                self.started = command == RESET;
+                self.logged_in = false;
+                self.connections.clear();
+                (command, Vec::new())
+            }
+            DIAL => {
+                // A byte for the kind of phone, then the number
+                let number = String::from_utf8_lossy(data.get(1..).unwrap_or_default());
+                let endpoint = self.config.phones.get(number.trim()).cloned();
+                match endpoint.map(|endpoint| self.connect(PHONE_CONNECTION, &endpoint)) {
+                    Some(Ok(())) => (command, Vec::new()),
+                    _ => error(3),
+                }
+            }
+            HANG_UP => {
+                self.connections.remove(&PHONE_CONNECTION);
+                (command, Vec::new())
+            }
+            TRANSFER_DATA => {
+                let Some(&id) = data.first() else {
+                    return error(2);
+                };
+                match self.transfer(id, &data[1..]) {
+                    Some(received) => {
+                        let mut answer = vec![id];
+                        answer.extend(received);
+                        (command, answer)
+                    }
+                    // The other end closed the connection
+                    None => (TRANSFER_DATA_END, vec![id]),
+                }
+            }
+            TELEPHONE_STATUS => {
+                let status = if self.connections.contains_key(&PHONE_CONNECTION) {
+                    0x05
+                } else {
+                    0x00
+                };
+                (command, vec![status, 0x4D, 0x00])
+            }
+            READ_CONFIG => {
+                let (offset, length) = match data {
+                    [offset, length, ..] => (*offset as usize, *length as usize),
+                    _ => return error(2),
+                };
+                let Some(bytes) = self.config_data.get(offset..offset + length) else {
+                    return error(2);
+                };
+                let mut answer = vec![offset as u8];
+                answer.extend_from_slice(bytes);
+                (command, answer)
+            }
+            WRITE_CONFIG => {
+                let Some((&offset, bytes)) = data.split_first() else {
+                    return error(2);
+                };
+                let offset = offset as usize;
+                let Some(target) = self.config_data.get_mut(offset..offset + bytes.len()) else {
+                    return error(2);
+                };
+                target.copy_from_slice(bytes);
+                (command, vec![offset as u8, bytes.len() as u8])
+            }
+            ISP_LOGIN => {
+                // The login and DNS servers sent by the game don't matter, DNS is answered
+                // from the configuration
+                self.logged_in = true;
+                let mut answer = ADAPTER_IP.octets().to_vec();
+                answer.extend_from_slice(&[0; 8]);
+                (command, answer)
+            }
+            ISP_LOGOUT => {
+                self.logged_in = false;
+                self.connections.retain(|&id, _| id == PHONE_CONNECTION);
+                (command, Vec::new())
+            }
+            TCP_CONNECT if self.logged_in => {
+                let [a, b, c, d, high, low, ..] = *data else {
+                    return error(2);
+                };
+                let (ip, port) = (Ipv4Addr::new(a, b, c, d), u16::from_be_bytes([high, low]));
+                let Some(id) = (0..MAX_CONNECTIONS).find(|id| !self.connections.contains_key(id))
+                else {
+                    return error(0);
+                };
+                let endpoint = self.config.servers.get(&(ip, port)).cloned();
+                match endpoint.map(|endpoint| self.connect(id, &endpoint)) {
+                    Some(Ok(())) => (command, vec![id]),
+                    _ => error(3),
+                }
+            }
+            TCP_CLOSE => {
+                let id = data.first().copied().unwrap_or(0);
+                self.connections.remove(&id);
+                (command, vec![id])
+            }
+            DNS_QUERY if self.logged_in => {
+                let name = String::from_utf8_lossy(data);
+                match self.config.dns.get(name.trim_end_matches('\0')) {
+                    Some(ip) => (command, ip.octets().to_vec()),
+                    None => error(2),
+                }
+            }
+            _ => error(0),
+        }
+    }
+
+    fn connect(&mut self, id: u8, endpoint: &str) -> io::Result<()> {
+        let stream = TcpStream::connect(endpoint)?;
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        self.connections.insert(id, stream);
+        Ok(())
+    }
+
+    // Sends the data, returns whatever already came back, None once the other end closed
+    fn transfer(&mut self, id: u8, data: &[u8]) -> Option<Vec<u8>> {
+        let stream = self.connections.get_mut(&id)?;
+        if write_all_nonblocking(stream, data).is_err() {
+            self.connections.remove(&id);
+            return None;
+        }
+
+        let mut buffer = [0; MAX_TRANSFER];
+        match stream.read(&mut buffer) {
+            Ok(0) => {
+                self.connections.remove(&id);
+                None
+            }
+            Ok(read) => Some(buffer[..read].to_vec()),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Some(Vec::new()),
+            Err(_) => {
+                self.connections.remove(&id);
+                None
+            }
+        }
+    }
+}
+
+fn write_all_nonblocking(stream: &mut TcpStream, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => data = &data[written..],
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => std::thread::yield_now(),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
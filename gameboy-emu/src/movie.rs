@@ -0,0 +1,264 @@
+use crate::gameboi::GameBoi;
+use crate::input::InputState;
+use crate::savestate::StateError;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+// ============ Movie Format ============
+
+// Little endian binary:
+//   "RBMV", version (u16), model (u8), ROM hash (u64)
+//   start: 0 for power on, 1 followed by the length (u32) and a save state
+//   frame count (u32), then for each frame the buttons held (InputState bits) and a
+//   flag, followed by the state hash after the frame if it is set
+const MAGIC: [u8; 4] = *b"RBMV";
+const VERSION: u16 = 1;
+// Only the DMG is emulated
+const MODEL_DMG: u8 = 0;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    NotAMovie,
+    UnsupportedVersion(u16),
+    UnsupportedModel(u8),
+    Truncated,
+    WrongRom,     // The movie was recorded with another ROM
+    NotAtPowerOn, // The movie starts at power on, the GameBoi has already run
+    State(StateError),
+    Desync { frame: usize }, // The state after this frame isn't the one recorded
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(error) => write!(f, "{error}"),
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "movie version {version}, only {VERSION} is supported")
+            }
+            MovieError::UnsupportedModel(model) => write!(f, "movie for an unknown model {model}"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::WrongRom => write!(f, "movie was recorded with another ROM"),
+            MovieError::NotAtPowerOn => {
+                write!(f, "movie starts at power on, the Game Boy has already run")
+            }
+            MovieError::State(error) => write!(f, "movie start: {error}"),
+            MovieError::Desync { frame } => write!(f, "movie desynced on frame {frame}"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(error: io::Error) -> Self {
+        MovieError::Io(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MovieFrame {
+    input: InputState,
+    hash: Option<u64>,
+}
+
+// Inputs for every frame from a starting point, played back they give the same frames.
+// Input is held for a whole frame, set right before it runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    rom_hash: u64,
+    start: Option<Vec<u8>>, // A save state, None for power on
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn input(&self, frame: usize) -> Option<InputState> {
+        self.frames.get(frame).map(|frame| frame.input)
+    }
+
+    pub fn starts_at_power_on(&self) -> bool {
+        self.start.is_none()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.push(MODEL_DMG);
+        data.extend_from_slice(&self.rom_hash.to_le_bytes());
+        match &self.start {
+            None => data.push(0),
+            Some(state) => {
+                data.push(1);
+                data.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                data.extend_from_slice(state);
+            }
+        }
+
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in &self.frames {
+            data.push(frame.input.bits());
+            data.push(frame.hash.is_some() as u8);
+            if let Some(hash) = frame.hash {
+                data.extend_from_slice(&hash.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut data = data;
+        let mut take = |count: usize| -> Result<&[u8], MovieError> {
+            if data.len() < count {
+                return Err(MovieError::Truncated);
+            }
+            let (taken, rest) = data.split_at(count);
+            data = rest;
+            Ok(taken)
+        };
+
+        if take(4).ok() != Some(&MAGIC[..]) {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let model = take(1)?[0];
+        if model != MODEL_DMG {
+            return Err(MovieError::UnsupportedModel(model));
+        }
+        let rom_hash = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let start = match take(1)?[0] {
+            0 => None,
+            _ => {
+                let length = u32::from_le_bytes(take(4)?.try_into().unwrap());
+                Some(take(length as usize)?.to_vec())
+            }
+        };
+
+        let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let header = take(2)?;
+            let input = InputState::from_bits(header[0]);
+            let hash = match header[1] {
+                0 => None,
+                _ => Some(u64::from_le_bytes(take(8)?.try_into().unwrap())),
+            };
+            frames.push(MovieFrame { input, hash });
+        }
+        Ok(Movie {
+            rom_hash,
+            start,
+            frames,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Movie, MovieError> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+// ============ Recording ============
+
+// Runs the GameBoi frame by frame, keeping the input of each
+pub struct MovieRecorder {
+    movie: Movie,
+    hashes: bool,
+}
+
+impl MovieRecorder {
+    // The movie starts where the GameBoi is: at power on if it hasn't run yet, from a
+    // save state otherwise. With `hashes`, the state hash after every frame is kept
+    // so playback can tell when it desyncs
+    pub fn new(gameboi: &GameBoi, hashes: bool) -> Self {
+        let start = (gameboi.clock() != 0).then(|| gameboi.save_state());
+        Self {
+            movie: Movie {
+                rom_hash: gameboi.rom_hash(),
+                start,
+                frames: Vec::new(),
+            },
+            hashes,
+        }
+    }
+
+    pub fn step(&mut self, gameboi: &mut GameBoi, input: InputState) -> [u8; 23040] {
+        gameboi.set_input(input);
+        let frame = gameboi.step();
+        let hash = self.hashes.then(|| gameboi.state_hash());
+        self.movie.frames.push(MovieFrame { input, hash });
+        frame
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// ============ Playback ============
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    // Gets the GameBoi to where the movie starts, it needs the movie's ROM loaded
+    pub fn new(movie: Movie, gameboi: &mut GameBoi) -> Result<Self, MovieError> {
+        if gameboi.rom_hash() != movie.rom_hash {
+            return Err(MovieError::WrongRom);
+        }
+        match &movie.start {
+            Some(state) => gameboi.load_state(state).map_err(MovieError::State)?,
+            None if gameboi.clock() != 0 => return Err(MovieError::NotAtPowerOn),
+            None => {}
+        }
+        Ok(Self { movie, frame: 0 })
+    }
+
+    // The next frame of the movie, None once it's over
+    pub fn step(&mut self, gameboi: &mut GameBoi) -> Option<Result<[u8; 23040], MovieError>> {
+        let MovieFrame { input, hash } = *self.movie.frames.get(self.frame)?;
+        gameboi.set_input(input);
+        let frame = gameboi.step();
+        let index = self.frame;
+        self.frame += 1;
+
+        if hash.is_some_and(|hash| hash != gameboi.state_hash()) {
+            return Some(Err(MovieError::Desync { frame: index }));
+        }
+        Some(Ok(frame))
+    }
+
+    // Frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame == self.movie.len()
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
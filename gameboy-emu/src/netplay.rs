@@ -0,0 +1,315 @@
+use crate::input::InputState;
+use crate::link::LinkedPair;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+// How many frames a side may run past the last input it heard from the other one,
+// guessing what it holds. Further than that, it waits
+const MAX_PREDICTION: usize = 8;
+// While waiting, unacknowledged inputs are sent again this often, packets get lost
+const RESEND: Duration = Duration::from_millis(5);
+// Nothing heard for this long while waiting, the other side is gone
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// ============ Packets ============
+
+// Little endian: the session hash (u64), how many of the receiver's inputs the sender
+// has (u32), the frame of the first input (u32), the input count (u8), then the inputs
+// as InputState bits. Each packet repeats every input not yet acknowledged
+const HEADER: usize = 17;
+const MAX_INPUTS: usize = 255;
+
+struct Packet {
+    session: u64,
+    ack: usize,
+    first: usize,
+    inputs: Vec<InputState>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.session.to_le_bytes().to_vec();
+        data.extend_from_slice(&(self.ack as u32).to_le_bytes());
+        data.extend_from_slice(&(self.first as u32).to_le_bytes());
+        data.push(self.inputs.len() as u8);
+        data.extend(self.inputs.iter().map(|input| input.bits()));
+        data
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Packet> {
+        let count = *data.get(HEADER - 1)? as usize;
+        if data.len() != HEADER + count {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as usize;
+        Some(Packet {
+            session: u64::from_le_bytes(data[..8].try_into().unwrap()),
+            ack: u32_at(8),
+            first: u32_at(12),
+            inputs: data[HEADER..]
+                .iter()
+                .map(|&bits| InputState::from_bits(bits))
+                .collect(),
+        })
+    }
+}
+
+// ============ Rollback Session ============
+
+// A frame run without the other side's input, on a guess
+struct Predicted {
+    state: Vec<u8>, // The pair's state before the frame
+    remote: InputState,
+}
+
+// One of two players, each with their own emulator running both Game Boys of a linked
+// pair, the player's own on `side`. Both sides send their inputs for every frame over
+// UDP, and go on without waiting for the other one's: until it arrives, the other
+// player is guessed to hold what they held last. When a guess turns out wrong, the pair
+// goes back to the state before that frame and runs again with the right inputs.
+// Since both pairs run the same frames with the same inputs, they end up the same
+pub struct NetplaySession {
+    pair: LinkedPair,
+    side: usize,
+    socket: UdpSocket,
+    session: u64, // The starting state's hash, both sides must start from the same one
+    frame: usize, // Frames run
+    local: Vec<InputState>,
+    remote: Vec<InputState>,        // Heard so far, from the first frame on
+    acked: usize,                   // Local inputs the other side has
+    predicted: VecDeque<Predicted>, // The last frames run, on a guess
+    screen: [u8; 23040],
+    latency: Duration,
+    outgoing: VecDeque<(Instant, Vec<u8>)>, // Held back for the latency
+    last_sent: Instant,
+    last_heard: Instant,
+    rollbacks: u64,
+    resimulated: u64, // Frames run again
+}
+
+impl NetplaySession {
+    // Both sides need the same ROM and the pair in the same state, fresh from power on
+    // or loaded from the same save state
+    pub fn new(
+        pair: LinkedPair,
+        side: usize,
+        address: impl ToSocketAddrs,
+        peer: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        Self::from_socket(pair, side, UdpSocket::bind(address)?, peer)
+    }
+
+    // With a socket already bound, to pick a free port and tell the other side about it
+    pub fn from_socket(
+        pair: LinkedPair,
+        side: usize,
+        socket: UdpSocket,
+        peer: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        let now = Instant::now();
+        Ok(Self {
+            session: pair.state_hash(),
+            pair,
+            side: side & 1,
+            socket,
+            frame: 0,
+            local: Vec::new(),
+            remote: Vec::new(),
+            acked: 0,
+            predicted: VecDeque::new(),
+            screen: [0; 23040],
+            latency: Duration::ZERO,
+            outgoing: VecDeque::new(),
+            last_sent: now,
+            last_heard: now,
+            rollbacks: 0,
+            resimulated: 0,
+        })
+    }
+
+    // Holds back every packet sent for this long, to try a slow network on one machine.
+    // Packets go out on the first step after they're due
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    // Runs a frame with the buttons this player holds, returns their Game Boy's screen.
+    // Waits when too far ahead of the other side
+    pub fn step(&mut self, input: InputState) -> io::Result<[u8; 23040]> {
+        self.local.push(input);
+        self.send()?;
+        self.receive()?;
+        while self.frame >= self.remote.len() + MAX_PREDICTION {
+            self.wait()?;
+        }
+        self.roll_back();
+        self.run_frame(self.frame);
+        self.frame += 1;
+        Ok(self.screen)
+    }
+
+    // Waits until both sides have each other's inputs for every frame and any wrong
+    // guess is run again, once both have run the same number of frames
+    pub fn sync(&mut self) -> io::Result<()> {
+        while self.remote.len() < self.frame || self.acked < self.local.len() {
+            self.wait()?;
+        }
+        self.roll_back();
+        // The other side may still be waiting to hear we have everything
+        self.send()?;
+        while let Some(&(due, _)) = self.outgoing.front() {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Frames run so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    // Frames run without the other side's input, which may still have to run again
+    pub fn predicted_frames(&self) -> usize {
+        self.predicted.len()
+    }
+
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    pub fn resimulated_frames(&self) -> u64 {
+        self.resimulated
+    }
+
+    // The same on both sides once synced
+    pub fn state_hash(&self) -> u64 {
+        self.pair.state_hash()
+    }
+
+    fn remote_input(&self, frame: usize) -> InputState {
+        match self.remote.get(frame).or(self.remote.last()) {
+            Some(&input) => input,
+            None => InputState::new(),
+        }
+    }
+
+    fn run_frame(&mut self, frame: usize) {
+        let remote = self.remote_input(frame);
+        if frame >= self.remote.len() {
+            self.predicted.push_back(Predicted {
+                state: self.pair.save_state(),
+                remote,
+            });
+        }
+        self.pair.gameboi(self.side).set_input(self.local[frame]);
+        self.pair.gameboi(1 - self.side).set_input(remote);
+        self.screen = self.pair.step()[self.side];
+    }
+
+    // Goes back to the first wrong guess if there is one, runs the frames since again,
+    // and forgets the guesses that were right
+    fn roll_back(&mut self) {
+        let first = self.frame - self.predicted.len();
+        let heard = self.remote.len().min(self.frame);
+        let wrong = (first..heard)
+            .find(|&frame| self.predicted[frame - first].remote != self.remote[frame]);
+
+        if let Some(wrong) = wrong {
+            let state = &self.predicted[wrong - first].state;
+            self.pair
+                .load_state(state)
+                .expect("the pair saved this state");
+            self.predicted.clear();
+            for frame in wrong..self.frame {
+                self.run_frame(frame);
+            }
+            self.rollbacks += 1;
+            self.resimulated += (self.frame - wrong) as u64;
+        } else {
+            self.predicted.drain(..heard.max(first) - first);
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let count = (self.local.len() - self.acked).min(MAX_INPUTS);
+        let packet = Packet {
+            session: self.session,
+            ack: self.remote.len(),
+            first: self.acked,
+            inputs: self.local[self.acked..self.acked + count].to_vec(),
+        };
+        self.outgoing
+            .push_back((Instant::now() + self.latency, packet.to_bytes()));
+        self.last_sent = Instant::now();
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some((due, data)) = self.outgoing.front() {
+            if *due > Instant::now() {
+                break;
+            }
+            match self.socket.send(data) {
+                // Nobody listening yet, the packet is lost
+                Err(error) if error.kind() != io::ErrorKind::ConnectionRefused => {
+                    return Err(error);
+                }
+                _ => {}
+            }
+            self.outgoing.pop_front();
+        }
+        Ok(())
+    }
+
+    // Takes in every packet that arrived, returns whether there was any
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut received = false;
+        let mut data = [0; HEADER + MAX_INPUTS];
+        loop {
+            let length = match self.socket.recv(&mut data) {
+                Ok(length) => length,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(received),
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(error) => return Err(error),
+            };
+            let Some(packet) = Packet::from_bytes(&data[..length]) else {
+                continue;
+            };
+            if packet.session != self.session {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the other side didn't start from the same state",
+                ));
+            }
+
+            received = true;
+            self.last_heard = Instant::now();
+            self.acked = self.acked.max(packet.ack.min(self.local.len()));
+            // Inputs already heard come again until acknowledged, a gap means a packet
+            // got lost and the next ones fill it
+            let skip = self.remote.len().saturating_sub(packet.first);
+            if packet.first <= self.remote.len() {
+                self.remote.extend(packet.inputs.iter().skip(skip));
+            }
+        }
+    }
+
+    fn wait(&mut self) -> io::Result<()> {
+        self.flush()?;
+        if self.last_sent.elapsed() >= RESEND {
+            self.send()?;
+        }
+        if !self.receive()? {
+            if self.last_heard.elapsed() > TIMEOUT {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
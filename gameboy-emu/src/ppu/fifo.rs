@@ -0,0 +1,667 @@
+use super::*;
+use crate::bus::{BusAccess, Memory};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+use std::fmt;
+
+// An object the renderer stopped on during PixelTransfer, it is only fetched once the
+// background fetcher is done with its current tile
+#[derive(Clone)]
+struct ObjFetch {
+    obj: Obj,
+    started: bool,
+    remaining_dots: u8,
+}
+
+// ============ FIFO Renderer ============
+
+#[derive(Clone)]
+pub struct FifoRenderer {
+    fetcher: PixelFetcher,
+    bg_fifo: PixelFIFO,
+    obj_fifo: PixelFIFO,
+
+    fine_scroll_x: u8, // Pixels still to be discarded at the start of the line (SCX % 8)
+    lx: u8,            // Next pixel to be drawn on the current line
+    ly: u8,
+
+    line_objs: Vec<Obj>,
+    next_obj: usize,
+    obj_fetch: Option<ObjFetch>,
+
+    window_triggered: bool,
+    window_drawn: bool,
+}
+
+impl FifoRenderer {
+    pub fn new() -> Self {
+        let fetcher = PixelFetcher::new();
+        Self {
+            fetcher,
+            bg_fifo: PixelFIFO::new(),
+            obj_fifo: PixelFIFO::new(),
+            fine_scroll_x: 0,
+            lx: 0,
+            ly: 0,
+            line_objs: Vec::with_capacity(10),
+            next_obj: 0,
+            obj_fetch: None,
+            window_triggered: false,
+            window_drawn: false,
+        }
+    }
+
+    fn fetch_lcdc_register(&self, mem: &mut Memory) -> LcdcRegister {
+        LcdcRegister::new(mem.read(LCDC))
+    }
+
+    fn apply_palette(&self, mem: &mut Memory, mut pixel: Pixel) -> Pixel {
+        let palette = match pixel.palette {
+            Option::None => mem.read(BGP),
+            Some(0) => mem.read(OBP0),
+            Some(1) => mem.read(OBP1),
+            _ => unreachable!(),
+        };
+        let shade = (palette >> (pixel.color * 2)) & 3;
+        pixel.color = shade;
+        pixel
+    }
+
+    // Returns the next object whose left edge has been reached by the current pixel
+    fn object_at(&mut self, lcdc: &LcdcRegister) -> Option<Obj> {
+        let obj = *self.line_objs.get(self.next_obj)?;
+        if obj.x > self.lx + 8 {
+            return None;
+        }
+        self.next_obj += 1;
+
+        // Disabled objects are skipped, and are not fetched even if re-enabled later on the line
+        if lcdc.obj_enable { Some(obj) } else { None }
+    }
+
+    fn update_obj_fifo(&mut self, mem: &mut Memory, obj: Obj) {
+        let lcdc = self.fetch_lcdc_register(mem);
+        let height = lcdc.obj_height();
+
+        // Early out if we're outside the sprite
+        if !obj.covers(self.ly, height) {
+            return;
+        }
+        let (tile_index, row_in_tile) = obj.tile_row(self.ly, height);
+
+        // Objects always use the 0x8000 tiles, the cache holds them already flipped
+        let row = mem.tile_row(tile_index as u16, row_in_tile, obj.flipx);
+
+        // Objects partially hidden on the left only have their visible pixels merged
+        let skip = (self.lx + 8).saturating_sub(obj.x);
+        self.obj_fifo.merge_tile_row(row, obj, skip);
+    }
+
+    // Palettes and the enable bits are applied as the pixel leaves the FIFOs, so
+    // changes in the middle of a line affect the very next pixel
+    fn mix_fifo_pixels(&mut self, mem: &mut Memory) -> u8 {
+        let lcdc = self.fetch_lcdc_register(mem);
+        let bg_pixel = self.bg_fifo.pop().unwrap();
+        let obj_pixel = self.obj_fifo.pop();
+
+        // On DMG, a disabled background is drawn white, and never hides objects
+        let bg_color = if lcdc.bg_enable { bg_pixel.color } else { 0 };
+
+        if let Some(obj_pixel) = obj_pixel {
+            let visible = lcdc.obj_enable && obj_pixel.color != 0;
+            if visible && (obj_pixel.sprite_priority || bg_color == 0) {
+                return self.apply_palette(mem, obj_pixel).color;
+            }
+        }
+
+        if lcdc.bg_enable {
+            self.apply_palette(mem, bg_pixel).color
+        } else {
+            0
+        }
+    }
+
+    fn window_starts_here(&self, mem: &mut Memory, lcdc: &LcdcRegister) -> bool {
+        if !lcdc.window_enabled || !self.window_triggered || self.fetcher.fetching_window {
+            return false;
+        }
+        let wx = mem.read(WX);
+        // WX < 7 starts the window before the first pixel
+        self.lx as u16 + 7 >= wx as u16 && (wx < 167)
+    }
+
+    fn start_window(&mut self, mem: &mut Memory) {
+        let wx = mem.read(WX);
+        self.bg_fifo.clear();
+        self.fetcher.start_window();
+        self.window_drawn = true;
+        if self.lx == 0 && wx < 7 {
+            self.fine_scroll_x = 7 - wx;
+        }
+    }
+}
+
+impl Renderer for FifoRenderer {
+    fn start_line(&mut self, mem: &mut Memory, line: LineInfo, objs: &[Obj]) {
+        self.fine_scroll_x = mem.read(SCX) % 8;
+        self.lx = 0;
+        self.ly = line.ly;
+        self.window_triggered = line.window_triggered;
+        self.window_drawn = false;
+
+        self.line_objs.clear();
+        self.line_objs.extend_from_slice(objs);
+        self.next_obj = 0;
+
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.obj_fetch = None;
+        self.fetcher.start_line(line);
+    }
+
+    // Runs a single dot of mode 3
+    fn pixeltransfer(&mut self, mem: &mut Memory, viewport: &mut Viewport) -> bool {
+        if let Some(fetch) = self.obj_fetch.as_mut() {
+            if fetch.started {
+                fetch.remaining_dots -= 1;
+                if fetch.remaining_dots == 0 {
+                    let obj = fetch.obj;
+                    self.obj_fetch = None;
+                    self.update_obj_fifo(mem, obj);
+                }
+                return false;
+            }
+
+            // The background fetcher finishes its tile before the object is fetched
+            if self.fetcher.is_waiting() && !self.bg_fifo.is_empty() {
+                fetch.started = true;
+                fetch.remaining_dots = OBJ_FETCH_DOTS - 1;
+            } else {
+                self.fetcher.step(mem, &mut self.bg_fifo);
+            }
+            return false;
+        }
+
+        self.fetcher.step(mem, &mut self.bg_fifo);
+
+        if self.bg_fifo.is_empty() {
+            return false;
+        }
+
+        //We discard the pixels scrolled out by SCX
+        if self.fine_scroll_x > 0 {
+            self.bg_fifo.pop();
+            self.fine_scroll_x -= 1;
+            return false;
+        }
+
+        let lcdc = self.fetch_lcdc_register(mem);
+        if self.window_starts_here(mem, &lcdc) {
+            self.start_window(mem);
+            return false;
+        }
+
+        //If there are objects at current coordinates, we stop to fetch them
+        if let Some(obj) = self.object_at(&lcdc) {
+            self.obj_fetch = Some(ObjFetch {
+                obj,
+                started: false,
+                remaining_dots: 0,
+            });
+            return false;
+        }
+
+        //This both pops and mixes pixels
+        let pixel_to_draw = self.mix_fifo_pixels(mem);
+        let idx = self.ly as usize * WIDTH + self.lx as usize;
+        viewport[idx] = pixel_to_draw;
+        self.lx += 1;
+
+        self.lx as usize == WIDTH
+    }
+
+    fn window_drawn(&self) -> bool {
+        self.window_drawn
+    }
+
+    fn clone_box(&self) -> Box<dyn Renderer> {
+        Box::new(self.clone())
+    }
+
+    fn kind(&self) -> RendererKind {
+        RendererKind::Fifo
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.fetcher.save_state(state);
+        self.bg_fifo.save_state(state);
+        self.obj_fifo.save_state(state);
+        state.u8(self.fine_scroll_x);
+        state.u8(self.lx);
+        state.u8(self.ly);
+        save_objs(state, &self.line_objs);
+        state.u8(self.next_obj as u8);
+        state.bool(self.obj_fetch.is_some());
+        if let Some(fetch) = &self.obj_fetch {
+            fetch.obj.save_state(state);
+            state.bool(fetch.started);
+            state.u8(fetch.remaining_dots);
+        }
+        state.bool(self.window_triggered);
+        state.bool(self.window_drawn);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.fetcher.load_state(state)?;
+        self.bg_fifo.load_state(state)?;
+        self.obj_fifo.load_state(state)?;
+        self.fine_scroll_x = state.u8()?;
+        self.lx = state.u8()?;
+        self.ly = state.u8()?;
+        load_objs(state, &mut self.line_objs)?;
+        self.next_obj = state.u8()? as usize;
+        self.obj_fetch = if state.bool()? {
+            Some(ObjFetch {
+                obj: Obj::load_state(state)?,
+                started: state.bool()?,
+                remaining_dots: state.u8()?,
+            })
+        } else {
+            None
+        };
+        self.window_triggered = state.bool()?;
+        self.window_drawn = state.bool()?;
+        if self.lx as usize > WIDTH || self.next_obj > self.line_objs.len() {
+            return Err(StateError::Invalid("pixel FIFO position"));
+        }
+        Ok(())
+    }
+}
+
+// ============= Pixel FIFO ============
+
+#[derive(Copy, Clone)]
+pub struct Pixel {
+    pub color: u8, // 0–3 after palette
+    pub bg_priority: bool,
+    pub sprite_priority: bool,
+    pub palette: Option<u8>,
+}
+
+impl Pixel {
+    fn transparent() -> Pixel {
+        Pixel {
+            color: 0,
+            bg_priority: false,
+            sprite_priority: false,
+            palette: Some(0),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct PixelFIFO {
+    queue: VecDeque<Pixel>,
+}
+
+impl fmt::Debug for PixelFIFO {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PixelFIFO(len={}) [", self.queue.len())?;
+
+        for (i, px) in self.queue.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", px.color)?;
+        }
+
+        write!(f, "]")
+    }
+}
+
+impl PixelFIFO {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::with_capacity(16),
+        }
+    }
+
+    pub fn push(&mut self, px: Pixel) {
+        if self.queue.len() < 16 {
+            self.queue.push_back(px);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Pixel> {
+        self.queue.pop_front()
+    }
+
+    pub fn can_push(&self) -> bool {
+        // The background fetcher only pushes a tile once the FIFO is empty
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // Pixels are a byte each: color in the low bits, then the flags, and the palette
+    // in the upper nibble (0xF for the background)
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.queue.len() as u8);
+        for pixel in &self.queue {
+            let palette = pixel.palette.unwrap_or(0xF);
+            state.u8(pixel.color
+                | (pixel.bg_priority as u8) << 2
+                | (pixel.sprite_priority as u8) << 3
+                | palette << 4);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let len = state.u8()?;
+        if len > 16 {
+            return Err(StateError::Invalid("pixel FIFO length"));
+        }
+        self.queue.clear();
+        for _ in 0..len {
+            let byte = state.u8()?;
+            self.queue.push_back(Pixel {
+                color: byte & 3,
+                bg_priority: byte & 0x04 != 0,
+                sprite_priority: byte & 0x08 != 0,
+                palette: match byte >> 4 {
+                    0xF => None,
+                    palette => Some(palette),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    pub fn push_tile_row(&mut self, row: [u8; 8]) {
+        for color in row {
+            self.push(Pixel {
+                color,
+                bg_priority: false,
+                sprite_priority: false,
+                palette: None,
+            });
+        }
+    }
+
+    // Objects are mixed into the object FIFO: an already present pixel is only
+    // replaced if it is transparent, so earlier objects keep priority
+    pub fn merge_tile_row(&mut self, row: [u8; 8], obj: Obj, skip: u8) {
+        while self.queue.len() < 8 {
+            self.queue.push_back(Pixel::transparent());
+        }
+
+        for (i, &color) in row.iter().enumerate().skip(skip as usize) {
+            let slot = &mut self.queue[i - skip as usize];
+            if slot.color == 0 {
+                *slot = Pixel {
+                    color,
+                    bg_priority: false,
+                    sprite_priority: obj.priority == 0,
+                    palette: Some(obj.palette),
+                };
+            }
+        }
+    }
+}
+
+// ============= Pixel Fetcher ============
+#[derive(Copy, Clone, Debug)]
+// The first 3 steps take 2 dots each and the last step is attempted every dot until it succeeds
+enum FetcherState {
+    GetTileIndex = 0,
+    GetTileLow = 1,
+    GetTileHigh = 2,
+    PushToFifo = 4, // 3 was a sleep state, never entered
+}
+
+#[derive(Clone)]
+struct PixelFetcher {
+    clock: u8,
+    state: FetcherState,
+    internal_ly: u8,
+    tile_x: u8, // Current horizontal tile index
+    tile_y: u8, // Current vertical tile index (or LY / 8)
+    tile_index: u8,
+    row: [u8; 8], // Color indices of the row being fetched
+
+    window_line: u8, // Lines of the window drawn so far this frame
+    fetching_window: bool,
+
+    // The first fetch of every line is done twice, the first result being thrown away
+    dummy_fetch: bool,
+}
+
+impl fmt::Debug for PixelFetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PixelFetcher(state={:?}, tile=({},{}), index={}, row={:?}, clock={})",
+            self.state, self.tile_x, self.tile_y, self.tile_index, self.row, self.clock
+        )
+    }
+}
+
+use FetcherState::*;
+impl PixelFetcher {
+    fn new() -> Self {
+        Self {
+            state: FetcherState::GetTileIndex,
+
+            internal_ly: 0,
+            tile_x: 0,
+            tile_y: 0,
+
+            tile_index: 0,
+            row: [0; 8],
+            clock: 0,
+
+            window_line: 0,
+            fetching_window: false,
+            dummy_fetch: false,
+        }
+    }
+
+    fn start_line(&mut self, line: LineInfo) {
+        self.internal_ly = line.ly;
+        self.window_line = line.window_line;
+        self.tile_x = 0;
+        self.clock = 0;
+        self.state = GetTileIndex;
+        self.fetching_window = false;
+        self.dummy_fetch = true;
+    }
+
+    fn start_window(&mut self) {
+        self.tile_x = 0;
+        self.clock = 0;
+        self.state = GetTileIndex;
+        self.fetching_window = true;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.clock);
+        state.u8(self.state as u8);
+        for byte in [self.internal_ly, self.tile_x, self.tile_y, self.tile_index] {
+            state.u8(byte);
+        }
+        state.bytes(&self.row);
+        state.u8(self.window_line);
+        state.bool(self.fetching_window);
+        state.bool(self.dummy_fetch);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.clock = state.u8()?;
+        self.state = match state.u8()? {
+            0 => GetTileIndex,
+            1 => GetTileLow,
+            2 => GetTileHigh,
+            4 => PushToFifo,
+            _ => return Err(StateError::Invalid("fetcher state")),
+        };
+        self.internal_ly = state.u8()?;
+        self.tile_x = state.u8()?;
+        self.tile_y = state.u8()?;
+        self.tile_index = state.u8()?;
+        state.bytes(&mut self.row)?;
+        self.window_line = state.u8()?;
+        self.fetching_window = state.bool()?;
+        self.dummy_fetch = state.bool()?;
+        Ok(())
+    }
+
+    // True once the current tile is fetched, and is only waiting for room in the FIFO
+    fn is_waiting(&self) -> bool {
+        matches!(self.state, PushToFifo)
+    }
+
+    //TODO! This is lazy
+    fn fetch_lcdc_register(&self, mem: &mut Memory) -> LcdcRegister {
+        LcdcRegister::new(mem.read(LCDC))
+    }
+
+    fn get_tile_idx(&mut self, mem: &mut Memory) {
+        let lcdc = self.fetch_lcdc_register(mem);
+
+        // Tilemap address
+        let tilemap_address = if self.fetching_window {
+            if lcdc.window_tilemap {
+                TILE_MAP1_ADDRESS
+            } else {
+                TILE_MAP0_ADDRESS
+            }
+        } else if lcdc.bg_tilemap {
+            TILE_MAP1_ADDRESS
+        } else {
+            TILE_MAP0_ADDRESS
+        };
+
+        // SCX and SCY are read on every fetch, so mid-line writes affect the next tile
+        let (tile_x, tile_y) = if self.fetching_window {
+            (self.tile_x & 0x1F, self.window_line / 8)
+        } else {
+            let scx = mem.read(SCX);
+            let scy = mem.read(SCY);
+            (
+                ((scx / 8).wrapping_add(self.tile_x)) & 0x1F,
+                self.internal_ly.wrapping_add(scy) / 8,
+            )
+        };
+        self.tile_y = tile_y;
+
+        let byte_address = tilemap_address + (tile_y as u16) * 32 + (tile_x as u16);
+        self.tile_index = mem.read(byte_address);
+    }
+
+    //Vertical pixel within the tile (0..7)
+    fn fine_y(&self, mem: &mut Memory) -> usize {
+        if self.fetching_window {
+            (self.window_line % 8) as usize
+        } else {
+            (self.internal_ly.wrapping_add(mem.read(SCY)) % 8) as usize
+        }
+    }
+
+    fn fetch_tile_row(&self, mem: &mut Memory) -> [u8; 8] {
+        // Determine addressing mode, LCDC.4
+        let tile = self.fetch_lcdc_register(mem).bg_tile(self.tile_index);
+        let fine_y = self.fine_y(mem);
+        mem.tile_row(tile, fine_y, false)
+    }
+
+    // The two bitplanes are read on separate steps, so a VRAM write in between only
+    // shows up in the high one
+    fn get_tile_low(&mut self, mem: &mut Memory) {
+        let tile_row = self.fetch_tile_row(mem);
+        for (color, fetched) in self.row.iter_mut().zip(tile_row) {
+            *color = fetched & 1; // low bit of the color
+        }
+    }
+
+    fn get_tile_high(&mut self, mem: &mut Memory) {
+        let tile_row = self.fetch_tile_row(mem);
+        for (color, fetched) in self.row.iter_mut().zip(tile_row) {
+            *color |= fetched & 2; // high bit of the color
+        }
+    }
+
+    fn push_to_fifo(&mut self, fifo: &mut PixelFIFO) {
+        // Push the 8 fetched pixels to FIFO
+        if !fifo.can_push() {
+            return; //Equivalent to sleeping!
+        }
+
+        fifo.push_tile_row(self.row);
+
+        self.tile_x = self.tile_x.wrapping_add(1);
+        self.state = FetcherState::GetTileIndex;
+    }
+
+    // Advances the fetcher by a single dot
+    fn step(&mut self, mem: &mut Memory, fifo: &mut PixelFIFO) {
+        if let PushToFifo = self.state {
+            self.push_to_fifo(fifo);
+            return;
+        }
+
+        // Every other step takes 2 dots, the work is done on the second one
+        self.clock += 1;
+        if self.clock < 2 {
+            return;
+        }
+        self.clock = 0;
+
+        self.state = match self.state {
+            GetTileIndex => {
+                self.get_tile_idx(mem);
+                GetTileLow
+            }
+            GetTileLow => {
+                self.get_tile_low(mem);
+                GetTileHigh
+            }
+            GetTileHigh => {
+                self.get_tile_high(mem);
+                if self.dummy_fetch {
+                    self.dummy_fetch = false;
+                    GetTileIndex
+                } else {
+                    PushToFifo
+                }
+            }
+            PushToFifo => unreachable!(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A crafted state can't put the fetcher in a state it never enters
+    #[test]
+    fn unknown_fetcher_state_is_refused() {
+        let mut state = StateWriter::new();
+        state.u8(0); // Clock
+        state.u8(3);
+        let data = state.finish();
+
+        let mut fetcher = PixelFetcher::new();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(
+            fetcher.load_state(&mut reader),
+            Err(StateError::Invalid("fetcher state"))
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//LCD controlppu
+const LCDC: u16 = 0xFF40;
+const STAT: u16 = 0xFF41; //Scrolling and misc
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LY: u16 = 0xFF44;
+const LYC: u16 = 0xFF45;
+//Palletes
+const BGP: u16 = 0xFF47;
+const OBP0: u16 = 0xFF48;
+const OBP1: u16 = 0xFF49;
+//Window position
+const WY: u16 = 0xFF4A;
+const WX: u16 = 0xFF4B;
+
+//Important Addresses
+const TILE_MAP0_ADDRESS: u16 = 0x9800; // To 0x9BFF
+const TILE_MAP1_ADDRESS: u16 = 0x9C00; // To 0x9BFF
+const OAM: u16 = 0xFE00; // TO 0xFE9F
+
+//Timings, in dots (1 dot = 1 T-cycle)
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SEARCH_DOTS: u16 = 80;
+const PIXEL_TRANSFER_DOTS: u16 = 172;
+const OBJ_FETCH_DOTS: u8 = 6;
+const LINES_PER_FRAME: u8 = 154;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+
+type Viewport = [u8; WIDTH * HEIGHT];
+
+pub mod fifo;
+#[allow(clippy::module_inception)]
+pub mod ppu;
+pub mod scanline;
+pub mod tile_cache;
+
+use crate::bus::Memory;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// ========== Important registers ==========
+
+struct LcdcRegister {
+    ppu_enabled: bool,
+    window_tilemap: bool, // 0->0x9800-0x9BFF 1->0x9C00-0x9FFF
+    window_enabled: bool,
+    bg_window_tiles: bool, // 0->0x8800-0x97FF 1->0x8000-0x8FFF
+    bg_tilemap: bool,      // 0->0x9800-0x9BFF 1->0x9C00-0x9FFF
+    obj_size: bool,
+    obj_enable: bool,
+    bg_enable: bool,
+}
+
+impl LcdcRegister {
+    fn new(register: u8) -> Self {
+        Self {
+            ppu_enabled: register & 0x80 != 0,     // Bit 7
+            window_tilemap: register & 0x40 != 0,  // Bit 6
+            window_enabled: register & 0x20 != 0,  // Bit 5
+            bg_window_tiles: register & 0x10 != 0, // Bit 4
+            bg_tilemap: register & 0x08 != 0,      // Bit 3
+            obj_size: register & 0x04 != 0,        // 0 -> 8 , 1 -> 16
+            obj_enable: register & 0x02 != 0,      // Bit 1
+            bg_enable: register & 0x01 != 0,       // Bit 0
+        }
+    }
+
+    fn obj_height(&self) -> u8 {
+        if self.obj_size { 16 } else { 8 }
+    }
+
+    // Tile number (0..383, from 0x8000) of a background or window tile index
+    fn bg_tile(&self, index: u8) -> u16 {
+        if self.bg_window_tiles {
+            index as u16
+        } else {
+            // Signed indexing, from 0x9000
+            (256 + index as i8 as i16) as u16
+        }
+    }
+}
+
+pub struct StatRegister {
+    lyc_select: bool,
+    mode2: bool,
+    mode1: bool,
+    mode0: bool,
+    ppu_state: u8,
+}
+
+impl StatRegister {
+    pub fn new(register: u8) -> Self {
+        Self {
+            //These Bits allows the CPU, to tell the PPU, when to enable a STAT interrupt!
+            lyc_select: register & 0x40 != 0,
+            mode2: register & 0x20 != 0, // -> Interrupt on OAM Search
+            mode1: register & 0x10 != 0, // -> Interrupt on VBlank
+            mode0: register & 0x08 != 0, // -> Interrupt on HBlank
+            // Bit 2 is LY==LYC, which the PPU works out for itself
+            ppu_state: register & 0x03, // 0: HBlank  1:VBlank 2:OAM 3:Drawing
+        }
+    }
+
+    pub fn get_ppu_state(&self) -> State {
+        match self.ppu_state {
+            0 => State::HBlank,
+            1 => State::VBlank,
+            2 => State::OAMSearch,
+            3 => State::PixelTransfer,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Obj {
+    x: u8,
+    y: u8,
+    tile_index: u8,
+    priority: u8,
+    flipx: bool,
+    flipy: bool,
+    palette: u8,
+}
+
+impl Obj {
+    fn default() -> Obj {
+        Obj {
+            x: 0,
+            y: 0,
+            tile_index: 0,
+            priority: 0,
+            flipx: false,
+            flipy: false,
+            palette: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        for byte in [self.x, self.y, self.tile_index, self.priority, self.palette] {
+            state.u8(byte);
+        }
+        state.bool(self.flipx);
+        state.bool(self.flipy);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Obj, StateError> {
+        Ok(Obj {
+            x: state.u8()?,
+            y: state.u8()?,
+            tile_index: state.u8()?,
+            priority: state.u8()?,
+            palette: state.u8()?,
+            flipx: state.bool()?,
+            flipy: state.bool()?,
+        })
+    }
+
+    // Objects are picked for a line in OAM search, the height can change before they
+    // are drawn: those no longer on the line aren't drawn
+    fn covers(&self, ly: u8, height: u8) -> bool {
+        ly.wrapping_sub(self.y.wrapping_sub(16)) < height
+    }
+
+    // Tile and row (0..7) of the object to draw on line ly, which it has to cover
+    fn tile_row(&self, ly: u8, height: u8) -> (u8, usize) {
+        let sprite_top_y = self.y.wrapping_sub(16);
+        let mut local_y = ly.wrapping_sub(sprite_top_y);
+
+        // === Vertical flip ===
+        if self.flipy {
+            local_y = (height - 1) - local_y;
+        }
+
+        // === Determine which tile and which row inside it ===
+        if height == 16 {
+            // For 8×16: bit 0 of tile index is ignored, we use it to select top/bottom
+            let base = self.tile_index & 0xFE;
+            if local_y >= 8 {
+                (base + 1, (local_y - 8) as usize)
+            } else {
+                (base, local_y as usize)
+            }
+        } else {
+            (self.tile_index, local_y as usize)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum State {
+    OAMSearch = 2,
+    PixelTransfer = 3,
+    HBlank = 0,
+    VBlank = 1,
+}
+
+// ============ Renderers ============
+
+// What the PPU knows about the line about to be drawn
+#[derive(Copy, Clone, Debug)]
+pub struct LineInfo {
+    pub ly: u8,
+    pub window_line: u8,        // Lines of the window drawn so far this frame
+    pub window_triggered: bool, // WY == LY happened this frame
+}
+
+impl LineInfo {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.ly);
+        state.u8(self.window_line);
+        state.bool(self.window_triggered);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<LineInfo, StateError> {
+        Ok(LineInfo {
+            ly: state.u8()?,
+            window_line: state.u8()?,
+            window_triggered: state.bool()?,
+        })
+    }
+}
+
+// Objects of a line, at most 10
+fn save_objs(state: &mut StateWriter, objs: &[Obj]) {
+    state.u8(objs.len() as u8);
+    for obj in objs {
+        obj.save_state(state);
+    }
+}
+
+fn load_objs(state: &mut StateReader, objs: &mut Vec<Obj>) -> Result<(), StateError> {
+    let count = state.u8()?;
+    if count > 10 {
+        return Err(StateError::Invalid("object count"));
+    }
+    objs.clear();
+    for _ in 0..count {
+        objs.push(Obj::load_state(state)?);
+    }
+    Ok(())
+}
+
+// The PPU takes care of the modes, LY, and interrupts, while a renderer produces
+// the pixels of each line during mode 3. Memory is lent by the PPU on every call
+pub trait Renderer: Send {
+    // Called when mode 3 starts, with the objects found during the OAM search
+    fn start_line(&mut self, mem: &mut Memory, line: LineInfo, objs: &[Obj]);
+
+    // Runs a single dot of mode 3, returns true once the line is complete
+    fn pixeltransfer(&mut self, mem: &mut Memory, viewport: &mut Viewport) -> bool;
+
+    // Whether the window was drawn on the last line, advancing its line counter
+    fn window_drawn(&self) -> bool;
+
+    // Renderers are boxed, this lets the whole emulator be cloned
+    fn clone_box(&self) -> Box<dyn Renderer>;
+
+    // Save states keep the renderer's kind, so the right one gets loaded back
+    fn kind(&self) -> RendererKind;
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+impl Clone for Box<dyn Renderer> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RendererKind {
+    // Pixel FIFO, accurate to the dot, mid-line register writes are visible
+    #[default]
+    Fifo,
+    // Draws whole lines at the start of HBlank, faster but ignores mid-line writes
+    Scanline,
+}
+
+impl RendererKind {
+    fn build(self) -> Box<dyn Renderer> {
+        match self {
+            RendererKind::Fifo => Box::new(fifo::FifoRenderer::new()),
+            RendererKind::Scanline => Box::new(scanline::ScanlineRenderer::new()),
+        }
+    }
+}
+
+pub use ppu::PPU;
+pub use tile_cache::TileCache;
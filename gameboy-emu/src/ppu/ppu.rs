@@ -0,0 +1,376 @@
+use super::*;
+use crate::bus::{BusAccess, Memory};
+use crate::interrupts::{self, Interrupt};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// ============ PPU ============
+
+// Owned by the bus, which lends it the memory on every step
+#[derive(Clone)]
+pub struct PPU {
+    framebuffer: Option<Viewport>,
+    viewport: Viewport,
+
+    state: State,
+    renderer: Box<dyn Renderer>,
+    ly: u8,
+
+    clock: u16, // Dot inside the current line (0..456)
+    lcd_off_clock: u32,
+    lcd_enabled: bool,
+
+    line_objs: Vec<Obj>,
+
+    window_triggered: bool, // WY == LY happened this frame
+    window_line: u8,
+    stat_line: bool, // STAT interrupts only fire on a rising edge of this
+
+    synced: u64, // Master clock at the last catch up
+}
+
+use State::*;
+impl PPU {
+    pub fn new(mem: &mut Memory, renderer: RendererKind) -> Self {
+        let framebuffer = None;
+        let viewport = [0xFF; WIDTH * HEIGHT];
+        let state = OAMSearch;
+        let renderer = renderer.build();
+
+        let ppu = Self {
+            framebuffer,
+            viewport,
+            state,
+            renderer,
+            ly: 0,
+            clock: 0,
+            lcd_off_clock: 0,
+            lcd_enabled: true,
+            line_objs: Vec::with_capacity(10),
+            window_triggered: false,
+            window_line: 0,
+            stat_line: false,
+            synced: 0,
+        };
+
+        //The boot ROM leaves the LCD on, with the usual palette
+        mem.write(LCDC, 0x91);
+        mem.write(BGP, 0xFC);
+        ppu
+    }
+
+    fn fetch_lcdc_register(&self, mem: &mut Memory) -> LcdcRegister {
+        LcdcRegister::new(mem.read(LCDC))
+    }
+
+    // ============ Objects & OAMSearch ============
+
+    fn fetch_object(&self, mem: &mut Memory, address: u16) -> Obj {
+        //Each object is 4 bytes long
+        let y = mem.read(address); // + 16;
+        let x = mem.read(address + 1); // + 8;
+        let tile_index = mem.read(address + 2);
+        let flags = mem.read(address + 3);
+
+        Obj {
+            x,
+            y,
+            tile_index,
+            priority: flags & 0x80,
+            flipy: flags & 0x40 != 0,
+            flipx: flags & 0x20 != 0,
+            palette: (flags & 0x10) >> 4,
+        }
+    }
+
+    fn fetch_objects_from_oam(&self, mem: &mut Memory) -> [Obj; 40] {
+        let mut objects = [Obj::default(); 40];
+
+        for (i, object) in objects.iter_mut().enumerate() {
+            *object = self.fetch_object(mem, OAM + (i as u16) * 4);
+        }
+
+        objects
+    }
+
+    fn oamsearch(&mut self, mem: &mut Memory) {
+        let sprite_height = self.fetch_lcdc_register(mem).obj_height() as u16;
+
+        let oam_data = self.fetch_objects_from_oam(mem);
+        // Object Y is stored with a +16 offset, compare in u16 to avoid wrapping
+        let line = self.ly as u16 + 16;
+
+        self.line_objs.clear();
+        for object_data in oam_data {
+            let sprite_top = object_data.y as u16;
+            if line >= sprite_top && line < sprite_top + sprite_height {
+                self.line_objs.push(object_data);
+                if self.line_objs.len() == 10 {
+                    break;
+                }
+            }
+        }
+        //Stable sort, objects on the same X keep their OAM order
+        self.line_objs.sort_by_key(|obj| obj.x);
+    }
+
+    // ============ Changing States ===========
+
+    fn set_state(&mut self, mem: &mut Memory, state: State) {
+        self.state = state.clone();
+
+        let mut stat = mem.read(STAT);
+        stat = (stat & !0b11) | (state as u8 & 0b11); // update mode bits only
+        mem.write(STAT, stat);
+    }
+
+    fn change_to_state(&mut self, mem: &mut Memory, state: State) {
+        self.set_state(mem, state.clone());
+
+        match state {
+            HBlank => {}
+            VBlank => {
+                interrupts::request(mem, Interrupt::VBlank);
+            }
+            PixelTransfer => {
+                let line = LineInfo {
+                    ly: self.ly,
+                    window_line: self.window_line,
+                    window_triggered: self.window_triggered,
+                };
+                self.renderer.start_line(mem, line, &self.line_objs);
+            }
+            OAMSearch => {
+                if self.ly == mem.read(WY) {
+                    self.window_triggered = true;
+                }
+                self.oamsearch(mem);
+            }
+        }
+    }
+
+    // The STAT interrupt is requested when any of its enabled sources goes high
+    fn update_stat(&mut self, mem: &mut Memory) {
+        let stat_value = mem.read(STAT);
+        let stat = StatRegister::new(stat_value);
+        let lyc_eq_ly = mem.read(LYC) == self.ly;
+
+        let new_stat = if lyc_eq_ly {
+            stat_value | 0x04
+        } else {
+            stat_value & !0x04
+        };
+        if new_stat != stat_value {
+            mem.write(STAT, new_stat);
+        }
+
+        let line = match self.state {
+            HBlank => stat.mode0,
+            VBlank => stat.mode1,
+            OAMSearch => stat.mode2,
+            _ => false,
+        } || (stat.lyc_select && lyc_eq_ly);
+
+        if line && !self.stat_line {
+            interrupts::request(mem, Interrupt::LCDStat);
+        }
+        self.stat_line = line;
+    }
+
+    fn increment_ly(&mut self, mem: &mut Memory) {
+        if matches!(self.state, HBlank) && self.renderer.window_drawn() {
+            self.window_line += 1;
+        }
+
+        self.ly = if self.ly + 1 >= LINES_PER_FRAME {
+            0
+        } else {
+            self.ly + 1
+        };
+        mem.write(LY, self.ly);
+
+        // Frame ready exactly when LY wraps from 153 → 0
+        if self.ly == 0 {
+            self.framebuffer = Some(self.viewport);
+            self.window_line = 0;
+            self.window_triggered = false;
+        }
+    }
+
+    fn disable_lcd(&mut self, mem: &mut Memory) {
+        self.lcd_enabled = false;
+        self.lcd_off_clock = 0;
+        self.clock = 0;
+        self.ly = 0;
+        mem.write(LY, 0);
+        self.set_state(mem, HBlank);
+        self.stat_line = false;
+        self.viewport = [0; WIDTH * HEIGHT];
+    }
+
+    fn enable_lcd(&mut self, mem: &mut Memory) {
+        self.lcd_enabled = true;
+        self.clock = 0;
+        self.window_line = 0;
+        self.window_triggered = false;
+        self.change_to_state(mem, OAMSearch);
+    }
+
+    pub fn is_frame_ready(&self) -> bool {
+        self.framebuffer.is_some()
+    }
+
+    pub fn clear_buffer(&mut self) {
+        self.framebuffer = None;
+    }
+
+    pub fn yield_frame(&self) -> [u8; 23040] {
+        self.framebuffer.unwrap()
+    }
+
+    // =========== Running the PPU ==============
+
+    // Advances the PPU by a single dot
+    fn tick(&mut self, mem: &mut Memory) {
+        let lcdc = self.fetch_lcdc_register(mem);
+        if !lcdc.ppu_enabled {
+            if self.lcd_enabled {
+                self.disable_lcd(mem);
+            }
+            // The screen stays blank, but frames are still handed out at the usual rate
+            self.lcd_off_clock += 1;
+            if self.lcd_off_clock == DOTS_PER_FRAME {
+                self.lcd_off_clock = 0;
+                self.framebuffer = Some(self.viewport);
+            }
+            return;
+        }
+        if !self.lcd_enabled {
+            self.enable_lcd(mem);
+        }
+
+        match self.state {
+            OAMSearch => {
+                if self.clock == OAM_SEARCH_DOTS - 1 {
+                    self.change_to_state(mem, PixelTransfer);
+                }
+            }
+            PixelTransfer => {
+                if self.renderer.pixeltransfer(mem, &mut self.viewport) {
+                    self.change_to_state(mem, HBlank);
+                }
+            }
+            HBlank | VBlank => {}
+        }
+
+        self.clock += 1;
+        if self.clock == DOTS_PER_LINE {
+            self.clock = 0;
+            self.increment_ly(mem);
+            let next_state = match self.ly {
+                0..=143 => OAMSearch,
+                144 => VBlank,
+                _ => self.state.clone(),
+            };
+            if !matches!((&next_state, &self.state), (VBlank, VBlank)) {
+                self.change_to_state(mem, next_state);
+            }
+        }
+
+        self.update_stat(mem);
+    }
+
+    pub fn step(&mut self, mem: &mut Memory, cycles: u32) {
+        for _ in 0..cycles {
+            self.tick(mem);
+        }
+    }
+
+    // Runs the PPU up to the master clock
+    pub fn sync(&mut self, mem: &mut Memory, now: u64) {
+        self.step(mem, (now - self.synced) as u32);
+        self.synced = now;
+    }
+
+    // Dots until the next point where the PPU may request an interrupt or hand out a
+    // frame, it has to be caught up by then
+    pub fn next_event(&self) -> u32 {
+        if !self.lcd_enabled {
+            return DOTS_PER_FRAME - self.lcd_off_clock;
+        }
+        let dots = match self.state {
+            OAMSearch => OAM_SEARCH_DOTS - self.clock,
+            // Mode 3 ends at some point after its minimum length, we then go dot by dot
+            PixelTransfer => (OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS)
+                .saturating_sub(self.clock)
+                .max(1),
+            HBlank | VBlank => DOTS_PER_LINE - self.clock,
+        };
+        dots as u32
+    }
+    // ============ Save States ============
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.framebuffer.is_some());
+        if let Some(framebuffer) = &self.framebuffer {
+            state.bytes(framebuffer);
+        }
+        state.bytes(&self.viewport);
+        state.u8(self.state.clone() as u8);
+        state.u8(self.ly);
+        state.u16(self.clock);
+        state.u32(self.lcd_off_clock);
+        state.bool(self.lcd_enabled);
+        save_objs(state, &self.line_objs);
+        state.bool(self.window_triggered);
+        state.u8(self.window_line);
+        state.bool(self.stat_line);
+        state.u64(self.synced);
+
+        state.u8(self.renderer.kind() as u8);
+        self.renderer.save_state(state);
+    }
+
+    // A state saved with the other renderer switches to it
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.framebuffer = if state.bool()? {
+            let mut framebuffer = [0; WIDTH * HEIGHT];
+            state.bytes(&mut framebuffer)?;
+            Some(framebuffer)
+        } else {
+            None
+        };
+        state.bytes(&mut self.viewport)?;
+        self.state = match state.u8()? {
+            0 => HBlank,
+            1 => VBlank,
+            2 => OAMSearch,
+            3 => PixelTransfer,
+            _ => return Err(StateError::Invalid("PPU state")),
+        };
+        self.ly = state.u8()?;
+        self.clock = state.u16()?;
+        self.lcd_off_clock = state.u32()?;
+        self.lcd_enabled = state.bool()?;
+        load_objs(state, &mut self.line_objs)?;
+        self.window_triggered = state.bool()?;
+        self.window_line = state.u8()?;
+        self.stat_line = state.bool()?;
+        self.synced = state.u64()?;
+        if self.ly >= LINES_PER_FRAME
+            || self.clock >= DOTS_PER_LINE
+            || self.lcd_off_clock >= DOTS_PER_FRAME
+        {
+            return Err(StateError::Invalid("PPU position"));
+        }
+
+        let kind = match state.u8()? {
+            0 => RendererKind::Fifo,
+            1 => RendererKind::Scanline,
+            _ => return Err(StateError::Invalid("renderer")),
+        };
+        if kind != self.renderer.kind() {
+            self.renderer = kind.build();
+        }
+        self.renderer.load_state(state)
+    }
+}
@@ -0,0 +1,254 @@
+use super::*;
+use crate::bus::{BusAccess, Memory};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// ============ Scanline Renderer ============
+
+// Draws a whole line at once, at the start of HBlank, using the registers as they are
+// at that moment. Mode 3 still lasts about as long as it would on hardware, so games
+// timing themselves on STAT keep working.
+#[derive(Clone)]
+pub struct ScanlineRenderer {
+    line: LineInfo,
+    line_objs: Vec<Obj>,
+
+    clock: u16,
+    duration: u16,
+    window_drawn: bool,
+}
+
+impl ScanlineRenderer {
+    pub fn new() -> Self {
+        Self {
+            line: LineInfo {
+                ly: 0,
+                window_line: 0,
+                window_triggered: false,
+            },
+            line_objs: Vec::with_capacity(10),
+            clock: 0,
+            duration: PIXEL_TRANSFER_DOTS,
+            window_drawn: false,
+        }
+    }
+
+    fn window_x(&self, mem: &mut Memory, lcdc: &LcdcRegister) -> Option<u8> {
+        let wx = mem.read(WX);
+        if lcdc.window_enabled && self.line.window_triggered && wx < 167 {
+            Some(wx)
+        } else {
+            None
+        }
+    }
+
+    // Estimates the length of mode 3 the same way the pixel FIFO would stall
+    fn pixeltransfer_duration(&self, mem: &mut Memory) -> u16 {
+        let lcdc = LcdcRegister::new(mem.read(LCDC));
+        let scx = mem.read(SCX);
+        let mut duration = PIXEL_TRANSFER_DOTS + (scx % 8) as u16;
+
+        if self.window_x(mem, &lcdc).is_some() {
+            duration += OBJ_FETCH_DOTS as u16;
+        }
+
+        if lcdc.obj_enable {
+            for obj in self.line_objs.iter().filter(|obj| obj.x < 168) {
+                let alignment = obj.x.wrapping_add(scx) % 8;
+                duration += OBJ_FETCH_DOTS as u16 + 5 - alignment.min(5) as u16;
+            }
+        }
+        duration
+    }
+
+    // Color indices of the background and window, before the palette is applied
+    fn draw_background(&mut self, mem: &mut Memory, lcdc: &LcdcRegister) -> [u8; WIDTH] {
+        let mut line = [0; WIDTH];
+        let scx = mem.read(SCX);
+        let scy = mem.read(SCY);
+        let window_x = self.window_x(mem, lcdc);
+
+        let bg_tilemap = if lcdc.bg_tilemap {
+            TILE_MAP1_ADDRESS
+        } else {
+            TILE_MAP0_ADDRESS
+        };
+        let window_tilemap = if lcdc.window_tilemap {
+            TILE_MAP1_ADDRESS
+        } else {
+            TILE_MAP0_ADDRESS
+        };
+
+        let mut cached_tile: Option<(u16, [u8; 8])> = None;
+        for (x, color) in line.iter_mut().enumerate() {
+            // WX < 7 starts the window before the first pixel
+            let in_window = window_x.is_some_and(|wx| x + 7 >= wx as usize);
+            let (tilemap, map_x, map_y) = if in_window {
+                self.window_drawn = true;
+                let wx = window_x.unwrap() as usize;
+                (window_tilemap, (x + 7 - wx) as u8, self.line.window_line)
+            } else {
+                (
+                    bg_tilemap,
+                    (x as u8).wrapping_add(scx),
+                    self.line.ly.wrapping_add(scy),
+                )
+            };
+
+            let map_address = tilemap + (map_y as u16 / 8) * 32 + (map_x as u16 / 8);
+            let row = match cached_tile {
+                Some((address, row)) if address == map_address => row,
+                _ => {
+                    let tile = lcdc.bg_tile(mem.read(map_address));
+                    let row = mem.tile_row(tile, map_y as usize % 8, false);
+                    cached_tile = Some((map_address, row));
+                    row
+                }
+            };
+            *color = row[map_x as usize % 8];
+        }
+        line
+    }
+
+    fn draw_line(&mut self, mem: &mut Memory, viewport: &mut Viewport) {
+        let lcdc = LcdcRegister::new(mem.read(LCDC));
+        let background = self.draw_background(mem, &lcdc);
+        let bgp = mem.read(BGP);
+        let obp = [mem.read(OBP0), mem.read(OBP1)];
+        let height = lcdc.obj_height();
+
+        // Objects are sorted by X then OAM order, the first opaque pixel wins,
+        // the same way the object FIFO is filled
+        let mut objects: [Option<(u8, &Obj)>; WIDTH] = [None; WIDTH];
+        if lcdc.obj_enable {
+            for obj in self
+                .line_objs
+                .iter()
+                .filter(|obj| obj.covers(self.line.ly, height))
+            {
+                let (tile_index, row_in_tile) = obj.tile_row(self.line.ly, height);
+                let row = mem.tile_row(tile_index as u16, row_in_tile, obj.flipx);
+
+                for (i, &color) in row.iter().enumerate() {
+                    let x = obj.x as usize + i;
+                    if x < 8 || x - 8 >= WIDTH || color == 0 {
+                        continue;
+                    }
+                    objects[x - 8].get_or_insert((color, obj));
+                }
+            }
+        }
+
+        let start = self.line.ly as usize * WIDTH;
+        for (x, pixel) in viewport[start..start + WIDTH].iter_mut().enumerate() {
+            // On DMG, a disabled background is drawn white, and never hides objects
+            let bg_color = if lcdc.bg_enable { background[x] } else { 0 };
+
+            *pixel = match objects[x] {
+                Some((color, obj)) if obj.priority == 0 || bg_color == 0 => {
+                    (obp[obj.palette as usize] >> (color * 2)) & 3
+                }
+                _ if lcdc.bg_enable => (bgp >> (bg_color * 2)) & 3,
+                _ => 0,
+            };
+        }
+    }
+}
+
+impl Renderer for ScanlineRenderer {
+    fn start_line(&mut self, mem: &mut Memory, line: LineInfo, objs: &[Obj]) {
+        self.line = line;
+        self.line_objs.clear();
+        self.line_objs.extend_from_slice(objs);
+        self.window_drawn = false;
+        self.clock = 0;
+        self.duration = self.pixeltransfer_duration(mem);
+    }
+
+    fn pixeltransfer(&mut self, mem: &mut Memory, viewport: &mut Viewport) -> bool {
+        self.clock += 1;
+        if self.clock < self.duration {
+            return false;
+        }
+        self.draw_line(mem, viewport);
+        true
+    }
+
+    fn window_drawn(&self) -> bool {
+        self.window_drawn
+    }
+
+    fn clone_box(&self) -> Box<dyn Renderer> {
+        Box::new(self.clone())
+    }
+
+    fn kind(&self) -> RendererKind {
+        RendererKind::Scanline
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.line.save_state(state);
+        save_objs(state, &self.line_objs);
+        state.u16(self.clock);
+        state.u16(self.duration);
+        state.bool(self.window_drawn);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.line = LineInfo::load_state(state)?;
+        load_objs(state, &mut self.line_objs)?;
+        self.clock = state.u16()?;
+        self.duration = state.u16()?;
+        self.window_drawn = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws line ly with the objects OAM search picked for it
+    fn draw(mem: &mut Memory, ly: u8, objs: &[Obj]) -> [u8; WIDTH] {
+        let mut renderer = ScanlineRenderer::new();
+        let line = LineInfo {
+            ly,
+            window_line: 0,
+            window_triggered: false,
+        };
+        renderer.start_line(mem, line, objs);
+        let mut viewport = [0; WIDTH * HEIGHT];
+        while !renderer.pixeltransfer(mem, &mut viewport) {}
+        viewport[ly as usize * WIDTH..(ly as usize + 1) * WIDTH]
+            .try_into()
+            .unwrap()
+    }
+
+    // Objects picked as 8x16 on line 12, then LCDC switches to 8x8 objects before the
+    // line is drawn: they no longer cover it
+    #[test]
+    fn object_height_switched_after_oam_search() {
+        let mut mem = Memory::new(vec![0; 0x8000]);
+        mem.write(LCDC, 0x82); // LCD and objects on, 8x8 objects, no background
+        mem.write(OBP0, 0xE4);
+        for address in 0x8010..0x8020 {
+            mem.write(address, 0xFF); // Tile 1 is color 3 all over
+        }
+        let obj = Obj {
+            x: 8,
+            y: 16, // Top on line 0
+            tile_index: 1,
+            ..Obj::default()
+        };
+        let flipped = Obj {
+            x: 16,
+            flipy: true,
+            ..obj
+        };
+
+        let line = draw(&mut mem, 12, &[obj, flipped]);
+        assert!(line.iter().all(|&color| color == 0));
+        // Still drawn on the lines they cover
+        let line = draw(&mut mem, 4, &[obj, flipped]);
+        assert_eq!(line[..16], [3; 16]);
+    }
+}
@@ -0,0 +1,129 @@
+// ============ Tile Cache ============
+
+// Tiles 0..383 live in 0x8000-0x97FF, 16 bytes each
+pub const TILE_COUNT: usize = 384;
+const TILE_DATA_START: u16 = 0x8000;
+const TILE_DATA_END: u16 = 0x97FF;
+
+// A tile decoded from 2bpp to color indices (0..3), rows left to right,
+// along with its horizontally flipped version for objects
+#[derive(Copy, Clone)]
+struct DecodedTile {
+    rows: [[u8; 8]; 8],
+    flipped_rows: [[u8; 8]; 8],
+}
+
+impl DecodedTile {
+    fn decode(bytes: &[u8]) -> Self {
+        let mut rows = [[0; 8]; 8];
+        for (y, row) in rows.iter_mut().enumerate() {
+            let low = bytes[y * 2];
+            let high = bytes[y * 2 + 1];
+            for (x, color) in row.iter_mut().enumerate() {
+                let bit = 7 - x;
+                *color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            }
+        }
+
+        let mut flipped_rows = rows;
+        for row in flipped_rows.iter_mut() {
+            row.reverse();
+        }
+
+        Self { rows, flipped_rows }
+    }
+}
+
+// Tiles are decoded lazily, the first time they are used after a VRAM write
+#[derive(Clone)]
+pub struct TileCache {
+    tiles: Box<[DecodedTile; TILE_COUNT]>,
+    dirty: [bool; TILE_COUNT],
+}
+
+impl TileCache {
+    pub fn new() -> Self {
+        let empty = DecodedTile {
+            rows: [[0; 8]; 8],
+            flipped_rows: [[0; 8]; 8],
+        };
+        Self {
+            tiles: Box::new([empty; TILE_COUNT]),
+            dirty: [true; TILE_COUNT],
+        }
+    }
+
+    pub fn invalidate(&mut self, address: u16) {
+        if (TILE_DATA_START..=TILE_DATA_END).contains(&address) {
+            self.dirty[((address - TILE_DATA_START) / 16) as usize] = true;
+        }
+    }
+
+    // tile_data is the whole tile data area, starting at 0x8000
+    pub fn row(&mut self, tile_data: &[u8], tile: u16, row: usize, flipx: bool) -> [u8; 8] {
+        let tile = tile as usize;
+        if self.dirty[tile] {
+            self.tiles[tile] = DecodedTile::decode(&tile_data[tile * 16..tile * 16 + 16]);
+            self.dirty[tile] = false;
+        }
+
+        if flipx {
+            self.tiles[tile].flipped_rows[row]
+        } else {
+            self.tiles[tile].rows[row]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tile 0 is a gradient, colors 0 to 3 from left to right, tile 1 is all color 3
+    fn tile_data() -> Vec<u8> {
+        let mut data = vec![0; TILE_COUNT * 16];
+        for row in 0..8 {
+            data[row * 2] = 0b0011_0011;
+            data[row * 2 + 1] = 0b0000_1111;
+        }
+        data[16..32].fill(0xFF);
+        data
+    }
+
+    #[test]
+    fn decodes_rows_and_their_flip() {
+        let mut cache = TileCache::new();
+        let data = tile_data();
+        assert_eq!(cache.row(&data, 0, 0, false), [0, 0, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(cache.row(&data, 0, 7, true), [3, 3, 2, 2, 1, 1, 0, 0]);
+        assert_eq!(cache.row(&data, 1, 3, false), [3; 8]);
+    }
+
+    // Both tiles change under the cache but only the first one is invalidated, the
+    // second one keeps what was decoded before
+    #[test]
+    fn write_decodes_only_its_tile_again() {
+        let mut cache = TileCache::new();
+        let mut data = tile_data();
+        cache.row(&data, 0, 0, false);
+        cache.row(&data, 1, 0, false);
+
+        data[..32].fill(0);
+        cache.invalidate(0x8000 + 5);
+        assert!(cache.dirty[0] && !cache.dirty[1]);
+        assert_eq!(cache.row(&data, 0, 0, false), [0; 8]);
+        assert_eq!(cache.row(&data, 1, 0, false), [3; 8]);
+        assert!(!cache.dirty[0]);
+    }
+
+    #[test]
+    fn writes_outside_tile_data_are_ignored() {
+        let mut cache = TileCache::new();
+        let data = tile_data();
+        cache.row(&data, 0, 0, false);
+        cache.row(&data, 383, 0, false);
+        cache.invalidate(0x7FFF);
+        cache.invalidate(0x9800);
+        assert!(!cache.dirty[0] && !cache.dirty[383]);
+    }
+}
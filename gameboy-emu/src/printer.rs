@@ -0,0 +1,393 @@
+use crate::serial::SerialDevice;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Commands, after the 0x88 0x33 magic bytes
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const PRINTING: u8 = 0x02;
+const UNPROCESSED_DATA: u8 = 0x08;
+const OTHER_ERROR: u8 = 0x40;
+
+// Answered to the first byte after the checksum, the printer is there
+const ALIVE: u8 = 0x81;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+// The printer's memory holds 9 DATA packets, 2 rows of tiles each
+const BUFFER_SIZE: usize = 9 * 0x280;
+// Pixel lines fed for each unit of margin
+const MARGIN_LINES: usize = 16;
+// STATUS requests answered as busy after a PRINT, games wait for it to finish
+const PRINTING_POLLS: u8 = 4;
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// ============ Game Boy Printer ============
+
+// Where the printer is in a packet: magic, command, compression, length, data,
+// checksum, then the 2 bytes answered with ALIVE and the status
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+#[derive(Clone)]
+struct Packet {
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+}
+
+// The printer is always the slave, the game sends packets on its internal clock and
+// reads the answers. Each PRINT writes the image as a PNG in the given directory
+#[derive(Clone)]
+pub struct Printer {
+    directory: PathBuf,
+    printed: usize, // For naming the files
+
+    phase: Phase,
+    packet: Packet,
+    buffer: Vec<u8>, // Tile data received since the last print
+    status: u8,
+    printing: u8, // STATUS requests left before the print is over
+}
+
+impl Printer {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            printed: 0,
+            phase: Phase::Magic1,
+            packet: Packet {
+                command: 0,
+                compressed: false,
+                length: 0,
+                data: Vec::new(),
+                checksum: 0,
+            },
+            buffer: Vec::new(),
+            status: 0,
+            printing: 0,
+        }
+    }
+
+    // Feeds a byte to the packet, returns the byte answered to it
+    fn receive(&mut self, byte: u8) -> u8 {
+        use Phase::*;
+        let mut answer = 0x00;
+        self.phase = match self.phase {
+            Magic1 if byte == 0x88 => Magic2,
+            Magic1 => Magic1,
+            Magic2 if byte == 0x33 => Command,
+            Magic2 => Magic1,
+            Command => {
+                self.packet.command = byte;
+                Compression
+            }
+            Compression => {
+                self.packet.compressed = byte & 0x01 != 0;
+                LengthLow
+            }
+            LengthLow => {
+                self.packet.length = byte as u16;
+                LengthHigh
+            }
+            LengthHigh => {
+                self.packet.length |= (byte as u16) << 8;
+                self.packet.data.clear();
+                if self.packet.length == 0 {
+                    ChecksumLow
+                } else {
+                    Data
+                }
+            }
+            Data => {
+                self.packet.data.push(byte);
+                if self.packet.data.len() == self.packet.length as usize {
+                    ChecksumLow
+                } else {
+                    Data
+                }
+            }
+            ChecksumLow => {
+                self.packet.checksum = byte as u16;
+                ChecksumHigh
+            }
+            ChecksumHigh => {
+                self.packet.checksum |= (byte as u16) << 8;
+                self.execute();
+                Alive
+            }
+            Alive => {
+                answer = ALIVE;
+                Status
+            }
+            Status => {
+                answer = self.status;
+                Magic1
+            }
+        };
+        answer
+    }
+
+    // The packet is complete, the status answered at its end reflects it
+    fn execute(&mut self) {
+        let packet = &self.packet;
+        let [low, high] = packet.length.to_le_bytes();
+        let sum = [packet.command, packet.compressed as u8, low, high]
+            .iter()
+            .chain(&packet.data)
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        if sum != packet.checksum {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+
+        match packet.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.printing = 0;
+            }
+            DATA => {
+                let data = if packet.compressed {
+                    decompress(&packet.data)
+                } else {
+                    packet.data.clone()
+                };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(room));
+            }
+            PRINT if packet.data.len() >= 4 => {
+                let (margins, palette) = (packet.data[1], packet.data[2]);
+                match self.print(margins, palette) {
+                    Ok(()) => self.status &= !OTHER_ERROR,
+                    Err(error) => {
+                        eprintln!("Printer: couldn't save the image: {error}");
+                        self.status |= OTHER_ERROR;
+                    }
+                }
+                self.buffer.clear();
+                self.printing = PRINTING_POLLS;
+            }
+            STATUS => {
+                // Printing takes a while, games wait for the busy bit to go away
+                self.printing = self.printing.saturating_sub(1);
+            }
+            _ => {}
+        }
+
+        if self.printing > 0 {
+            self.status |= PRINTING;
+        } else {
+            self.status &= !PRINTING;
+        }
+        if self.buffer.is_empty() {
+            self.status &= !UNPROCESSED_DATA;
+        } else {
+            self.status |= UNPROCESSED_DATA;
+        }
+    }
+
+    // The buffered tiles, 20 to a row, with the margins above and below in white.
+    // An incomplete last row is padded with zeros, nothing is printed without tiles
+    fn print(&mut self, margins: u8, palette: u8) -> io::Result<()> {
+        let rows = self.buffer.len().div_ceil(16 * TILES_PER_ROW);
+        if rows == 0 {
+            return Ok(());
+        }
+        let mut tiles = self.buffer.clone();
+        tiles.resize(rows * 16 * TILES_PER_ROW, 0);
+
+        // 0 is taken as the usual palette by the printer
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let (top, bottom) = ((margins >> 4) as usize, (margins & 0x0F) as usize);
+        let height = (top + bottom) * MARGIN_LINES + rows * 8;
+
+        let mut pixels = vec![SHADES[0]; WIDTH * height];
+        for (tile_index, tile) in tiles.chunks_exact(16).enumerate() {
+            let (row, column) = (tile_index / TILES_PER_ROW, tile_index % TILES_PER_ROW);
+            for (line, bytes) in tile.chunks_exact(2).enumerate() {
+                let y = top * MARGIN_LINES + row * 8 + line;
+                for bit in 0..8 {
+                    let color =
+                        ((bytes[0] >> (7 - bit)) & 1) | (((bytes[1] >> (7 - bit)) & 1) << 1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    pixels[y * WIDTH + column * 8 + bit] = SHADES[shade as usize];
+                }
+            }
+        }
+
+        fs::create_dir_all(&self.directory)?;
+        self.printed += 1;
+        let path = self
+            .directory
+            .join(format!("print_{:04}.png", self.printed));
+        fs::write(path, encode_png(WIDTH, height, &pixels))
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8, _done_at: u64) -> u8 {
+        self.receive(byte)
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+// Runs start with a byte: bit 7 set for a repeated byte (low bits + 2 times),
+// otherwise it is followed by low bits + 1 bytes to copy
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 != 0 {
+            if let Some(&byte) = bytes.next() {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            }
+        } else {
+            out.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    out
+}
+
+// ============ PNG ============
+
+// 8-bit grayscale, with the image data stored uncompressed in the zlib stream, which
+// is plenty for printer strips
+fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks_exact(width) {
+        raw.push(0); // No filter
+        raw.extend_from_slice(row);
+    }
+
+    // zlib header, then deflate blocks of at most 65535 bytes, at least one as the last
+    // block has to be there
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+    if blocks.is_empty() {
+        blocks.push(&[]);
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        let length = block.len() as u16;
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 0, 0, 0, 0]); // 8 bits, grayscale, no interlacing
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for each test, removed at the end
+    fn printer(name: &str) -> Printer {
+        let directory =
+            std::env::temp_dir().join(format!("rustboi_printer_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        Printer::new(directory)
+    }
+
+    fn png_size(png: &[u8]) -> (u32, u32) {
+        let word = |i: usize| u32::from_be_bytes(png[i..i + 4].try_into().unwrap());
+        (word(16), word(20))
+    }
+
+    // 100 bytes is 6 tiles and a bit, a single row padded with zeros
+    #[test]
+    fn incomplete_row_is_padded() {
+        let mut printer = printer("incomplete");
+        printer.buffer = vec![0xFF; 100];
+        printer.print(0x00, 0xE4).unwrap();
+
+        let png = fs::read(printer.directory.join("print_0001.png")).unwrap();
+        assert_eq!(png_size(&png), (WIDTH as u32, 8));
+        fs::remove_dir_all(&printer.directory).unwrap();
+    }
+
+    #[test]
+    fn nothing_printed_without_tiles() {
+        let mut printer = printer("empty");
+        printer.print(0x00, 0xE4).unwrap();
+        printer.print(0x13, 0xE4).unwrap();
+        assert!(!printer.directory.exists());
+    }
+
+    // The last deflate block is there even without image data
+    #[test]
+    fn empty_png_has_a_final_block() {
+        let png = encode_png(WIDTH, 0, &[]);
+        let idat = png.windows(4).position(|kind| kind == b"IDAT").unwrap();
+        assert_eq!(
+            png[idat + 4..idat + 11],
+            [0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+
+// Every this many snapshots, one is stored whole instead of as a delta
+const KEYFRAME_INTERVAL: usize = 60;
+
+// ============ Rewind ============
+
+#[derive(Clone)]
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>, // Compressed, see compress
+}
+
+// Save states taken every few frames, most of them stored as the difference with the
+// last keyframe, which is mostly zeros and compresses well. Once over budget, the
+// oldest keyframe goes along with the deltas that need it
+#[derive(Clone)]
+pub struct Rewind {
+    interval: u64, // Frames between snapshots
+    budget: usize, // Bytes
+    used: usize,
+    snapshots: VecDeque<Snapshot>,
+    keyframe: Vec<u8>,     // The last keyframe, uncompressed, for the next deltas
+    since_keyframe: usize, // Snapshots taken since it
+}
+
+impl Rewind {
+    pub fn new(interval: u64, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            budget,
+            used: 0,
+            snapshots: VecDeque::new(),
+            keyframe: Vec::new(),
+            since_keyframe: 0,
+        }
+    }
+
+    pub fn wants_snapshot(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.interval)
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        let keyframe = self.snapshots.is_empty() || self.since_keyframe + 1 >= KEYFRAME_INTERVAL;
+        let data = if keyframe {
+            let data = compress(&state, &[]);
+            self.keyframe = state;
+            self.since_keyframe = 0;
+            data
+        } else {
+            self.since_keyframe += 1;
+            compress(&state, &self.keyframe)
+        };
+
+        self.used += data.len();
+        self.snapshots.push_back(Snapshot {
+            frame,
+            keyframe,
+            data,
+        });
+        while self.used > self.budget && self.snapshots.len() > 1 {
+            self.drop_oldest_group();
+        }
+    }
+
+    fn drop_oldest_group(&mut self) {
+        while let Some(snapshot) = self.snapshots.pop_front() {
+            self.used -= snapshot.data.len();
+            if self.snapshots.front().is_none_or(|next| next.keyframe) {
+                break;
+            }
+        }
+        if self.snapshots.is_empty() {
+            self.keyframe.clear();
+        }
+    }
+
+    // The newest snapshot taken at or before the frame, the ones after it are dropped.
+    // Returns the frame it was taken at and the state
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.frame <= frame)?;
+        self.truncate(self.snapshots[index].frame);
+
+        let snapshot = self.snapshots.back().unwrap();
+        let state = if snapshot.keyframe {
+            self.keyframe.clone()
+        } else {
+            decompress(&snapshot.data, &self.keyframe)
+        };
+        Some((snapshot.frame, state))
+    }
+
+    // Drops the snapshots taken after the frame
+    pub fn truncate(&mut self, frame: u64) {
+        let kept = self
+            .snapshots
+            .partition_point(|snapshot| snapshot.frame <= frame);
+        if kept == self.snapshots.len() {
+            return;
+        }
+        for snapshot in self.snapshots.drain(kept..) {
+            self.used -= snapshot.data.len();
+        }
+
+        match self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.keyframe)
+        {
+            Some(key) => {
+                self.keyframe = decompress(&self.snapshots[key].data, &[]);
+                self.since_keyframe = kept - 1 - key;
+            }
+            None => self.clear(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe.clear();
+        self.used = 0;
+        self.since_keyframe = 0;
+    }
+}
+
+// The state XORed with the base (nothing for keyframes), then as pairs of runs: a
+// number of zeros and a number of literal bytes, both as varints, then the literals
+fn compress(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let delta: Vec<u8> = state
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| byte ^ base.get(i).copied().unwrap_or(0))
+        .collect();
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < delta.len() {
+        let zeros = delta[i..].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+        let start = i;
+        // A couple of zeros in the middle of literals aren't worth a new pair
+        while i < delta.len() && delta[i..].iter().take(3).any(|&byte| byte != 0) {
+            i += 1;
+        }
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, i - start);
+        out.extend_from_slice(&delta[start..i]);
+    }
+    out
+}
+
+fn decompress(data: &[u8], base: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = read_varint(data, &mut i);
+        let literals = read_varint(data, &mut i);
+        delta.resize(delta.len() + zeros, 0);
+        delta.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+
+    for (i, byte) in delta.iter_mut().enumerate() {
+        *byte ^= base.get(i).copied().unwrap_or(0);
+    }
+    delta
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
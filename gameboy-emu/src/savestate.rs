@@ -0,0 +1,171 @@
+use std::fmt;
+
+// ============ Save States ============
+
+// A state is little endian binary: the magic, the format version, then every component
+// in a fixed order (CPU, memory, scheduler, PPU, timer, serial, input), and the frame
+// and lag counters. Whatever is outside the Game Boy, like the device in the serial
+// port, isn't part of it. Any change to what gets saved needs a new version
+const MAGIC: [u8; 4] = *b"RBOI";
+pub const VERSION: u16 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str), // A value no emulator could have saved
+    TrailingData,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "save state version {version}, only {VERSION} is supported"
+                )
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {what}"),
+            StateError::TrailingData => write!(f, "save state has data past its end"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// FNV-1a, to compare states (or ROMs) without keeping them around
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        Self { data }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn option_u64(&mut self, value: Option<u64>) {
+        self.bool(value.is_some());
+        self.u64(value.unwrap_or(0));
+    }
+
+    // Fixed size, the reader has to know the length
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the magic and the version
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        if !data.starts_with(&MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        let mut reader = Self {
+            data: &data[MAGIC.len()..],
+        };
+        match reader.u16()? {
+            VERSION => Ok(reader),
+            version => Err(StateError::UnsupportedVersion(version)),
+        }
+    }
+
+    // Everything has to have been read
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::TrailingData)
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut bytes = [0; N];
+        self.bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn option_u64(&mut self) -> Result<Option<u64>, StateError> {
+        let some = self.bool()?;
+        let value = self.u64()?;
+        Ok(some.then_some(value))
+    }
+
+    // The next bytes, without copying them
+    pub fn slice(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < length {
+            return Err(StateError::Truncated);
+        }
+        let (read, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(read)
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.slice(bytes.len())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// ============ Scheduler ============
+
+// Components run lazily, they only catch up with the master clock when the CPU touches
+// them, or when they reach a point where something visible happens (an interrupt, a new
+// frame...), which they register here as an event. There is no APU yet, it will need
+// an event of its own for the frame sequencer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    Ppu,
+    Timer,
+    Dma,
+    Input,
+    Serial,
+}
+
+const EVENT_COUNT: usize = 5;
+const NEVER: u64 = u64::MAX;
+
+#[derive(Clone)]
+pub struct Scheduler {
+    now: u64,                   // Master clock, in T-cycles
+    events: [u64; EVENT_COUNT], // When each event is due
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            events: [NEVER; EVENT_COUNT],
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, cycles: u32) {
+        self.now += cycles as u64;
+    }
+
+    // Replaces any previous time for this event
+    pub fn schedule_in(&mut self, event: Event, cycles: u64) {
+        self.events[event as usize] = self.now + cycles;
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events[event as usize] = NEVER;
+    }
+
+    // T-cycles until the next event, None if nothing is scheduled
+    pub fn until_next_event(&self) -> Option<u64> {
+        let next = *self.events.iter().min().unwrap();
+        (next != NEVER).then(|| next.saturating_sub(self.now))
+    }
+
+    // The earliest event that is due, it has to be scheduled again to happen once more
+    pub fn pop_due(&mut self) -> Option<Event> {
+        let (index, &time) = self
+            .events
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, time)| **time)
+            .unwrap();
+        if time > self.now {
+            return None;
+        }
+
+        self.events[index] = NEVER;
+        Some(match index {
+            0 => Event::Ppu,
+            1 => Event::Timer,
+            2 => Event::Dma,
+            3 => Event::Input,
+            _ => Event::Serial,
+        })
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.now);
+        for &time in &self.events {
+            state.u64(time);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.now = state.u64()?;
+        for time in self.events.iter_mut() {
+            *time = state.u64()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+use crate::bus::{BusAccess, Memory};
+use crate::interrupts::{self, Interrupt};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use std::sync::{Arc, Mutex};
+
+pub const SB: u16 = 0xFF01; //Serial transfer data
+pub const SC: u16 = 0xFF02; //Serial transfer control
+
+// 8192 Hz on the internal clock. The CGB fast clock (SC bit 1) doesn't exist on DMG
+const BIT_CYCLES: u64 = 512;
+// How often a device driving the clock gets asked for a byte
+const POLL_CYCLES: u64 = 512;
+
+// ============ Serial Device ============
+
+// Whatever sits at the other end of the link cable, bytes are exchanged whole: both
+// sides shift out their byte while shifting in the other's
+pub trait SerialDevice: Send {
+    // The Game Boy starts driving the clock, the device receives `byte` and answers with
+    // its own, which gets shifted in bit by bit until `done_at`
+    fn exchange(&mut self, byte: u8, done_at: u64) -> u8;
+
+    // The Game Boy waits for the device to drive the clock, with `byte` in SB. Returns
+    // the byte shifted in if the device started a transfer, and when it ends, which is
+    // `now` for a byte sent whole
+    fn external_clock(&mut self, byte: u8, now: u64) -> Option<(u8, u64)> {
+        let _ = (byte, now);
+        None
+    }
+
+    // Devices that need to hear from the Game Boy at a regular pace, whether a transfer
+    // runs or not, return the period here, in T-cycles
+    fn sync_interval(&self) -> Option<u64> {
+        None
+    }
+
+    // Called every sync interval, at multiples of it counted from power on. `waiting` is
+    // SB if a transfer is waiting on an external clock
+    fn sync(&mut self, waiting: Option<u8>) {
+        let _ = waiting;
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice>;
+}
+
+impl Clone for Box<dyn SerialDevice> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// ============ Serial ============
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transfer {
+    Idle,
+    // The device answered `incoming` at `start`, SB shifts a bit every BIT_CYCLES
+    Internal { start: u64, out: u8, incoming: u8 },
+    External { poll_at: u64 },
+    // The device drives the clock, `incoming` is in once it's done
+    Clocked { done_at: u64, incoming: u8 },
+}
+
+// SB once `bits` bits are shifted: out leaves through bit 7, incoming comes in
+// through bit 0
+fn shifted(out: u8, incoming: u8, bits: u64) -> u8 {
+    match bits {
+        0 => out,
+        8.. => incoming,
+        _ => (out << bits) | (incoming >> (8 - bits)),
+    }
+}
+
+// SB and SC live in memory, the controller only keeps track of the running transfer.
+// Without a device, nothing answers: bytes read back as 0xFF, and an external clock
+// transfer never ends
+#[derive(Clone)]
+pub struct Serial {
+    device: Option<Box<dyn SerialDevice>>,
+    transfer: Transfer,
+    next_sync: Option<u64>, // For devices with a sync interval
+}
+
+impl Serial {
+    pub fn new(mem: &mut Memory) -> Self {
+        mem.write(SC, 0x7E);
+        Self {
+            device: None,
+            transfer: Transfer::Idle,
+            next_sync: None,
+        }
+    }
+
+    pub fn connect(
+        &mut self,
+        device: Box<dyn SerialDevice>,
+        now: u64,
+    ) -> Option<Box<dyn SerialDevice>> {
+        self.next_sync = device
+            .sync_interval()
+            .map(|interval| (now / interval + 1) * interval);
+        self.device.replace(device)
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.next_sync = None;
+        self.device.take()
+    }
+
+    // The bus syncs the serial port before any write to its registers
+    pub fn write(&mut self, mem: &mut Memory, now: u64, address: u16, value: u8) {
+        match address {
+            SB => mem.write(SB, value),
+            SC => {
+                // Only bits 7 and 0 exist on DMG
+                mem.write(SC, value | 0x7E);
+                self.transfer = match (value & 0x80 != 0, value & 0x01 != 0) {
+                    (false, _) => Transfer::Idle,
+                    (true, true) => {
+                        let out = mem.read(SB);
+                        let incoming = match self.device.as_mut() {
+                            Some(device) => device.exchange(out, now + 8 * BIT_CYCLES),
+                            None => 0xFF,
+                        };
+                        Transfer::Internal {
+                            start: now,
+                            out,
+                            incoming,
+                        }
+                    }
+                    (true, false) => Transfer::External { poll_at: now },
+                };
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Runs the transfer up to the master clock
+    pub fn sync(&mut self, mem: &mut Memory, now: u64) {
+        match self.transfer {
+            Transfer::Internal {
+                start,
+                out,
+                incoming,
+            } => {
+                let bits = (now - start) / BIT_CYCLES;
+                if bits >= 8 {
+                    self.complete(mem, incoming);
+                } else {
+                    mem.write(SB, shifted(out, incoming, bits));
+                }
+            }
+            Transfer::External { poll_at } if poll_at <= now => {
+                let out = mem.read(SB);
+                let clocked = self
+                    .device
+                    .as_mut()
+                    .and_then(|device| device.external_clock(out, now));
+                self.transfer = match clocked {
+                    Some((incoming, done_at)) => Transfer::Clocked { done_at, incoming },
+                    None => Transfer::External {
+                        poll_at: now + POLL_CYCLES,
+                    },
+                };
+                if let Transfer::Clocked { done_at, incoming } = self.transfer
+                    && done_at <= now
+                {
+                    self.complete(mem, incoming);
+                }
+            }
+            Transfer::Clocked { done_at, incoming } if done_at <= now => {
+                self.complete(mem, incoming);
+            }
+            _ => {}
+        }
+
+        if let (Some(device), Some(next_sync)) = (self.device.as_mut(), self.next_sync)
+            && next_sync <= now
+        {
+            let waiting = match self.transfer {
+                Transfer::External { .. } => Some(mem.read(SB)),
+                _ => None,
+            };
+            device.sync(waiting);
+            self.next_sync = device.sync_interval().map(|interval| next_sync + interval);
+        }
+    }
+
+    fn complete(&mut self, mem: &mut Memory, incoming: u8) {
+        mem.write(SB, incoming);
+        let sc = mem.read(SC) & 0x7F;
+        mem.write(SC, sc);
+        interrupts::request(mem, Interrupt::Serial);
+        self.transfer = Transfer::Idle;
+    }
+
+    // T-cycles until the transfer ends, until the device is asked again, or until the
+    // device's next sync
+    pub fn next_event(&self, now: u64) -> Option<u64> {
+        let transfer = match self.transfer {
+            Transfer::Idle => None,
+            Transfer::Internal { start, .. } => Some(start + 8 * BIT_CYCLES),
+            Transfer::External { poll_at } => self.device.as_ref().map(|_| poll_at),
+            Transfer::Clocked { done_at, .. } => Some(done_at),
+        };
+        let next = match (transfer, self.next_sync) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        next.map(|time| time.saturating_sub(now))
+    }
+
+    // The device isn't saved, whatever is connected when loading stays connected, and
+    // keeps syncing at multiples of its interval
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let (kind, time, out, incoming) = match self.transfer {
+            Transfer::Idle => (0, 0, 0, 0),
+            Transfer::Internal {
+                start,
+                out,
+                incoming,
+            } => (1, start, out, incoming),
+            Transfer::External { poll_at } => (2, poll_at, 0, 0),
+            Transfer::Clocked { done_at, incoming } => (3, done_at, 0, incoming),
+        };
+        state.u8(kind);
+        state.u64(time);
+        state.u8(out);
+        state.u8(incoming);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, now: u64) -> Result<(), StateError> {
+        let kind = state.u8()?;
+        let time = state.u64()?;
+        let out = state.u8()?;
+        let incoming = state.u8()?;
+        self.transfer = match kind {
+            0 => Transfer::Idle,
+            1 => Transfer::Internal {
+                start: time,
+                out,
+                incoming,
+            },
+            2 => Transfer::External { poll_at: time },
+            3 => Transfer::Clocked {
+                done_at: time,
+                incoming,
+            },
+            _ => return Err(StateError::Invalid("serial transfer")),
+        };
+        self.next_sync = self
+            .device
+            .as_ref()
+            .and_then(|device| device.sync_interval())
+            .map(|interval| (now / interval + 1) * interval);
+        Ok(())
+    }
+}
+
+// ============ Blargg Output ============
+
+// Blargg's test ROMs print their results through the serial port, one byte at a time
+// on the internal clock. Clones share the text, so a handle can be kept to read it
+#[derive(Clone, Default)]
+pub struct BlarggOutput {
+    text: Arc<Mutex<String>>,
+    echo: bool,
+}
+
+impl BlarggOutput {
+    // Also prints every character to stdout as it arrives
+    pub fn new() -> Self {
+        Self {
+            text: Arc::default(),
+            echo: true,
+        }
+    }
+
+    pub fn silent() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> String {
+        self.text.lock().unwrap().clone()
+    }
+}
+
+impl SerialDevice for BlarggOutput {
+    fn exchange(&mut self, byte: u8, _done_at: u64) -> u8 {
+        let c = byte as char;
+        self.text.lock().unwrap().push(c);
+        if self.echo {
+            print!("{}", c);
+            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        }
+        0xFF
+    }
+
+    fn clone_box(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::IF;
+
+    // Always answers the same byte
+    #[derive(Clone)]
+    struct Answer(u8);
+
+    impl SerialDevice for Answer {
+        fn exchange(&mut self, _byte: u8, _done_at: u64) -> u8 {
+            self.0
+        }
+
+        fn clone_box(&self) -> Box<dyn SerialDevice> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn started(sc: u8) -> (Serial, Memory) {
+        let mut mem = Memory::new(vec![]);
+        let mut serial = Serial::new(&mut mem);
+        serial.connect(Box::new(Answer(0x3C)), 0);
+        mem.write(IF, 0);
+        serial.write(&mut mem, 0, SB, 0xA5);
+        serial.write(&mut mem, 0, SC, sc);
+        (serial, mem)
+    }
+
+    fn serial_interrupt(mem: &Memory) -> bool {
+        mem.read(IF) & 0x08 != 0
+    }
+
+    // Each bit of SB goes out through bit 7 while the answer comes in through bit 0
+    #[test]
+    fn sb_shifts_a_bit_at_a_time() {
+        let (mut serial, mut mem) = started(0x81);
+        serial.sync(&mut mem, 3 * BIT_CYCLES - 1);
+        assert_eq!(mem.read(SB), 0x94);
+        serial.sync(&mut mem, 3 * BIT_CYCLES);
+        assert_eq!(mem.read(SB), 0x29);
+        assert!(!serial_interrupt(&mem));
+
+        serial.sync(&mut mem, 8 * BIT_CYCLES);
+        assert_eq!(mem.read(SB), 0x3C);
+        assert_eq!(mem.read(SC) & 0x80, 0);
+        assert!(serial_interrupt(&mem));
+    }
+
+    // SC bit 1, the CGB fast clock, reads as 1 and a byte still takes 4096 T-cycles
+    #[test]
+    fn fast_clock_bit_is_ignored() {
+        let (mut serial, mut mem) = started(0x83);
+        assert_eq!(mem.read(SC), 0xFF);
+        assert_eq!(serial.next_event(0), Some(8 * BIT_CYCLES));
+        serial.sync(&mut mem, 8 * BIT_CYCLES - 1);
+        assert_eq!(mem.read(SC) & 0x80, 0x80);
+
+        serial.sync(&mut mem, 8 * BIT_CYCLES);
+        assert_eq!(mem.read(SB), 0x3C);
+        assert!(serial_interrupt(&mem));
+    }
+}
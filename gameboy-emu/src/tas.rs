@@ -0,0 +1,152 @@
+use crate::input::{Button, InputState};
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+
+// Shades drawn, as color indices (0 is the lightest)
+const RELEASED: u8 = 1;
+const PRESSED: u8 = 3;
+const LAG: u8 = 2;
+
+// ============ Input Display ============
+
+// Each button is a block of 3x3 pixels, at (x, y) in blocks from the bottom left corner
+// of the overlay: the D-pad as a cross, then Select and Start, then B and A
+const BLOCK: usize = 3;
+const LAYOUT: [(Button, usize, usize); 8] = [
+    (Button::Up, 1, 0),
+    (Button::Left, 0, 1),
+    (Button::Right, 2, 1),
+    (Button::Down, 1, 2),
+    (Button::Select, 4, 2),
+    (Button::Start, 6, 2),
+    (Button::B, 8, 1),
+    (Button::A, 10, 0),
+];
+// The overlay is 11x3 blocks, with a pixel of margin from the screen's corner, and a
+// lag marker to the right of A
+const LEFT: usize = 1;
+const TOP: usize = HEIGHT - 1 - 3 * BLOCK;
+const LAG_MARKER: (usize, usize) = (12, 2);
+
+// Draws the buttons held over the frame, lag frames get a marker
+pub fn draw_input(frame: &mut [u8; WIDTH * HEIGHT], input: InputState, lag: bool) {
+    for (button, x, y) in LAYOUT {
+        let shade = if input.is_pressed(button) {
+            PRESSED
+        } else {
+            RELEASED
+        };
+        fill_block(frame, x, y, shade);
+    }
+    if lag {
+        fill_block(frame, LAG_MARKER.0, LAG_MARKER.1, LAG);
+    }
+}
+
+fn fill_block(frame: &mut [u8; WIDTH * HEIGHT], x: usize, y: usize, shade: u8) {
+    let (left, top) = (LEFT + x * BLOCK, TOP + y * BLOCK);
+    for row in top..top + BLOCK {
+        frame[row * WIDTH + left..row * WIDTH + left + BLOCK].fill(shade);
+    }
+}
+
+// ============ Frontend Keys ============
+
+// Pause, frame advance and the input display toggle, for a frontend. Each acts when its
+// key is pressed, not while it's held
+#[derive(Default)]
+pub struct TasKeys {
+    paused: bool,
+    input_display: bool,
+    held: [bool; 3], // The keys on the last update, for presses
+}
+
+impl TasKeys {
+    // Takes pause, frame advance and input display as held now, returns whether the
+    // game runs a frame
+    pub fn update(&mut self, keys: [bool; 3]) -> bool {
+        let [pause, advance, display] = [0, 1, 2].map(|i| keys[i] && !self.held[i]);
+        self.held = keys;
+        self.paused ^= pause;
+        self.input_display ^= display;
+        !self.paused || advance
+    }
+
+    pub fn input_display(&self) -> bool {
+        self.input_display
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The overlay, in pixels: 13 blocks wide with the lag marker, 3 high
+    fn in_overlay(x: usize, y: usize) -> bool {
+        (LEFT..LEFT + 13 * BLOCK).contains(&x) && (TOP..TOP + 3 * BLOCK).contains(&y)
+    }
+
+    fn block(frame: &[u8; WIDTH * HEIGHT], (x, y): (usize, usize)) -> u8 {
+        frame[(TOP + y * BLOCK + 1) * WIDTH + LEFT + x * BLOCK + 1]
+    }
+
+    #[test]
+    fn draws_only_in_the_corner() {
+        let mut input = InputState::new();
+        input.press(Button::A);
+        input.press(Button::Left);
+        for lag in [false, true] {
+            let mut frame = [0; WIDTH * HEIGHT];
+            draw_input(&mut frame, input, lag);
+
+            for (i, &pixel) in frame.iter().enumerate() {
+                if !in_overlay(i % WIDTH, i / WIDTH) {
+                    assert_eq!(pixel, 0, "pixel {i} drawn outside the overlay");
+                }
+            }
+            for (button, x, y) in LAYOUT {
+                let shade = if input.is_pressed(button) {
+                    PRESSED
+                } else {
+                    RELEASED
+                };
+                assert_eq!(block(&frame, (x, y)), shade, "{button:?}");
+            }
+            let marker = if lag { LAG } else { 0 };
+            assert_eq!(block(&frame, LAG_MARKER), marker);
+        }
+    }
+
+    // Keys act on the update they are pressed on, holding them does nothing more
+    #[test]
+    fn keys_act_when_pressed() {
+        const PAUSE: [bool; 3] = [true, false, false];
+        const ADVANCE: [bool; 3] = [false, true, false];
+        const DISPLAY: [bool; 3] = [false, false, true];
+        const NONE: [bool; 3] = [false; 3];
+        let mut keys = TasKeys::default();
+        assert!(keys.update(NONE));
+
+        assert!(!keys.update(PAUSE));
+        assert!(!keys.update(PAUSE));
+        assert!(!keys.update(NONE));
+
+        // A frame for each press of frame advance
+        assert!(keys.update(ADVANCE));
+        assert!(!keys.update(ADVANCE));
+        assert!(!keys.update(NONE));
+        assert!(keys.update(ADVANCE));
+
+        assert!(!keys.update(DISPLAY));
+        assert!(keys.input_display());
+        assert!(!keys.update(DISPLAY));
+        assert!(keys.input_display());
+        assert!(!keys.update(NONE));
+        assert!(!keys.update(DISPLAY));
+        assert!(!keys.input_display());
+
+        assert!(keys.update(PAUSE));
+        assert!(keys.update(NONE));
+    }
+}
@@ -0,0 +1,264 @@
+use crate::bus::{BusAccess, Memory};
+use crate::interrupts::{self, Interrupt};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+// After the boot ROM, DIV reads 0xAB
+const BOOT_COUNTER: u16 = 0xABCC;
+// TIMA reads 0 for an M-cycle after overflowing, before TMA is loaded
+const RELOAD_DELAY: u64 = 4;
+
+// ============ Timer ============
+
+// The timer is driven by the 16-bit system counter, incremented every T-cycle, DIV being
+// its upper byte. TIMA is incremented on the falling edges of the bit selected by TAC
+// (ANDed with the enable bit), which is why writing DIV or TAC can increment it.
+// The registers live in memory, the timer catches up with the master clock when its
+// registers are touched, or when TIMA overflows.
+#[derive(Clone)]
+pub struct Timer {
+    counter: u16,
+    synced: u64, // Master clock at the last catch up
+
+    reload_at: Option<u64>, // TIMA overflowed, TMA gets loaded at that time
+    reloaded_at: Option<u64>,
+}
+
+impl Timer {
+    pub fn new(mem: &mut Memory) -> Self {
+        mem.write(DIV, (BOOT_COUNTER >> 8) as u8);
+        mem.write(TAC, 0xF8);
+        Self {
+            counter: BOOT_COUNTER,
+            synced: 0,
+            reload_at: None,
+            reloaded_at: None,
+        }
+    }
+
+    // The counter bit TIMA follows, if the timer is enabled
+    fn selected_bit(tac: u8) -> Option<u8> {
+        if tac & 0x04 == 0 {
+            return None;
+        }
+        Some(match tac & 0x03 {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        })
+    }
+
+    // State of the signal whose falling edges increment TIMA
+    fn signal(&self, tac: u8) -> bool {
+        Self::selected_bit(tac).is_some_and(|bit| self.counter & (1 << bit) != 0)
+    }
+
+    fn increment_tima(&mut self, mem: &mut Memory, now: u64) {
+        let tima = mem.read(TIMA);
+        if tima == 0xFF {
+            mem.write(TIMA, 0);
+            self.reload_at = Some(now + RELOAD_DELAY);
+        } else {
+            mem.write(TIMA, tima + 1);
+        }
+    }
+
+    fn reload(&mut self, mem: &mut Memory, now: u64) {
+        let tma = mem.read(TMA);
+        mem.write(TIMA, tma);
+        interrupts::request(mem, Interrupt::Timer);
+        self.reload_at = None;
+        self.reloaded_at = Some(now);
+    }
+
+    // Moves the counter forward, incrementing TIMA on the falling edges of the selected bit
+    fn advance(&mut self, mem: &mut Memory, cycles: u64) {
+        let old = self.counter as u64;
+        self.counter = self.counter.wrapping_add(cycles as u16);
+        self.synced += cycles;
+
+        let Some(bit) = Self::selected_bit(mem.read(TAC)) else {
+            return;
+        };
+        let period = 2u64 << bit;
+        let edges = (old + cycles) / period - old / period;
+        if edges > 0 {
+            // sync stops on the overflowing edge, so only the last one can overflow
+            let tima = mem.read(TIMA);
+            mem.write(TIMA, tima + (edges - 1) as u8);
+            self.increment_tima(mem, self.synced);
+        }
+    }
+
+    // Runs the timer up to the master clock
+    pub fn sync(&mut self, mem: &mut Memory, now: u64) {
+        while self.synced < now {
+            let target = match self.next_event(mem) {
+                Some(cycles) => now.min(self.synced + cycles),
+                None => now,
+            };
+            self.advance(mem, target - self.synced);
+
+            if self.reload_at == Some(self.synced) {
+                self.reload(mem, self.synced);
+            }
+        }
+        mem.write(DIV, (self.counter >> 8) as u8);
+    }
+
+    // The bus catches the timer up before any write to its registers
+    pub fn write(&mut self, mem: &mut Memory, now: u64, address: u16, value: u8) {
+        let tac = mem.read(TAC);
+        match address {
+            DIV => {
+                let signal = self.signal(tac);
+                self.counter = 0;
+                mem.write(DIV, 0);
+                if signal {
+                    self.increment_tima(mem, now);
+                }
+            }
+            TIMA => {
+                if self.reload_at.is_some() {
+                    // Writing during the overflow cycle cancels the reload and the interrupt
+                    self.reload_at = None;
+                    mem.write(TIMA, value);
+                } else if self.reloaded_at != Some(now) {
+                    // On the reload cycle itself, TMA wins
+                    mem.write(TIMA, value);
+                }
+            }
+            TMA => {
+                mem.write(TMA, value);
+                if self.reloaded_at == Some(now) {
+                    mem.write(TIMA, value);
+                }
+            }
+            TAC => {
+                let signal = self.signal(tac);
+                mem.write(TAC, value | 0xF8);
+                if signal && !self.signal(value) {
+                    self.increment_tima(mem, now);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // T-cycles until something happens: TMA being loaded, or TIMA overflowing
+    pub fn next_event(&self, mem: &Memory) -> Option<u64> {
+        if let Some(reload_at) = self.reload_at {
+            return Some(reload_at - self.synced);
+        }
+
+        let bit = Self::selected_bit(mem.read(TAC))?;
+        let period = 2u64 << bit;
+        let counter = self.counter as u64;
+        let first_edge = (counter / period + 1) * period;
+        let increments = 0x100 - mem.read(TIMA) as u64;
+        Some(first_edge + (increments - 1) * period - counter)
+    }
+
+    // The registers are saved with the rest of memory
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u64(self.synced);
+        state.option_u64(self.reload_at);
+        state.option_u64(self.reloaded_at);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.u16()?;
+        self.synced = state.u64()?;
+        self.reload_at = state.option_u64()?;
+        self.reloaded_at = state.option_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::IF;
+
+    // TIMA follows bit 3 of the counter: it goes up when the counter reaches a
+    // multiple of 16
+    fn started(counter: u16, tima: u8) -> (Timer, Memory) {
+        let mut mem = Memory::new(vec![]);
+        let mut timer = Timer::new(&mut mem);
+        timer.counter = counter;
+        timer.write(&mut mem, 0, TAC, 0x05);
+        mem.write(TIMA, tima);
+        mem.write(TMA, 0x42);
+        mem.write(IF, 0);
+        (timer, mem)
+    }
+
+    fn timer_interrupt(mem: &Memory) -> bool {
+        mem.read(IF) & 0x04 != 0
+    }
+
+    // On overflow TIMA reads 0 for an M-cycle, then TMA is loaded and the interrupt raised
+    #[test]
+    fn reload_is_delayed_by_a_m_cycle() {
+        let (mut timer, mut mem) = started(0, 0xFF);
+        timer.sync(&mut mem, 16);
+        assert_eq!(mem.read(TIMA), 0);
+        timer.sync(&mut mem, 19);
+        assert_eq!(mem.read(TIMA), 0);
+        assert!(!timer_interrupt(&mem));
+
+        timer.sync(&mut mem, 20);
+        assert_eq!(mem.read(TIMA), 0x42);
+        assert!(timer_interrupt(&mem));
+    }
+
+    // Writing TIMA while it reads 0 cancels the reload and the interrupt
+    #[test]
+    fn tima_write_during_the_delay_cancels_the_reload() {
+        let (mut timer, mut mem) = started(0, 0xFF);
+        timer.sync(&mut mem, 18);
+        timer.write(&mut mem, 18, TIMA, 0x99);
+        timer.sync(&mut mem, 24);
+        assert_eq!(mem.read(TIMA), 0x99);
+        assert!(!timer_interrupt(&mem));
+    }
+
+    // On the cycle TMA is loaded, a TIMA write is lost and a TMA write goes through
+    #[test]
+    fn writes_on_the_reload_cycle() {
+        let (mut timer, mut mem) = started(0, 0xFF);
+        timer.sync(&mut mem, 20);
+        timer.write(&mut mem, 20, TIMA, 0x99);
+        assert_eq!(mem.read(TIMA), 0x42);
+        timer.write(&mut mem, 20, TMA, 0x77);
+        assert_eq!(mem.read(TIMA), 0x77);
+        assert!(timer_interrupt(&mem));
+    }
+
+    // Resetting DIV while the selected bit is set is a falling edge
+    #[test]
+    fn div_reset_increments_tima_on_a_falling_edge() {
+        let (mut timer, mut mem) = started(8, 0x10);
+        timer.write(&mut mem, 0, DIV, 0);
+        assert_eq!(mem.read(TIMA), 0x11);
+
+        // Not when it's clear
+        let (mut timer, mut mem) = started(4, 0x10);
+        timer.write(&mut mem, 0, DIV, 0);
+        assert_eq!(mem.read(TIMA), 0x10);
+    }
+
+    // Disabling the timer while the selected bit is set is a falling edge too
+    #[test]
+    fn disabling_the_timer_can_increment_tima() {
+        let (mut timer, mut mem) = started(8, 0x10);
+        timer.write(&mut mem, 0, TAC, 0x01);
+        assert_eq!(mem.read(TIMA), 0x11);
+    }
+}
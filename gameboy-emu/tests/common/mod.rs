@@ -0,0 +1,39 @@
+#![allow(dead_code)] // Each test file only uses part of it
+
+// Shared by the tests: ROMs from the repository, a hash to compare frames, and the
+// Tetris script of the link tests
+
+pub mod tetris;
+
+use gameboy_emu::GameBoi;
+use std::path::PathBuf;
+
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
+pub type Frame = [u8; WIDTH * HEIGHT];
+
+// A ROM next to Cargo.toml, or None with a note when it isn't there, so tests that need
+// a commercial ROM are skipped instead of failing
+pub fn rom(name: &str) -> Option<Vec<u8>> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(name);
+    match std::fs::read(&path) {
+        Ok(rom) => Some(rom),
+        Err(_) => {
+            eprintln!("{} not found, skipping", path.display());
+            None
+        }
+    }
+}
+
+pub fn load(rom: &[u8]) -> GameBoi {
+    let mut gameboi = GameBoi::new();
+    gameboi.load_rom_from_data(rom);
+    gameboi
+}
+
+// FNV-1a, like save states
+pub fn frame_hash(frame: &Frame) -> u64 {
+    frame.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
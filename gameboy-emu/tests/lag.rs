@@ -0,0 +1,79 @@
+mod common;
+
+use common::load;
+use gameboy_emu::GameBoi;
+
+// Waits for 10 V-blanks without reading the joypad, then polls it forever
+const PROGRAM: [u8; 21] = [
+    0x06, 0x0A, // LD B,10
+    0xF0, 0x44, // LDH A,(LY)
+    0xFE, 0x90, // CP 144
+    0x20, 0xFA, // JR NZ,-6
+    0xF0, 0x44, // LDH A,(LY)
+    0xFE, 0x90, // CP 144
+    0x28, 0xFA, // JR Z,-6
+    0x05, // DEC B
+    0x20, 0xF1, // JR NZ,-15
+    0xF0, 0x00, // LDH A,(JOYP)
+    0x18, 0xFC, // JR -4
+];
+
+fn waiting() -> GameBoi {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+    load(&rom)
+}
+
+// Frames are handed out as LY wraps to 0, the game polls on the tenth one
+#[test]
+fn lag_frames_until_the_game_polls() {
+    let mut gameboi = waiting();
+    for frame in 1..=9 {
+        gameboi.step();
+        assert!(gameboi.is_lag_frame());
+        assert_eq!(gameboi.lag_frames(), frame);
+    }
+    for _ in 0..10 {
+        gameboi.step();
+        assert!(!gameboi.is_lag_frame());
+        assert_eq!(gameboi.lag_frames(), 9);
+    }
+}
+
+#[test]
+fn lag_count_is_in_save_states() {
+    let mut gameboi = waiting();
+    for _ in 0..5 {
+        gameboi.step();
+    }
+    let state = gameboi.save_state();
+    for _ in 0..10 {
+        gameboi.step();
+    }
+    assert!(!gameboi.is_lag_frame());
+
+    let mut fresh = waiting();
+    for gameboi in [&mut gameboi, &mut fresh] {
+        gameboi.load_state(&state).unwrap();
+        assert!(gameboi.is_lag_frame());
+        assert_eq!(gameboi.lag_frames(), 5);
+    }
+}
+
+// The input display only shows in the frames handed out, in the bottom left corner
+#[test]
+fn input_display_leaves_the_emulation_alone() {
+    let mut plain = waiting();
+    let mut shown = waiting();
+    shown.set_input_display(true);
+    for _ in 0..3 {
+        let (frame, drawn) = (plain.step(), shown.step());
+        assert_eq!(plain.state_hash(), shown.state_hash());
+        let changed: Vec<usize> = (0..frame.len()).filter(|&i| frame[i] != drawn[i]).collect();
+        assert!(!changed.is_empty());
+        for i in changed {
+            let (x, y) = (i % 160, i / 160);
+            assert!(x < 40 && y >= 134, "pixel ({x}, {y}) drawn over");
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use gameboy_emu::{MobileAdapter, MobileConfig, SerialDevice};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener};
+
+const HOST: &str = "gameboy.datacenter.ne.jp";
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REQUEST: &str = "GET /index.html HTTP/1.0\r\n\r\n";
+const RESPONSE: &str = "HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\nHi\n";
+
+// Answers a single request and closes the connection
+fn mock_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader.get_mut().write_all(RESPONSE.as_bytes()).unwrap();
+    });
+    address
+}
+
+// Magic, header (command, 0, length), data and checksum, the same both ways
+fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+    let header = [command, 0, 0, data.len() as u8];
+    let checksum = header
+        .iter()
+        .chain(data)
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    let mut packet = vec![0x99, 0x66];
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+// What the adapter clocks out for an answer, ending with its ID and a 0
+fn answer(command: u8, data: &[u8]) -> Vec<u8> {
+    let mut answer = packet(command, data);
+    answer.extend_from_slice(&[0x88, 0x00]);
+    answer
+}
+
+// Sends a packet as a game would, then clocks the answer out and returns every byte of it
+fn send(adapter: &mut MobileAdapter, command: u8, data: &[u8]) -> Vec<u8> {
+    for byte in packet(command, data) {
+        assert_eq!(adapter.exchange(byte, 0), 0xD2);
+    }
+    assert_eq!(adapter.exchange(0x81, 0), 0x88);
+    assert_eq!(adapter.exchange(0x00, 0), command | 0x80);
+
+    let mut answer = vec![0x99];
+    while adapter.exchange(0x4B, 0) != 0x99 {}
+    answer.extend((0..5).map(|_| adapter.exchange(0x4B, 0)));
+    let length = answer[5] as usize;
+    answer.extend((0..length + 2).map(|_| adapter.exchange(0x4B, 0)));
+    answer.push(adapter.exchange(0x80, 0));
+    answer.push(adapter.exchange(answer[2], 0));
+    answer
+}
+
+#[test]
+fn http_request_through_the_adapter() {
+    let config = MobileConfig::new()
+        .host(HOST, SERVER_IP)
+        .server(SERVER_IP, 80, &mock_server());
+    let mut adapter = MobileAdapter::new(config);
+
+    assert_eq!(
+        send(&mut adapter, 0x10, b"NINTENDO"),
+        answer(0x90, b"NINTENDO")
+    );
+    let mut login = vec![4];
+    login.extend_from_slice(b"user");
+    login.push(4);
+    login.extend_from_slice(b"pass");
+    login.extend_from_slice(&[0; 8]);
+    let mut ip = vec![127, 0, 0, 1];
+    ip.extend_from_slice(&[0; 8]);
+    assert_eq!(send(&mut adapter, 0x21, &login), answer(0xA1, &ip));
+    assert_eq!(
+        send(&mut adapter, 0x28, HOST.as_bytes()),
+        answer(0xA8, &[10, 0, 0, 1])
+    );
+    assert_eq!(
+        send(&mut adapter, 0x28, b"elsewhere"),
+        answer(0xEE, &[0x28, 2])
+    );
+    assert_eq!(
+        send(&mut adapter, 0x23, &[10, 0, 0, 1, 0, 80]),
+        answer(0xA3, &[0])
+    );
+
+    // Polled with empty transfers until the server closes the connection
+    let mut request = vec![0];
+    request.extend_from_slice(REQUEST.as_bytes());
+    let mut received = Vec::new();
+    let mut reply = send(&mut adapter, 0x15, &request);
+    for _ in 0..10_000 {
+        if reply[2] != 0x95 {
+            break;
+        }
+        received.extend_from_slice(&reply[7..reply.len() - 4]);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        reply = send(&mut adapter, 0x15, &[0]);
+    }
+    assert_eq!(reply, answer(0x9F, &[0]));
+    assert_eq!(received, RESPONSE.as_bytes());
+
+    assert_eq!(send(&mut adapter, 0x22, &[]), answer(0xA2, &[]));
+    assert_eq!(send(&mut adapter, 0x11, &[]), answer(0x91, &[]));
+}
+
+// A packet with a wrong checksum is refused, the adapter waits for the next one
+#[test]
+fn bad_checksum_is_refused() {
+    let mut adapter = MobileAdapter::new(MobileConfig::new());
+    let mut bad = packet(0x10, b"NINTENDO");
+    *bad.last_mut().unwrap() ^= 0xFF;
+    for byte in bad {
+        adapter.exchange(byte, 0);
+    }
+    assert_eq!(adapter.exchange(0x81, 0), 0x88);
+    assert_eq!(adapter.exchange(0x00, 0), 0xF1);
+
+    assert_eq!(
+        send(&mut adapter, 0x10, b"NINTENDO"),
+        answer(0x90, b"NINTENDO")
+    );
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use common::{Frame, load, rom};
+use gameboy_emu::{Button, GameBoi, InputState, Movie, MovieError, MoviePlayer, MovieRecorder};
+
+// Start held now and then, so the game does more than sit on its title screen
+fn input(frame: usize) -> InputState {
+    let mut input = InputState::new();
+    input.set(Button::Start, frame % 40 < 5);
+    input.set(Button::Right, frame % 60 > 30);
+    input
+}
+
+fn record(gameboi: &mut GameBoi, frames: usize) -> (Movie, Vec<Frame>) {
+    let mut recorder = MovieRecorder::new(gameboi, true);
+    let frames = (0..frames)
+        .map(|frame| recorder.step(gameboi, input(frame)))
+        .collect();
+    (recorder.finish(), frames)
+}
+
+fn play(movie: &Movie, gameboi: &mut GameBoi) -> Result<Vec<Frame>, MovieError> {
+    let mut player = MoviePlayer::new(movie.clone(), gameboi)?;
+    let mut frames = Vec::new();
+    while let Some(frame) = player.step(gameboi) {
+        frames.push(frame?);
+    }
+    assert!(player.is_finished());
+    Ok(frames)
+}
+
+// A movie from power on gives the same frames and the same state on a fresh GameBoi
+#[test]
+fn playback_matches_recording() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut recorded = load(&rom);
+    let (movie, expected) = record(&mut recorded, 300);
+    assert_eq!(movie.len(), 300);
+    assert!(movie.starts_at_power_on());
+    assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+
+    let mut played = load(&rom);
+    let frames = play(&movie, &mut played).unwrap();
+    assert!(frames == expected);
+    assert_eq!(played.state_hash(), recorded.state_hash());
+}
+
+// A movie recorded mid-game carries the state it starts from
+#[test]
+fn playback_from_a_save_state() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut recorded = load(&rom);
+    record(&mut recorded, 100);
+    let (movie, expected) = record(&mut recorded, 200);
+    assert!(!movie.starts_at_power_on());
+    assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+
+    let mut played = load(&rom);
+    assert!(play(&movie, &mut played).unwrap() == expected);
+    assert_eq!(played.state_hash(), recorded.state_hash());
+}
+
+// Frames are the input and a hash flag after the 20 byte header of a power on movie,
+// each one 10 bytes with its hash
+#[test]
+fn changed_input_desyncs() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let (movie, _) = record(&mut load(&rom), 100);
+    let mut data = movie.to_bytes();
+    data[20 + 10 * 50] ^= 0x01;
+    let movie = Movie::from_bytes(&data).unwrap();
+
+    let error = play(&movie, &mut load(&rom)).unwrap_err();
+    assert!(matches!(error, MovieError::Desync { frame: 50 }), "{error}");
+}
+
+#[test]
+fn wrong_start_is_refused() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let (movie, _) = record(&mut load(&rom), 10);
+
+    let mut other = load(&[0; 0x8000]);
+    let error = MoviePlayer::new(movie.clone(), &mut other).err().unwrap();
+    assert!(matches!(error, MovieError::WrongRom), "{error}");
+
+    let mut running = load(&rom);
+    running.step();
+    let error = MoviePlayer::new(movie, &mut running).err().unwrap();
+    assert!(matches!(error, MovieError::NotAtPowerOn), "{error}");
+}
+
+// Broken files give an error, whatever part of them is broken
+#[test]
+fn broken_movies_are_refused() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut gameboi = load(&rom);
+    gameboi.step();
+    let (movie, _) = record(&mut gameboi, 20);
+    let data = movie.to_bytes();
+
+    for length in 0..data.len() {
+        let error = Movie::from_bytes(&data[..length]).unwrap_err();
+        match length {
+            0..4 => assert!(matches!(error, MovieError::NotAMovie), "{error}"),
+            _ => assert!(matches!(error, MovieError::Truncated), "{error}"),
+        }
+    }
+
+    let broken = |offset: usize, bytes: &[u8]| {
+        let mut data = data.clone();
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Movie::from_bytes(&data)
+    };
+    assert!(matches!(broken(0, b"RBSS"), Err(MovieError::NotAMovie)));
+    assert!(matches!(
+        broken(4, &[9, 0]),
+        Err(MovieError::UnsupportedVersion(9))
+    ));
+    assert!(matches!(
+        broken(6, &[1]),
+        Err(MovieError::UnsupportedModel(1))
+    ));
+    // The lengths of the start state and of the frames
+    assert!(matches!(broken(16, &[0xFF; 4]), Err(MovieError::Truncated)));
+    let state_length = u32::from_le_bytes(data[16..20].try_into().unwrap()) as usize;
+    assert!(matches!(
+        broken(20 + state_length, &[0xFF; 4]),
+        Err(MovieError::Truncated)
+    ));
+
+    // A start state that doesn't load
+    let movie = broken(20, b"NOPE").unwrap();
+    let error = MoviePlayer::new(movie, &mut load(&rom)).err().unwrap();
+    assert!(matches!(error, MovieError::State(_)), "{error}");
+}
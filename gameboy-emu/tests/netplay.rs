@@ -0,0 +1,68 @@
+mod common;
+
+use common::rom;
+use common::tetris::tetris_input;
+use gameboy_emu::{GameBoi, LinkedPair, NetplaySession};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+// Through the linked menus, into the versus game
+const FRAMES: usize = 1100;
+const LATENCY: Duration = Duration::from_millis(30);
+
+fn pair(rom: &[u8]) -> LinkedPair {
+    let [first, second] = [0, 1].map(|_| {
+        let mut gameboi = GameBoi::new();
+        gameboi.load_rom_from_data(rom);
+        gameboi
+    });
+    LinkedPair::new(first, second)
+}
+
+// Returns the state hash once synced, and the rollbacks
+fn play(rom: &[u8], side: usize, socket: UdpSocket, peer: String) -> (u64, u64) {
+    let mut session = NetplaySession::from_socket(pair(rom), side, socket, peer).unwrap();
+    session.set_latency(LATENCY);
+    for frame in 0..FRAMES {
+        session.step(tetris_input(frame, side)).unwrap();
+    }
+    session.sync().unwrap();
+    (session.state_hash(), session.rollbacks())
+}
+
+// Two sessions on localhost, packets held back to fake a slow network. Each side only
+// knows its own player's input, yet once synced both must be in the state a linked pair
+// run offline with both inputs gets to
+#[test]
+fn sessions_match_the_offline_run() {
+    let Some(rom) = rom("../Tetris.gb") else {
+        return;
+    };
+    let sockets = [0, 1].map(|_| UdpSocket::bind("127.0.0.1:0").unwrap());
+    let addresses = sockets
+        .each_ref()
+        .map(|socket| socket.local_addr().unwrap().to_string());
+    let sides: Vec<_> = sockets
+        .into_iter()
+        .enumerate()
+        .map(|(side, socket)| {
+            let (rom, peer) = (rom.clone(), addresses[1 - side].clone());
+            thread::spawn(move || play(&rom, side, socket, peer))
+        })
+        .collect();
+
+    let mut offline = pair(&rom);
+    for frame in 0..FRAMES {
+        for side in 0..2 {
+            offline.gameboi(side).set_input(tetris_input(frame, side));
+        }
+        offline.step();
+    }
+
+    let results: Vec<(u64, u64)> = sides.into_iter().map(|side| side.join().unwrap()).collect();
+    assert_eq!(results[0].0, offline.state_hash());
+    assert_eq!(results[1].0, offline.state_hash());
+    // Both ran on guesses, some of them wrong
+    assert!(results[0].1 + results[1].1 > 0);
+}
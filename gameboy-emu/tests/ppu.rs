@@ -0,0 +1,34 @@
+mod common;
+
+use common::{Frame, frame_hash, rom};
+use gameboy_emu::{GameBoi, RendererKind};
+
+// dmg-acid2 draws a face that only comes out right when the background, window and
+// objects follow the hardware's rules. This is the hash of the face drawn right, checked
+// by eye
+const ACID2_HASH: u64 = 0xF272_A8FF_E3DB_4C16;
+
+fn acid2_frame(renderer: RendererKind) -> Option<Frame> {
+    let mut gameboi = GameBoi::with_renderer(renderer);
+    gameboi.load_rom_from_data(&rom("dmg-acid2.gb")?);
+    let mut frame = gameboi.step();
+    for _ in 0..30 {
+        frame = gameboi.step();
+    }
+    Some(frame)
+}
+
+#[test]
+fn dmg_acid2() {
+    if let Some(frame) = acid2_frame(RendererKind::Fifo) {
+        assert_eq!(frame_hash(&frame), ACID2_HASH);
+    }
+}
+
+// The test doesn't write registers in the middle of lines, both renderers must agree
+#[test]
+fn dmg_acid2_scanline() {
+    if let Some(frame) = acid2_frame(RendererKind::Scanline) {
+        assert_eq!(frame_hash(&frame), ACID2_HASH);
+    }
+}
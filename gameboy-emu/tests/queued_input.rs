@@ -0,0 +1,46 @@
+mod common;
+
+use common::{HEIGHT, WIDTH, load};
+use gameboy_emu::{Button, InputState};
+
+const LINE_CYCLES: u64 = 456;
+
+// Halts until the joypad interrupt is requested, then makes the background black
+const PROGRAM: [u8; 13] = [
+    0x3E, 0x10, // LD A,$10
+    0xE0, 0x00, // LDH (JOYP),A: action buttons selected
+    0xE0, 0xFF, // LDH (IE),A: joypad interrupt enabled
+    0x76, // HALT
+    0x3E, 0xFF, // LD A,$FF
+    0xE0, 0x47, // LDH (BGP),A
+    0x18, 0xFE, // JR -2
+];
+
+// A press queued for the middle of the next frame wakes the CPU then, not at the end
+// of the frame: the lines drawn before it are white, the ones after it black
+#[test]
+fn queued_press_lands_mid_frame() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+    let mut gameboi = load(&rom);
+    gameboi.step();
+
+    let mut input = InputState::new();
+    input.press(Button::A);
+    // Frames are handed out as LY wraps to 0, line 72 starts this much later
+    gameboi.queue_input(72 * LINE_CYCLES, input);
+    let frame = gameboi.step();
+
+    let dark = |line: usize| {
+        frame[line * WIDTH..(line + 1) * WIDTH]
+            .iter()
+            .all(|&c| c == 3)
+    };
+    let light = |line: usize| {
+        frame[line * WIDTH..(line + 1) * WIDTH]
+            .iter()
+            .all(|&c| c == 0)
+    };
+    assert!((0..72).all(light));
+    assert!((72..HEIGHT).all(dark));
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{load, rom};
+use gameboy_emu::{Button, GameBoi, InputState};
+use std::collections::HashMap;
+
+fn input(frame: u64) -> InputState {
+    let mut input = InputState::new();
+    input.set(Button::Start, frame % 40 < 5);
+    input.set(Button::Right, frame % 60 > 30);
+    input
+}
+
+// Runs the frames with rewind on, `ahead` runs a frame further and loads the state back
+// every frame, like a frontend's run-ahead. Returns the state hash after each frame
+fn run(gameboi: &mut GameBoi, frames: u64, ahead: impl Fn(&mut GameBoi)) -> HashMap<u64, u64> {
+    let mut hashes = HashMap::new();
+    for _ in 0..frames {
+        gameboi.set_input(input(gameboi.frames()));
+        gameboi.step();
+        ahead(gameboi);
+        hashes.insert(gameboi.frames(), gameboi.state_hash());
+    }
+    hashes
+}
+
+fn check_rewind(gameboi: &mut GameBoi, hashes: &HashMap<u64, u64>) {
+    let frame = gameboi.frames();
+    assert_eq!(gameboi.rewind(30), 30);
+    assert_eq!(gameboi.state_hash(), hashes[&(frame - 30)]);
+    // Playing on keeps a history that can be rewound again
+    run(gameboi, 10, |_| {});
+    assert_eq!(gameboi.rewind(20), 20);
+    assert_eq!(gameboi.state_hash(), hashes[&(frame - 40)]);
+}
+
+// Loading a state keeps the history up to the loaded frame
+#[test]
+fn rewind_survives_loading_states() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut gameboi = load(&rom);
+    gameboi.enable_rewind(1, 1 << 20);
+    let hashes = run(&mut gameboi, 120, |gameboi| {
+        let state = gameboi.save_state();
+        gameboi.step();
+        gameboi.load_state(&state).unwrap();
+    });
+    check_rewind(&mut gameboi, &hashes);
+}
+
+#[test]
+fn rewind_with_run_ahead() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut gameboi = load(&rom);
+    gameboi.enable_rewind(1, 1 << 20);
+    gameboi.set_run_ahead(2);
+    let hashes = run(&mut gameboi, 120, |_| {});
+    check_rewind(&mut gameboi, &hashes);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{load, rom};
+use gameboy_emu::{Button, InputState};
+
+const AHEAD: u32 = 2;
+
+fn input(frame: u64) -> InputState {
+    let mut input = InputState::new();
+    input.set(Button::Start, frame % 40 < 5);
+    input.set(Button::Right, frame % 60 > 30);
+    input
+}
+
+// With run-ahead, a step shows the frame a plain run gets AHEAD frames later when the
+// input stays the same, and leaves the machine where the plain run is
+#[test]
+fn run_ahead_shows_a_later_frame() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut plain = load(&rom);
+    let mut ahead = load(&rom);
+    ahead.set_run_ahead(AHEAD);
+
+    // The screen has to move for the check to mean anything
+    let mut moved = false;
+    for frame in 0..300 {
+        plain.set_input(input(frame));
+        let now = plain.step();
+        ahead.set_input(input(frame));
+        let shown = ahead.step();
+        assert_eq!(ahead.frames(), plain.frames());
+        assert_eq!(ahead.state_hash(), plain.state_hash(), "frame {frame}");
+
+        let state = plain.save_state();
+        let mut later = now;
+        for _ in 0..AHEAD {
+            later = plain.step();
+        }
+        assert!(
+            shown == later,
+            "frame {frame} isn't the one {AHEAD} frames later"
+        );
+        moved |= later != now;
+        plain.load_state(&state).unwrap();
+    }
+    assert!(moved);
+}
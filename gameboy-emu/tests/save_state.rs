@@ -0,0 +1,82 @@
+mod common;
+
+use common::{Frame, load, rom};
+use gameboy_emu::{Button, GameBoi, InputState, StateError};
+
+// Start held now and then, so the game does more than sit on its title screen
+fn input(frame: usize) -> InputState {
+    let mut input = InputState::new();
+    input.set(Button::Start, frame % 40 < 5);
+    input.set(Button::Right, frame % 60 > 30);
+    input
+}
+
+fn run(gameboi: &mut GameBoi, frames: std::ops::Range<usize>) -> Vec<Frame> {
+    frames
+        .map(|frame| {
+            gameboi.set_input(input(frame));
+            gameboi.step()
+        })
+        .collect()
+}
+
+// A state saved mid-game, loaded in the same GameBoi and in a fresh one, gives the same
+// frames, to the bit, as running on from where it was saved
+#[test]
+fn frames_after_loading_match() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut gameboi = load(&rom);
+    run(&mut gameboi, 0..200);
+    let state = gameboi.save_state();
+    let expected = run(&mut gameboi, 200..500);
+
+    gameboi.load_state(&state).unwrap();
+    let mut fresh = load(&rom);
+    fresh.load_state(&state).unwrap();
+    for mut gameboi in [gameboi, fresh] {
+        let frames = run(&mut gameboi, 200..500);
+        for (frame, (got, expected)) in frames.iter().zip(&expected).enumerate() {
+            assert!(got == expected, "frame {} differs", 200 + frame);
+        }
+    }
+}
+
+// Broken states are refused with the right error, and leave the GameBoi as it was
+#[test]
+fn broken_states_are_refused() {
+    let Some(rom) = rom("boxxle.gb") else {
+        return;
+    };
+    let mut gameboi = load(&rom);
+    run(&mut gameboi, 0..100);
+    let state = gameboi.save_state();
+    let hash = gameboi.state_hash();
+
+    let mut check = |data: &[u8], error: StateError| {
+        assert_eq!(gameboi.load_state(data), Err(error));
+        assert_eq!(gameboi.state_hash(), hash);
+    };
+
+    check(&[], StateError::NotAState);
+    check(b"PNG\x89 not a state", StateError::NotAState);
+
+    let mut version = state.clone();
+    version[4..6].copy_from_slice(&99u16.to_le_bytes());
+    check(&version, StateError::UnsupportedVersion(99));
+
+    // Cut anywhere past the header
+    for length in (6..state.len()).step_by(97).chain([state.len() - 1]) {
+        check(&state[..length], StateError::Truncated);
+    }
+
+    let mut trailing = state.clone();
+    trailing.push(0);
+    check(&trailing, StateError::TrailingData);
+
+    // The state ends with the lag flag
+    let mut flag = state.clone();
+    *flag.last_mut().unwrap() = 2;
+    check(&flag, StateError::Invalid("flag"));
+}
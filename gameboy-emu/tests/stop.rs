@@ -0,0 +1,65 @@
+mod common;
+
+use common::load;
+use gameboy_emu::{Button, GameBoi, InputState};
+
+// Selects the action buttons, then STOPs with nothing held and loops
+fn stopped() -> GameBoi {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x108].copy_from_slice(&[
+        0x3E, 0x10, // LD A,$10
+        0xE0, 0x00, // LDH (JOYP),A
+        0x10, 0x00, // STOP
+        0x18, 0xFE, // JR -2
+    ]);
+    let mut gameboi = load(&rom);
+    assert_eq!(gameboi.step(), [0; 23040]);
+    assert!(gameboi.is_stopped());
+    gameboi
+}
+
+fn pressed(button: Button) -> InputState {
+    let mut input = InputState::new();
+    input.press(button);
+    input
+}
+
+// The LCD is stopped too: blank frames are handed out and the clock doesn't run
+#[test]
+fn blank_frames_while_stopped() {
+    let mut gameboi = stopped();
+    let clock = gameboi.clock();
+    for _ in 0..3 {
+        assert_eq!(gameboi.step(), [0; 23040]);
+    }
+    assert!(gameboi.is_stopped());
+    assert_eq!(gameboi.clock(), clock);
+}
+
+// A button of the selected row wakes the CPU up, one of the other row doesn't
+#[test]
+fn button_press_wakes_up() {
+    let mut gameboi = stopped();
+    gameboi.set_input(pressed(Button::Up));
+    assert!(gameboi.is_stopped());
+
+    let clock = gameboi.clock();
+    gameboi.set_input(pressed(Button::A));
+    assert!(!gameboi.is_stopped());
+    gameboi.step();
+    assert!(gameboi.clock() > clock);
+}
+
+// Queued input can't wait for a clock that isn't running, it wakes the CPU up on the
+// next step
+#[test]
+fn queued_press_wakes_up() {
+    let mut gameboi = stopped();
+    let clock = gameboi.clock();
+    gameboi.queue_input(1000, pressed(Button::A));
+    assert!(gameboi.is_stopped());
+
+    gameboi.step();
+    assert!(!gameboi.is_stopped());
+    assert!(gameboi.clock() > clock);
+}